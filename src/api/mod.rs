@@ -1,16 +1,84 @@
-use crate::backend::multithread_backend::ShardedStorageBackend;
+use crate::backend::multithread_backend::{BackendError, ShardedStorageBackend};
+use crate::export::{self, ExportFormat};
+use crate::ingest::{BulkLoader, BulkLoaderConfig};
 use crate::lexer;
 use crate::record;
 use crate::record::query;
+use log::warn;
 use prometheus::{self, Encoder};
 use serde::{Deserialize, Serialize};
+use std::io::{self, BufWriter, Write};
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use warp::http::StatusCode;
+use warp::hyper::body::{Body, Bytes, Sender};
 use warp::{Filter, Rejection, Reply};
 
+/// Largest body accepted by the bulk endpoint
+const MAX_BULK_BODY_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Delay (in seconds) advertised to clients whose request was rejected by an overloaded shard
+const RETRY_AFTER_SECONDS: u32 = 1;
+
+/// Records returned by a text search without a limit, the best scored ones
+const DEFAULT_TEXT_LIMIT: usize = 100;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RawAPIQuery {
     pub query: String,
+    // Overrides the default query timeout of the server
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    // Maximum number of records returned, 100 by default for text searches
+    #[serde(default)]
+    pub limit: Option<usize>,
+    // Reports the values matched by the fuzzy fields along with each record
+    #[serde(default)]
+    pub fuzzy_matches: bool,
+    // Keys of the labels returned, replacing the keep(...) of the query if any
+    #[serde(default)]
+    pub fields: Option<Vec<String>>,
+    // Only returns the first record of each tuple of the labels kept
+    #[serde(default)]
+    pub distinct: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RawAPIMultiGet {
+    pub ids: Vec<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RawAPIExport {
+    // Search selecting the records, all of them by default
+    #[serde(default)]
+    pub query: Option<String>,
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RawAPIKeyStatus {
+    // Number of values with the largest posting lists to report
+    #[serde(default = "default_top_values")]
+    pub top: usize,
+}
+
+fn default_top_values() -> usize {
+    10
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RawAPIReshard {
+    pub shards: u16,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RawAPIPromote {
+    pub shard: u16,
+    // Index of the replica in the membership, 0 being the node and the followers coming next
+    pub replica: usize,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -25,12 +93,33 @@ pub struct SuccessResponse {
     // The query that triggered the error
     pub query: String,
     pub data: ResponseData,
+    // Set when the data is partial because some shards did not finish before the timeout
+    #[serde(default)]
+    pub timed_out: bool,
+    #[serde(default)]
+    pub unfinished_shards: Vec<u16>,
+    // Shards restarted after a crash, the data misses the records they held
+    #[serde(default)]
+    pub degraded_shards: Vec<u16>,
+}
+
+impl SuccessResponse {
+    fn new(query: String, data: ResponseData) -> SuccessResponse {
+        SuccessResponse {
+            query,
+            data,
+            timed_out: false,
+            unfinished_shards: Vec::new(),
+            degraded_shards: Vec::new(),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ResponseData {
-    Records { data: Vec<Arc<record::RCRecord>> },
+    Records { data: Vec<record::IdentifiedRecord> },
     Values { data: Vec<Arc<str>> },
+    Count { data: usize },
 }
 
 async fn metrics_handler() -> Result<impl Reply, Rejection> {
@@ -52,7 +141,37 @@ async fn metrics_handler() -> Result<impl Reply, Rejection> {
     Ok(res)
 }
 
-fn handle_search(search: RawAPIQuery, storage: Arc<RwLock<ShardedStorageBackend>>) -> warp::reply::Json {
+fn backend_error_response(query: String, error: BackendError) -> warp::reply::Response {
+    let status = match error {
+        BackendError::Overloaded(_) | BackendError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        BackendError::InvalidRecord(_) => StatusCode::BAD_REQUEST,
+        BackendError::StoreFull { .. } => StatusCode::INSUFFICIENT_STORAGE,
+    };
+    let reply = warp::reply::with_status(
+        warp::reply::json(&ErrorResponse {
+            query,
+            error: error.to_string(),
+        }),
+        status,
+    );
+    match error {
+        BackendError::Overloaded(_) => warp::reply::with_header(reply, "Retry-After", RETRY_AFTER_SECONDS.to_string()).into_response(),
+        _ => reply.into_response(),
+    }
+}
+
+/// Run a search, or only count its results (distinct values for label_values) when `count` is set
+fn handle_search(
+    search: RawAPIQuery,
+    storage: Arc<RwLock<ShardedStorageBackend>>,
+    query_timeout: Option<Duration>,
+    count: bool,
+) -> warp::reply::Response {
+    let deadline = search
+        .timeout_ms
+        .map(Duration::from_millis)
+        .or(query_timeout)
+        .map(|timeout| Instant::now() + timeout);
     let query = match lexer::parse_query(search.query.as_str()) {
         Ok(x) => x,
         Err(x) => {
@@ -60,18 +179,184 @@ fn handle_search(search: RawAPIQuery, storage: Arc<RwLock<ShardedStorageBackend>
                 query: search.query,
                 error: x,
             })
+            .into_response()
         }
     };
     let data = match query {
-        query::Query::Simple(x) => ResponseData::Records {
-            data: storage.read().unwrap().search(x),
-        },
-        query::Query::KeyValues(x) => ResponseData::Values {
-            data: storage.read().unwrap().key_values_search(x),
-        },
+        query::Query::Simple(mut x) if count => {
+            x.deadline = deadline;
+            storage.read().unwrap().try_count(x).map(|result| {
+                (ResponseData::Count { data: result.data }, result.unfinished_shards, result.degraded_shards)
+            })
+        }
+        query::Query::Simple(mut x) => {
+            x.deadline = deadline;
+            x.limit = search.limit.or_else(|| x.has_text().then_some(DEFAULT_TEXT_LIMIT));
+            x.query_flags.set(query::SearchFlags::REPORT_FUZZY_MATCHES, search.fuzzy_matches);
+            if let Some(fields) = search.fields {
+                let keys = fields.iter().map(|key| Box::from(key.as_str())).collect();
+                x.projection = Some(query::Projection { keys, distinct: false });
+            }
+            if let Some(projection) = x.projection.as_mut() {
+                projection.distinct |= search.distinct;
+            }
+            storage.read().unwrap().try_search(x).map(|result| {
+                (ResponseData::Records { data: result.data }, result.unfinished_shards, result.degraded_shards)
+            })
+        }
+        query::Query::KeyValues(mut x) => {
+            x.deadline = deadline;
+            storage.read().unwrap().try_key_values_search(x).map(|result| {
+                let data = match count {
+                    true => ResponseData::Count { data: result.data.len() },
+                    false => ResponseData::Values { data: result.data },
+                };
+                (data, result.unfinished_shards, result.degraded_shards)
+            })
+        }
     };
-    let response = SuccessResponse { query: search.query, data };
-    warp::reply::json(&response)
+    match data {
+        Ok((data, unfinished_shards, degraded_shards)) => warp::reply::json(&SuccessResponse {
+            query: search.query,
+            data,
+            timed_out: !unfinished_shards.is_empty(),
+            unfinished_shards,
+            degraded_shards,
+        })
+        .into_response(),
+        Err(error) => backend_error_response(search.query, error),
+    }
+}
+
+fn handle_get(id: u64, storage: Arc<RwLock<ShardedStorageBackend>>) -> warp::reply::Response {
+    match storage.read().unwrap().try_get(id) {
+        Ok(Some(record)) => warp::reply::json(&SuccessResponse::new(
+            id.to_string(),
+            ResponseData::Records { data: vec![record] },
+        ))
+        .into_response(),
+        Ok(None) => warp::reply::with_status(
+            warp::reply::json(&ErrorResponse {
+                query: id.to_string(),
+                error: format!("Error record {} not found", id),
+            }),
+            StatusCode::NOT_FOUND,
+        )
+        .into_response(),
+        Err(error) => backend_error_response(id.to_string(), error),
+    }
+}
+
+fn handle_multi_get(multi_get: RawAPIMultiGet, storage: Arc<RwLock<ShardedStorageBackend>>) -> warp::reply::Response {
+    let query = format!("{:?}", multi_get.ids);
+    match storage.read().unwrap().try_multi_get(multi_get.ids) {
+        Ok(data) => warp::reply::json(&SuccessResponse::new(query, ResponseData::Records { data })).into_response(),
+        Err(error) => backend_error_response(query, error),
+    }
+}
+
+/// Load the body from a blocking thread, a load waiting on full shard queues must not stall
+/// the runtime serving the other requests
+async fn handle_bulk(body: Bytes, storage: Arc<RwLock<ShardedStorageBackend>>) -> Result<impl Reply, Rejection> {
+    let loaded = tokio::task::spawn_blocking(move || BulkLoader::new(&storage.read().unwrap(), BulkLoaderConfig::default()).load(&body[..])).await;
+    let (loaded, status) = match loaded {
+        Ok(loaded) => (loaded, StatusCode::BAD_REQUEST),
+        Err(e) => (Err(format!("Error the bulk load failed: {}", e)), StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    Ok(match loaded {
+        Ok(summary) => warp::reply::with_status(warp::reply::json(&summary), StatusCode::OK),
+        Err(error) => warp::reply::with_status(
+            warp::reply::json(&ErrorResponse {
+                query: String::from("_bulk"),
+                error,
+            }),
+            status,
+        ),
+    })
+}
+
+/// Writes to the body of a streamed response, from a blocking thread
+struct BodyWriter {
+    sender: Sender,
+    runtime: tokio::runtime::Handle,
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.runtime
+            .block_on(self.sender.send_data(Bytes::copy_from_slice(buf)))
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Stream the matching records as they are read from the shards. Errors happening once
+/// the response started abort the body, so that clients do not mistake it for a full export.
+fn handle_export(raw_export: RawAPIExport, storage: Arc<RwLock<ShardedStorageBackend>>) -> warp::reply::Response {
+    let query = raw_export.query.clone().unwrap_or_default();
+    let parsed = export::parse_selector(raw_export.query.as_deref())
+        .and_then(|search| Ok((search, raw_export.format.as_deref().unwrap_or("text").parse::<ExportFormat>()?)));
+    let (search, format) = match parsed {
+        Ok(x) => x,
+        Err(error) => {
+            return warp::reply::with_status(warp::reply::json(&ErrorResponse { query, error }), StatusCode::BAD_REQUEST).into_response()
+        }
+    };
+    let (sender, body) = Body::channel();
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let mut out = BufWriter::with_capacity(64 * 1024, BodyWriter { sender, runtime });
+        let exported = export::export(&storage, search, format, &mut out);
+        if let Err(error) = exported {
+            warn!("Error exporting {}: {}", query, error);
+            if let Ok(writer) = out.into_inner() {
+                writer.sender.abort();
+            }
+        }
+    });
+    warp::reply::with_header(warp::reply::Response::new(body), "content-type", format.content_type()).into_response()
+}
+
+/// The records are migrated in the background, the status answered has the progress to poll
+/// on /status. Starting it waits for the write lock, so it is done from a blocking task.
+async fn handle_reshard(reshard: RawAPIReshard, storage: Arc<RwLock<ShardedStorageBackend>>) -> Result<impl Reply, Rejection> {
+    let shards = reshard.shards;
+    let started = tokio::task::spawn_blocking(move || {
+        ShardedStorageBackend::reshard(&storage, shards).map(|_| storage.read().unwrap().get_status())
+    })
+    .await;
+    let (started, status) = match started {
+        Ok(started) => (started, StatusCode::CONFLICT),
+        Err(e) => (Err(format!("Error the resharding failed to start: {}", e)), StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    Ok(match started {
+        Ok(status) => warp::reply::with_status(warp::reply::json(&status), StatusCode::ACCEPTED),
+        Err(error) => warp::reply::with_status(
+            warp::reply::json(&ErrorResponse {
+                query: format!("reshard({})", shards),
+                error,
+            }),
+            status,
+        ),
+    })
+}
+
+fn handle_promote(promote: RawAPIPromote, storage: Arc<RwLock<ShardedStorageBackend>>) -> warp::reply::WithStatus<warp::reply::Json> {
+    let backend = storage.read().unwrap();
+    match backend.promote(promote.shard, promote.replica) {
+        Ok(()) => warp::reply::with_status(warp::reply::json(&backend.get_status()), StatusCode::OK),
+        Err(error) => warp::reply::with_status(
+            warp::reply::json(&ErrorResponse {
+                query: format!("promote({}, {})", promote.shard, promote.replica),
+                error,
+            }),
+            StatusCode::BAD_REQUEST,
+        ),
+    }
 }
 
 fn handle_status(storage: Arc<RwLock<ShardedStorageBackend>>) -> warp::reply::Json {
@@ -79,17 +364,77 @@ fn handle_status(storage: Arc<RwLock<ShardedStorageBackend>>) -> warp::reply::Js
     warp::reply::json(&per_shard_status)
 }
 
-pub async fn serve(addr: impl Into<SocketAddr>, storage: Arc<RwLock<ShardedStorageBackend>>) {
+pub async fn serve(addr: impl Into<SocketAddr>, storage: Arc<RwLock<ShardedStorageBackend>>, query_timeout: Option<Duration>) {
     let mut storage_clone = storage.clone();
     let search = warp::post()
         .and(warp::path("search"))
         .and(warp::body::json())
-        .map(move |search: RawAPIQuery| handle_search(search, storage_clone.clone()));
+        .map(move |search: RawAPIQuery| handle_search(search, storage_clone.clone(), query_timeout, false));
+
+    storage_clone = storage.clone();
+    let count = warp::post()
+        .and(warp::path("count"))
+        .and(warp::body::json())
+        .map(move |search: RawAPIQuery| handle_search(search, storage_clone.clone(), query_timeout, true));
+
+    storage_clone = storage.clone();
+    let get = warp::get()
+        .and(warp::path!("records" / u64))
+        .map(move |id: u64| handle_get(id, storage_clone.clone()));
+
+    storage_clone = storage.clone();
+    let multi_get = warp::post()
+        .and(warp::path!("records" / "_mget"))
+        .and(warp::body::json())
+        .map(move |multi_get: RawAPIMultiGet| handle_multi_get(multi_get, storage_clone.clone()));
+
+    storage_clone = storage.clone();
+    let bulk = warp::post()
+        .and(warp::path!("records" / "_bulk"))
+        .and(warp::body::content_length_limit(MAX_BULK_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and_then(move |body| handle_bulk(body, storage_clone.clone()));
+
+    storage_clone = storage.clone();
+    let export = warp::get()
+        .and(warp::path("export"))
+        .and(warp::query())
+        .map(move |raw_export: RawAPIExport| handle_export(raw_export, storage_clone.clone()));
+
+    storage_clone = storage.clone();
+    let reshard = warp::post()
+        .and(warp::path("reshard"))
+        .and(warp::body::json())
+        .and_then(move |reshard: RawAPIReshard| handle_reshard(reshard, storage_clone.clone()));
+
+    storage_clone = storage.clone();
+    let promote = warp::post()
+        .and(warp::path("promote"))
+        .and(warp::body::json())
+        .map(move |promote: RawAPIPromote| handle_promote(promote, storage_clone.clone()));
+
+    storage_clone = storage.clone();
+    let key_status = warp::get()
+        .and(warp::path!("index" / "keys" / String))
+        .and(warp::query())
+        .map(move |key: String, raw: RawAPIKeyStatus| warp::reply::json(&storage_clone.read().unwrap().get_key_status(&key, raw.top)));
 
     storage_clone = storage.clone();
     let status = warp::get().and(warp::path("status")).map(move || handle_status(storage_clone.clone()));
 
     let prometheus = warp::get().and(warp::path("metrics")).and_then(metrics_handler);
     let www_static = warp::get().and(warp::path::end()).and(warp::fs::dir("web/"));
-    warp::serve(www_static.or(search).or(prometheus).or(status)).run(addr).await;
+    let routes = www_static
+        .or(search)
+        .or(count)
+        .or(get)
+        .or(multi_get)
+        .or(bulk)
+        .or(export)
+        .or(reshard)
+        .or(promote)
+        .or(prometheus)
+        .or(status)
+        .or(key_status);
+    warp::serve(routes).run(addr).await;
 }
@@ -0,0 +1,853 @@
+use crate::index;
+use crate::record;
+use crate::record::query;
+
+use super::replication::{ReplicatedShard, REPLICATION_LOG_SIZE};
+use super::multithread_backend::{
+    from_global_id, to_global_id, Admission, BackendRequest, ShardHandle, ShardHealth, ShardKeyStatus, ShardLanes, ShardMessage,
+    ShardedStorageBackend, ShardedStorageBackendConfig, ShardedStorageBackendStatus,
+};
+
+use crossbeam_channel::{bounded, Receiver};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use smallstr::SmallString;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of connections opened to a remote shard for reads, writes use a single
+/// one so that a flush is only answered once the writes sent before it are applied
+const REMOTE_READ_CONNECTIONS: usize = 4;
+
+/// Time after which a node which doesn't answer is considered unreachable
+const REMOTE_IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Static membership of a cluster, every node hosts a fixed number of shards
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    pub nodes: Vec<ClusterNode>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClusterNode {
+    /// Address the node listens on for coordinators (e.g. 10.0.0.2:7070)
+    pub address: String,
+    /// Number of shards the node runs, must match its --threads option
+    pub shards: u16,
+    /// Nodes keeping a copy of every shard of this node, they run the same number of shards
+    #[serde(default)]
+    pub followers: Vec<String>,
+}
+
+impl ClusterConfig {
+    /// Load the membership from a JSON file: {"nodes": [{"address": "10.0.0.2:7070", "shards": 8}]}
+    pub fn from_file(path: &str) -> Result<ClusterConfig, String> {
+        let file = File::open(path).map_err(|e| format!("Error opening cluster config {}: {}", path, e))?;
+        let config: ClusterConfig =
+            serde_json::from_reader(BufReader::new(file)).map_err(|e| format!("Error parsing cluster config {}: {}", path, e))?;
+        if config.nodes.is_empty() || config.shard_count() == 0 {
+            return Err(format!("Error cluster config {} doesn't define any shard", path));
+        }
+        Ok(config)
+    }
+
+    pub fn shard_count(&self) -> u16 {
+        self.nodes.iter().map(|node| node.shards).sum()
+    }
+}
+
+/// Searches are sent with the time left before their deadline, clocks of the nodes being unrelated
+#[derive(Serialize, Deserialize)]
+struct WireSearch {
+    search_fields: Vec<query::Field>,
+    query_flags: u8,
+    timeout_ms: Option<u64>,
+    #[serde(default)]
+    limit: Option<usize>,
+    #[serde(default)]
+    text_stats: Option<query::TextStats>,
+    #[serde(default)]
+    projection: Option<query::Projection>,
+}
+
+impl WireSearch {
+    fn new(search_fields: &[query::Field], query_flags: query::SearchFlags, deadline: Option<Instant>) -> WireSearch {
+        WireSearch {
+            search_fields: search_fields.to_vec(),
+            query_flags: query_flags.bits(),
+            timeout_ms: deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()).as_millis() as u64),
+            limit: None,
+            text_stats: None,
+            projection: None,
+        }
+    }
+
+    /// A search along with its limit and the statistics to rank its records with
+    fn from_search(search: query::Search) -> WireSearch {
+        WireSearch {
+            limit: search.limit,
+            text_stats: search.text_stats,
+            projection: search.projection,
+            ..WireSearch::new(&search.search_fields, search.query_flags, search.deadline)
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout_ms.map(|timeout| Instant::now() + Duration::from_millis(timeout))
+    }
+
+    fn to_search(&self) -> query::Search {
+        let mut search = query::Search::new_with_flags(self.search_fields.clone(), query::SearchFlags::from_bits_truncate(self.query_flags));
+        search.deadline = self.deadline();
+        search.limit = self.limit;
+        search.text_stats = self.text_stats.clone();
+        search.projection = self.projection.clone();
+        search
+    }
+
+    fn to_key_values_search(&self, key_field: &str) -> query::KeyValuesSearch {
+        let flags = query::SearchFlags::from_bits_truncate(self.query_flags);
+        let mut search = query::KeyValuesSearch::new_with_flags(self.search_fields.clone(), key_field, flags);
+        search.deadline = self.deadline();
+        search
+    }
+}
+
+/// Request sent by a coordinator to a node, addressing one of the shards of the node
+#[derive(Serialize, Deserialize)]
+enum NodeRequest {
+    Status { shard: u16 },
+    RawAdd { shard: u16, record: record::Record },
+    Migrate { shard: u16, record: record::RCRecord },
+    Scan { shard: u16, query: WireSearch, from: u32, limit: usize },
+    Ids { shard: u16, query: WireSearch },
+    Flush { shard: u16 },
+    Add { shard: u16, record: record::Record },
+    BulkAdd { shard: u16, records: Vec<record::Record> },
+    Get { shard: u16, ids: Vec<u32> },
+    Search { shard: u16, query: WireSearch },
+    Count { shard: u16, query: WireSearch },
+    TextStats { shard: u16, query: WireSearch },
+    KeyValuesSearch { shard: u16, query: WireSearch, key_field: Box<str> },
+    KeyStatus { shard: u16, key: String, top: usize },
+}
+
+/// Answers of a node, every request is answered by zero or more items followed by Done or Error
+#[derive(Serialize, Deserialize)]
+enum NodeResponse {
+    Status(ShardedStorageBackendStatus),
+    Added(Option<u32>),
+    // Ids are local to the shard, the coordinator turns them into its own global ids
+    Record {
+        id: u32,
+        record: Arc<record::RCRecord>,
+        #[serde(default)]
+        score: Option<f64>,
+    },
+    Value(Arc<str>),
+    Count(usize),
+    Ids(Vec<u32>),
+    TextStats(query::TextStats),
+    KeyStatus(Option<index::KeyStatus>),
+    Done { timed_out: bool, health: ShardHealth },
+    Error(String),
+}
+
+fn to_record(record: &record::SmallRecord) -> record::Record {
+    record::Record {
+        label_pairs: record.label_pairs.iter().map(|l| record::LabelPair::new(&l.key, &l.val)).collect(),
+    }
+}
+
+fn to_small_record(record: &record::Record) -> record::SmallRecord {
+    record::SmallRecord {
+        label_pairs: record
+            .label_pairs
+            .iter()
+            .map(|l| record::SmallLabelPair {
+                key: SmallString::from_str(&l.key),
+                val: SmallString::from_str(&l.val),
+            })
+            .collect(),
+    }
+}
+
+/// Handle to a shard hosted by a node of the cluster. Requests are queued like for a local
+/// shard and forwarded by a few threads, each keeping its own connection to the node.
+struct RemoteShard {
+    lanes: ShardLanes,
+    health: Arc<Mutex<ShardHealth>>,
+    // Writes which could not be forwarded to the node
+    lost_writes: Arc<AtomicUsize>,
+}
+
+impl ShardHandle for RemoteShard {
+    fn lanes(&self) -> &ShardLanes {
+        &self.lanes
+    }
+
+    fn health(&self) -> ShardHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    fn lost_writes(&self) -> usize {
+        self.lost_writes.load(Ordering::SeqCst)
+    }
+}
+
+/// Create the handles to the shards of every node of the cluster
+pub(crate) fn connect(cluster: &ClusterConfig, config: &ShardedStorageBackendConfig) -> Vec<Box<dyn ShardHandle>> {
+    let mut shards: Vec<Box<dyn ShardHandle>> = Vec::new();
+    for node in &cluster.nodes {
+        info!("Using {} shards of node {} (followers: {:?})", node.shards, node.address, node.followers);
+        for remote_shard in 0..node.shards {
+            let shard_id = shards.len() as u16;
+            if node.followers.is_empty() {
+                shards.push(Box::new(RemoteShard::new(&node.address, remote_shard, shard_id, config)));
+                continue;
+            }
+            let replicas = std::iter::once(&node.address)
+                .chain(node.followers.iter())
+                .map(|address| {
+                    let replica: Arc<dyn ShardHandle> = Arc::new(RemoteShard::new(address, remote_shard, shard_id, config));
+                    (address.clone(), replica)
+                })
+                .collect();
+            shards.push(Box::new(ReplicatedShard::new(replicas, REPLICATION_LOG_SIZE)));
+        }
+    }
+    shards
+}
+
+impl RemoteShard {
+    fn new(address: &str, remote_shard: u16, shard_id: u16, config: &ShardedStorageBackendConfig) -> RemoteShard {
+        let (lanes, reads, writes) = ShardLanes::new(shard_id, config.queue_size);
+        let health = Arc::new(Mutex::new(ShardHealth::new(shard_id)));
+        let lost_writes = Arc::new(AtomicUsize::new(0));
+        let receivers = std::iter::once(writes).chain(std::iter::repeat_n(reads, REMOTE_READ_CONNECTIONS));
+        for (i, requests) in receivers.enumerate() {
+            let forwarder = RemoteForwarder {
+                address: address.to_string(),
+                remote_shard,
+                shard_id,
+                connection: None,
+                health: health.clone(),
+                lost_writes: lost_writes.clone(),
+            };
+            thread::Builder::new()
+                .name(format!("remote-shard-{}-{}", shard_id, i))
+                .spawn(move || forwarder.run(requests))
+                .unwrap();
+        }
+        RemoteShard { lanes, health, lost_writes }
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Connection {
+    fn open(address: &str) -> io::Result<Connection> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(REMOTE_IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(REMOTE_IO_TIMEOUT))?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+}
+
+/// Forward the requests of a remote shard queue to the node hosting the shard
+struct RemoteForwarder {
+    address: String,
+    remote_shard: u16,
+    shard_id: u16,
+    connection: Option<Connection>,
+    health: Arc<Mutex<ShardHealth>>,
+    lost_writes: Arc<AtomicUsize>,
+}
+
+impl RemoteForwarder {
+    /// Stops once the shard handle is dropped
+    fn run(mut self, requests: Receiver<BackendRequest>) {
+        for request in requests.iter() {
+            let write = request.is_write();
+            match self.forward(request) {
+                Ok(health) => {
+                    *self.health.lock().unwrap() = ShardHealth {
+                        shard_id: self.shard_id,
+                        ..health
+                    }
+                }
+                Err(_) if write => {
+                    self.lost_writes.fetch_add(1, Ordering::SeqCst);
+                }
+                Err(_) => (),
+            }
+        }
+    }
+
+    /// Send a request and pass every item of the answer to `on_item`. Returns whether the
+    /// node timed out along with the health of its shard.
+    fn call(&mut self, request: &NodeRequest, on_item: impl FnMut(NodeResponse)) -> Result<(bool, ShardHealth), String> {
+        let result = self.exchange(request, on_item);
+        // Health is updated before the caller drops the response channel of the request
+        if let Err(e) = &result {
+            warn!("Request to shard {} of node {} failed: {}", self.remote_shard, self.address, e);
+            self.connection = None;
+            let mut health = self.health.lock().unwrap();
+            health.healthy = false;
+            health.last_crash = Some(format!("Node {} unreachable: {}", self.address, e));
+        }
+        result
+    }
+
+    fn exchange(&mut self, request: &NodeRequest, mut on_item: impl FnMut(NodeResponse)) -> Result<(bool, ShardHealth), String> {
+        if self.connection.is_none() {
+            self.connection = Some(Connection::open(&self.address).map_err(|e| e.to_string())?);
+        }
+        let connection = self.connection.as_mut().unwrap();
+        serde_json::to_writer(&mut connection.writer, request).map_err(|e| e.to_string())?;
+        connection.writer.write_all(b"\n").map_err(|e| e.to_string())?;
+        connection.writer.flush().map_err(|e| e.to_string())?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if connection.reader.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+                return Err(String::from("connection closed"));
+            }
+            match serde_json::from_str(&line).map_err(|e| e.to_string())? {
+                NodeResponse::Done { timed_out, health } => return Ok((timed_out, health)),
+                NodeResponse::Error(e) => return Err(e),
+                item => on_item(item),
+            }
+        }
+    }
+
+    /// On error the response channel is dropped without a Done message,
+    /// so a query fanned out to this shard reports it as unfinished
+    fn forward(&mut self, request: BackendRequest) -> Result<ShardHealth, String> {
+        let shard = self.remote_shard;
+        let shard_id = self.shard_id;
+        let address = self.address.clone();
+        let health = match request {
+            BackendRequest::StatusRequest { response_chan } => {
+                self.call(&NodeRequest::Status { shard }, |item| {
+                    if let NodeResponse::Status(mut status) = item {
+                        status.shard_id = shard_id;
+                        status.node = Some(address.clone());
+                        response_chan.send(status).ok();
+                    }
+                })?
+                .1
+            }
+            BackendRequest::RawAddRequest { record } => {
+                let record = to_record(&record);
+                self.call(&NodeRequest::RawAdd { shard, record }, drop)?.1
+            }
+            BackendRequest::MigrateRequest { record } => {
+                let record = (*record).clone();
+                self.call(&NodeRequest::Migrate { shard, record }, drop)?.1
+            }
+            BackendRequest::ScanRequest {
+                query,
+                from,
+                limit,
+                response_chan,
+            } => {
+                let query = WireSearch::new(&query.search_fields, query.query_flags, query.deadline);
+                self.call(&NodeRequest::Scan { shard, query, from, limit }, |item| {
+                    if let NodeResponse::Record { id, record, .. } = item {
+                        response_chan.send((id, record)).ok();
+                    }
+                })?
+                .1
+            }
+            BackendRequest::IdsRequest { query, response_chan } => {
+                let query = WireSearch::new(&query.search_fields, query.query_flags, query.deadline);
+                self.call(&NodeRequest::Ids { shard, query }, |item| {
+                    if let NodeResponse::Ids(ids) = item {
+                        response_chan.send(ids).ok();
+                    }
+                })?
+                .1
+            }
+            BackendRequest::FlushRequest { response_chan } => {
+                let (_, health) = self.call(&NodeRequest::Flush { shard }, drop)?;
+                response_chan.send(()).ok();
+                health
+            }
+            BackendRequest::AddRequest { record, response_chan } => {
+                let record = to_record(&record);
+                self.call(&NodeRequest::Add { shard, record }, |item| {
+                    if let NodeResponse::Added(id) = item {
+                        response_chan.send(id).ok();
+                    }
+                })?
+                .1
+            }
+            BackendRequest::BulkAddRequest { records, response_chan } => {
+                let records = records.iter().map(to_record).collect();
+                self.call(&NodeRequest::BulkAdd { shard, records }, |item| {
+                    if let NodeResponse::Count(added) = item {
+                        response_chan.send(added).ok();
+                    }
+                })?
+                .1
+            }
+            BackendRequest::GetRequest { ids, response_chan } => {
+                self.call(&NodeRequest::Get { shard, ids }, |item| {
+                    if let NodeResponse::Record { id, record, .. } = item {
+                        let id = to_global_id(shard_id, id);
+                        response_chan
+                            .send(record::IdentifiedRecord {
+                                id,
+                                record,
+                                score: None,
+                                fuzzy_matches: Vec::new(),
+                            })
+                            .ok();
+                    }
+                })?
+                .1
+            }
+            BackendRequest::SearchRequest { query, response_chan } => {
+                let query = WireSearch::from_search(query);
+                let (timed_out, health) = self.call(&NodeRequest::Search { shard, query }, |item| {
+                    if let NodeResponse::Record { id, record, score } = item {
+                        let id = to_global_id(shard_id, id);
+                        response_chan
+                            .send(ShardMessage::Item(record::IdentifiedRecord {
+                                id,
+                                record,
+                                score,
+                                fuzzy_matches: Vec::new(),
+                            }))
+                            .ok();
+                    }
+                })?;
+                response_chan.send(ShardMessage::Done { shard_id, timed_out }).ok();
+                health
+            }
+            BackendRequest::CountRequest { query, response_chan } => {
+                let query = WireSearch::new(&query.search_fields, query.query_flags, query.deadline);
+                let (timed_out, health) = self.call(&NodeRequest::Count { shard, query }, |item| {
+                    if let NodeResponse::Count(count) = item {
+                        response_chan.send(ShardMessage::Item(count)).ok();
+                    }
+                })?;
+                response_chan.send(ShardMessage::Done { shard_id, timed_out }).ok();
+                health
+            }
+            BackendRequest::TextStatsRequest { query, response_chan } => {
+                let query = WireSearch::new(&query.search_fields, query.query_flags, query.deadline);
+                let (timed_out, health) = self.call(&NodeRequest::TextStats { shard, query }, |item| {
+                    if let NodeResponse::TextStats(stats) = item {
+                        response_chan.send(ShardMessage::Item(stats)).ok();
+                    }
+                })?;
+                response_chan.send(ShardMessage::Done { shard_id, timed_out }).ok();
+                health
+            }
+            BackendRequest::KeyValuesSearchRequest { query, response_chan } => {
+                let key_field = query.key_field.clone();
+                let query = WireSearch::new(&query.search_fields, query.query_flags, query.deadline);
+                let (timed_out, health) = self.call(&NodeRequest::KeyValuesSearch { shard, query, key_field }, |item| {
+                    if let NodeResponse::Value(value) = item {
+                        response_chan.send(ShardMessage::Item(value)).ok();
+                    }
+                })?;
+                response_chan.send(ShardMessage::Done { shard_id, timed_out }).ok();
+                health
+            }
+            BackendRequest::KeyStatusRequest { key, top, response_chan } => {
+                self.call(&NodeRequest::KeyStatus { shard, key, top }, |item| {
+                    if let NodeResponse::KeyStatus(status) = item {
+                        response_chan.send(ShardKeyStatus { shard_id, status }).ok();
+                    }
+                })?
+                .1
+            }
+        };
+        Ok(health)
+    }
+}
+
+/// Serve the shards of this node to the coordinators of the cluster, one thread per connection
+pub fn serve_node(listener: TcpListener, storage: Arc<RwLock<ShardedStorageBackend>>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let storage = storage.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, storage) {
+                        warn!("Coordinator connection closed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Error accepting coordinator connection: {}", e),
+        }
+    }
+}
+
+fn handle_connection(stream: TcpStream, storage: Arc<RwLock<ShardedStorageBackend>>) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    for line in reader.lines() {
+        let mut respond = |response: NodeResponse| -> io::Result<()> {
+            serde_json::to_writer(&mut writer, &response)?;
+            writer.write_all(b"\n")
+        };
+        match serde_json::from_str(&line?) {
+            Ok(request) => serve_request(request, &storage.read().unwrap(), &mut respond)?,
+            Err(e) => respond(NodeResponse::Error(format!("Error bad request: {}", e)))?,
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Forward the items of a fanned out request, returning whether the shard timed out.
+/// A shard which stops without saying it is done counts as timed out.
+fn stream_messages<T>(
+    r: Receiver<ShardMessage<T>>,
+    respond: &mut impl FnMut(NodeResponse) -> io::Result<()>,
+    to_response: impl Fn(T) -> NodeResponse,
+) -> io::Result<bool> {
+    for message in r.iter() {
+        match message {
+            ShardMessage::Item(x) => respond(to_response(x))?,
+            ShardMessage::Done { timed_out, .. } => return Ok(timed_out),
+        }
+    }
+    Ok(true)
+}
+
+fn serve_request(
+    request: NodeRequest,
+    backend: &ShardedStorageBackend,
+    respond: &mut impl FnMut(NodeResponse) -> io::Result<()>,
+) -> io::Result<()> {
+    let shard_id = match &request {
+        NodeRequest::Status { shard }
+        | NodeRequest::RawAdd { shard, .. }
+        | NodeRequest::Migrate { shard, .. }
+        | NodeRequest::Scan { shard, .. }
+        | NodeRequest::Ids { shard, .. }
+        | NodeRequest::Flush { shard }
+        | NodeRequest::Add { shard, .. }
+        | NodeRequest::BulkAdd { shard, .. }
+        | NodeRequest::Get { shard, .. }
+        | NodeRequest::Search { shard, .. }
+        | NodeRequest::Count { shard, .. }
+        | NodeRequest::TextStats { shard, .. }
+        | NodeRequest::KeyValuesSearch { shard, .. }
+        | NodeRequest::KeyStatus { shard, .. } => *shard,
+    };
+    let shard = match backend.shard(shard_id) {
+        Some(shard) => shard,
+        None => return respond(NodeResponse::Error(format!("Error shard {} is not hosted by this node", shard_id))),
+    };
+    let send = |request: BackendRequest| shard.send(request, Admission::Block).map_err(|e| e.to_string());
+
+    let sent = match request {
+        NodeRequest::Status { .. } => {
+            let (s, r) = bounded(1);
+            send(BackendRequest::StatusRequest { response_chan: s }).map(|_| {
+                r.iter().try_for_each(|status| respond(NodeResponse::Status(status)))?;
+                Ok(false)
+            })
+        }
+        NodeRequest::RawAdd { record, .. } => {
+            send(BackendRequest::RawAddRequest { record: to_small_record(&record) }).map(|_| Ok(false))
+        }
+        NodeRequest::Migrate { record, .. } => send(BackendRequest::MigrateRequest { record: Arc::new(record) }).map(|_| Ok(false)),
+        NodeRequest::Scan { query, from, limit, .. } => {
+            let (s, r) = bounded(1000);
+            let query = query.to_search();
+            send(BackendRequest::ScanRequest {
+                query,
+                from,
+                limit,
+                response_chan: s,
+            })
+            .map(|_| {
+                r.iter().try_for_each(|(id, record)| respond(NodeResponse::Record { id, record, score: None }))?;
+                Ok(false)
+            })
+        }
+        NodeRequest::Ids { query, .. } => {
+            let (s, r) = bounded(1);
+            send(BackendRequest::IdsRequest {
+                query: query.to_search(),
+                response_chan: s,
+            })
+            .map(|_| {
+                r.iter().try_for_each(|ids| respond(NodeResponse::Ids(ids)))?;
+                Ok(false)
+            })
+        }
+        NodeRequest::Flush { .. } => {
+            let (s, r) = bounded(1);
+            send(BackendRequest::FlushRequest { response_chan: s }).map(|_| {
+                r.iter().for_each(drop);
+                Ok(false)
+            })
+        }
+        NodeRequest::Add { record, .. } => {
+            let (s, r) = bounded(1);
+            let record = to_small_record(&record);
+            send(BackendRequest::AddRequest { record, response_chan: s }).map(|_| {
+                r.iter().try_for_each(|id| respond(NodeResponse::Added(id)))?;
+                Ok(false)
+            })
+        }
+        NodeRequest::BulkAdd { records, .. } => {
+            let (s, r) = bounded(1);
+            let records = records.iter().map(to_small_record).collect();
+            send(BackendRequest::BulkAddRequest { records, response_chan: s }).map(|_| {
+                r.iter().try_for_each(|added| respond(NodeResponse::Count(added)))?;
+                Ok(false)
+            })
+        }
+        NodeRequest::Get { ids, .. } => {
+            let (s, r) = bounded(1000);
+            send(BackendRequest::GetRequest { ids, response_chan: s }).map(|_| {
+                r.iter().try_for_each(|x| {
+                    respond(NodeResponse::Record {
+                        id: from_global_id(x.id).1,
+                        record: x.record,
+                        score: None,
+                    })
+                })?;
+                Ok(false)
+            })
+        }
+        NodeRequest::Search { query, .. } => {
+            let (s, r) = bounded(1000);
+            send(BackendRequest::SearchRequest {
+                query: query.to_search(),
+                response_chan: s,
+            })
+            .map(|_| {
+                stream_messages(r, respond, |x: record::IdentifiedRecord| NodeResponse::Record {
+                    id: from_global_id(x.id).1,
+                    record: x.record,
+                    score: x.score,
+                })
+            })
+        }
+        NodeRequest::Count { query, .. } => {
+            let (s, r) = bounded(1);
+            send(BackendRequest::CountRequest {
+                query: query.to_search(),
+                response_chan: s,
+            })
+            .map(|_| stream_messages(r, respond, NodeResponse::Count))
+        }
+        NodeRequest::TextStats { query, .. } => {
+            let (s, r) = bounded(1);
+            send(BackendRequest::TextStatsRequest {
+                query: query.to_search(),
+                response_chan: s,
+            })
+            .map(|_| stream_messages(r, respond, NodeResponse::TextStats))
+        }
+        NodeRequest::KeyValuesSearch { query, key_field, .. } => {
+            let (s, r) = bounded(1000);
+            send(BackendRequest::KeyValuesSearchRequest {
+                query: query.to_key_values_search(&key_field),
+                response_chan: s,
+            })
+            .map(|_| stream_messages(r, respond, NodeResponse::Value))
+        }
+        NodeRequest::KeyStatus { key, top, .. } => {
+            let (s, r) = bounded(1);
+            send(BackendRequest::KeyStatusRequest { key, top, response_chan: s }).map(|_| {
+                r.iter().try_for_each(|status| respond(NodeResponse::KeyStatus(status.status)))?;
+                Ok(false)
+            })
+        }
+    };
+    match sent {
+        Ok(timed_out) => respond(NodeResponse::Done {
+            timed_out: timed_out?,
+            health: shard.health(),
+        }),
+        Err(e) => respond(NodeResponse::Error(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start a node with the given number of shards on a free port of localhost
+    fn start_node(shards: u16) -> ClusterNode {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let storage = Arc::new(RwLock::new(ShardedStorageBackend::new_with_cpus(shards)));
+        thread::spawn(move || serve_node(listener, storage));
+        ClusterNode {
+            address,
+            shards,
+            followers: Vec::new(),
+        }
+    }
+
+    /// Start a node which dies once it answered the given number of requests of its first coordinator connection
+    fn start_dying_node(shards: u16, requests: usize) -> ClusterNode {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let storage = ShardedStorageBackend::new_with_cpus(shards);
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = BufWriter::new(stream);
+            for line in reader.lines().take(requests) {
+                let request = serde_json::from_str(&line.unwrap()).unwrap();
+                serve_request(request, &storage, &mut |response: NodeResponse| {
+                    serde_json::to_writer(&mut writer, &response)?;
+                    writer.write_all(b"\n")
+                })
+                .unwrap();
+                writer.flush().unwrap();
+            }
+        });
+        ClusterNode {
+            address,
+            shards,
+            followers: Vec::new(),
+        }
+    }
+
+    fn load_test_data(backend: &ShardedStorageBackend) {
+        backend.raw_add(String::from(r#"{author="tolkien", title="The Silmarillion", language="english"}"#));
+        backend.raw_add(String::from(r#"{author="tolkien", title="The Silmarillion", language="french"}"#));
+        backend.raw_add(String::from(r#"{author="tolkien", title="The Hobbit", language="french"}"#));
+        backend.raw_add(String::from(r#"{author="herbert", title="Dune", language="english"}"#));
+        backend.wait_pending_operations();
+    }
+
+    #[test]
+    fn it_queries_several_nodes() {
+        let cluster = ClusterConfig {
+            nodes: vec![start_node(2), start_node(1), start_node(3)],
+        };
+        let backend = ShardedStorageBackend::new_cluster(cluster, ShardedStorageBackendConfig::default());
+        assert_eq!(backend.shard_count(), 6);
+        load_test_data(&backend);
+        // Records are routed by content, adding them again doesn't duplicate them
+        load_test_data(&backend);
+
+        let tolkien = query::Search::new(vec![query::Field::new_eq("author", "tolkien")]);
+        let records = backend.search(tolkien.clone());
+        assert_eq!(records.len(), 3);
+        assert_eq!(backend.count(tolkien), 3);
+        assert_eq!(backend.count(query::Search::new(vec![])), 4);
+
+        let mut languages = backend.key_values_search(query::KeyValuesSearch::new(vec![query::Field::new_re("title", "The.*")], "language"));
+        languages.sort();
+        assert_eq!(languages, vec![Arc::from("english"), Arc::from("french")]);
+
+        let record = backend.get(records[0].id).unwrap();
+        assert_eq!(record, records[0]);
+        assert_eq!(backend.get_status().health.iter().filter(|h| h.healthy).count(), 6);
+
+        let authors = backend.get_key_status("author", 1);
+        assert_eq!((authors.postings, authors.shards.len()), (4, 6));
+        assert_eq!(authors.top_values, vec![(Arc::from("tolkien"), 3)]);
+    }
+
+    #[test]
+    fn it_reports_unreachable_nodes() {
+        // Bind then drop a listener to get a port nobody listens on
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let cluster = ClusterConfig {
+            nodes: vec![
+                start_node(1),
+                ClusterNode {
+                    address: closed,
+                    shards: 1,
+                    followers: Vec::new(),
+                },
+            ],
+        };
+        let backend = ShardedStorageBackend::new_cluster(cluster, ShardedStorageBackendConfig::default());
+        let result = backend.try_search(query::Search::new(vec![query::Field::new_eq("author", "tolkien")])).unwrap();
+        assert_eq!(result.unfinished_shards, vec![1]);
+        assert_eq!(result.degraded_shards, vec![1]);
+    }
+
+    #[test]
+    fn it_replicates_shards() {
+        let follower = start_node(2);
+        let mut leader = start_node(2);
+        leader.followers.push(follower.address.clone());
+        let backend = ShardedStorageBackend::new_cluster(ClusterConfig { nodes: vec![leader] }, ShardedStorageBackendConfig::default());
+        load_test_data(&backend);
+
+        let replication = backend.get_status().replication;
+        assert_eq!(replication.len(), 2);
+        assert!(replication.iter().all(|shard| shard.replicas.iter().all(|replica| replica.lag_writes == 0)));
+
+        // The follower received the same writes in the same order, so it gives the same ids
+        let tolkien = query::Search::new(vec![query::Field::new_eq("author", "tolkien")]);
+        let mut records = backend.search(tolkien.clone());
+        backend.promote(0, 1).unwrap();
+        backend.promote(1, 1).unwrap();
+        let mut promoted_records = backend.search(tolkien);
+        records.sort_by_key(|x| x.id);
+        promoted_records.sort_by_key(|x| x.id);
+        assert_eq!(records, promoted_records);
+        assert!(backend.get_status().replication.iter().all(|shard| shard.leader == 1));
+        assert!(backend.promote(0, 2).is_err());
+    }
+
+    #[test]
+    fn it_degrades_followers_whose_node_dies() {
+        let follower = start_dying_node(1, 2);
+        let mut leader = start_node(1);
+        leader.followers.push(follower.address.clone());
+        let backend = ShardedStorageBackend::new_cluster(ClusterConfig { nodes: vec![leader] }, ShardedStorageBackendConfig::default());
+        // The follower dies after two of the four records, the others are lost on their way to it
+        load_test_data(&backend);
+
+        let replicas = &backend.get_status().replication[0].replicas;
+        assert!(!replicas[0].degraded && replicas[1].degraded);
+        assert!(backend.promote(0, 1).unwrap_err().contains("missed writes"));
+        let tolkien = query::Search::new(vec![query::Field::new_eq("author", "tolkien")]);
+        assert_eq!(backend.search(tolkien).len(), 3);
+    }
+
+    #[test]
+    fn it_reads_from_followers_when_the_leader_is_down() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let follower = start_node(1);
+        let leader = ClusterNode {
+            address: closed,
+            shards: 1,
+            followers: vec![follower.address],
+        };
+        let backend = ShardedStorageBackend::new_cluster(ClusterConfig { nodes: vec![leader] }, ShardedStorageBackendConfig::default());
+        load_test_data(&backend);
+
+        let tolkien = query::Search::new(vec![query::Field::new_eq("author", "tolkien")]);
+        let result = backend.try_search(tolkien).unwrap();
+        assert_eq!(result.data.len(), 3);
+        assert!(result.unfinished_shards.is_empty());
+        assert!(result.degraded_shards.is_empty());
+    }
+}
@@ -1,3 +1,5 @@
+pub mod cluster;
 pub mod multithread_backend;
+pub mod replication;
 pub mod singlethread_backend;
 pub mod timewindow_backend;
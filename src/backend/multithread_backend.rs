@@ -1,110 +1,1101 @@
+use crate::index;
+use crate::lexer;
 use crate::record;
 use crate::record::query;
+use crate::store::symbols::{SymbolTable, SymbolTableStatus};
+pub use crate::index::IndexConfig;
+pub use crate::store::StoreLayout;
 
-use crate::telemetry::LOCAL_SHARD_LATENCY_HISTOGRAM;
+use crate::telemetry::{LOCAL_SHARD_LATENCY_HISTOGRAM, MEMORY_USAGE_BYTES, SHARD_CRASHES, SHARD_QUEUE_DEPTH, SHARD_REJECTED_REQUESTS};
 use ahash::AHasher;
-use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_channel::{bounded, select, Receiver, Sender, TrySendError};
+use prometheus::IntGauge;
 use serde::{Deserialize, Serialize};
-use hashbrown::HashSet;
+use hashbrown::{HashMap, HashSet};
+use log::{error, info, warn};
 
+use std::cell::RefCell;
+use std::fmt;
 use std::hash::Hasher;
-use std::sync::Arc;
-use std::thread::spawn;
-use std::time::Instant;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once, RwLock};
+use std::thread::{self, spawn};
+use std::time::{Duration, Instant};
 
+/// Number of records moved per scan request while resharding, shards serve
+/// other requests in between two batches
+const MIGRATION_BATCH_SIZE: usize = 10000;
+
+/// Number of records read per scan request while exporting
+const EXPORT_BATCH_SIZE: usize = 1000;
+
+/// Time given to shards past the deadline of a query to send what they found before aborting
+const SHARD_ABORT_GRACE: Duration = Duration::from_millis(10);
+
+use super::cluster::{self, ClusterConfig};
+use super::replication::ReplicationStatus;
 use super::singlethread_backend::*;
 
-#[allow(dead_code)]
-enum BackendRequest {
+#[allow(dead_code, clippy::large_enum_variant, clippy::enum_variant_names)]
+pub(crate) enum BackendRequest {
     StatusRequest {
         response_chan: Sender<ShardedStorageBackendStatus>,
     },
     RawAddRequest {
-        line: String,
+        record: record::SmallRecord,
+    },
+    MigrateRequest {
+        record: Arc<record::RCRecord>,
+    },
+    ScanRequest {
+        query: query::Search,
+        from: u32,
+        limit: usize,
+        response_chan: Sender<(u32, Arc<record::RCRecord>)>,
+    },
+    /// Ids of the records matching the query, answered at once
+    IdsRequest {
+        query: query::Search,
+        response_chan: Sender<Vec<u32>>,
+    },
+    FlushRequest {
+        response_chan: Sender<()>,
     },
     AddRequest {
         record: record::SmallRecord,
         response_chan: Sender<Option<u32>>,
     },
+    /// Add a batch of records, answering with the number of records which were not duplicates
+    BulkAddRequest {
+        records: Vec<record::SmallRecord>,
+        response_chan: Sender<usize>,
+    },
+    GetRequest {
+        ids: Vec<u32>,
+        response_chan: Sender<record::IdentifiedRecord>,
+    },
     SearchRequest {
         query: query::Search,
-        response_chan: Sender<Arc<record::RCRecord>>,
+        response_chan: Sender<ShardMessage<record::IdentifiedRecord>>,
+    },
+    CountRequest {
+        query: query::Search,
+        response_chan: Sender<ShardMessage<usize>>,
+    },
+    TextStatsRequest {
+        query: query::Search,
+        response_chan: Sender<ShardMessage<query::TextStats>>,
     },
     KeyValuesSearchRequest {
         query: query::KeyValuesSearch,
-        response_chan: Sender<Arc<str>>,
+        response_chan: Sender<ShardMessage<Arc<str>>>,
     },
+    KeyStatusRequest {
+        key: String,
+        top: usize,
+        response_chan: Sender<ShardKeyStatus>,
+    },
+}
+
+/// Answer of a shard to a query fanned out to every shard, the shard tells when it is
+/// done so that the ones which did not answer before the deadline can be reported
+pub(crate) enum ShardMessage<T> {
+    Item(T),
+    Done { shard_id: u16, timed_out: bool },
+}
+
+/// Result of a query fanned out to every shard
+pub struct ShardedResult<T> {
+    pub data: T,
+    /// Shards which did not answer, or only partially, before the deadline of the query
+    pub unfinished_shards: Vec<u16>,
+    /// Shards which were restarted after a crash, their answer misses the records they lost
+    pub degraded_shards: Vec<u16>,
+}
+
+impl<T> ShardedResult<T> {
+    pub fn timed_out(&self) -> bool {
+        !self.unfinished_shards.is_empty()
+    }
+}
+
+/// Gather the answers of the shards until they are all done or the deadline is reached
+fn collect_shard_messages<T>(r: Receiver<ShardMessage<T>>, shard_count: usize, deadline: Option<Instant>) -> ShardedResult<Vec<T>> {
+    let mut data = Vec::new();
+    let mut pending: HashSet<u16> = (0..shard_count as u16).collect();
+    let mut unfinished_shards = Vec::new();
+    loop {
+        let message = match deadline {
+            Some(deadline) => r.recv_deadline(deadline + SHARD_ABORT_GRACE).ok(),
+            None => r.recv().ok(),
+        };
+        match message {
+            Some(ShardMessage::Item(x)) => data.push(x),
+            Some(ShardMessage::Done { shard_id, timed_out }) => {
+                pending.remove(&shard_id);
+                if timed_out {
+                    unfinished_shards.push(shard_id);
+                }
+            }
+            // Either every shard is done or the deadline is over
+            None => break,
+        }
+    }
+    unfinished_shards.extend(pending);
+    unfinished_shards.sort_unstable();
+    ShardedResult {
+        data,
+        unfinished_shards,
+        degraded_shards: Vec::new(),
+    }
+}
+
+impl BackendRequest {
+    /// Writes go through their own queue so that reads are not stuck behind a large ingestion
+    pub(crate) fn is_write(&self) -> bool {
+        matches!(
+            self,
+            BackendRequest::RawAddRequest { .. }
+                | BackendRequest::MigrateRequest { .. }
+                | BackendRequest::AddRequest { .. }
+                | BackendRequest::BulkAddRequest { .. }
+                | BackendRequest::FlushRequest { .. }
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BackendError {
+    /// The queue of the shard is full, the request can be retried later
+    Overloaded(u16),
+    /// The shard thread is gone and can't be restarted
+    Unavailable(u16),
+    InvalidRecord(String),
+    /// The memory used by the shards of this process reached the configured limit
+    StoreFull { used: usize, limit: usize },
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Overloaded(shard_id) => write!(f, "Error shard {} is overloaded, retry later", shard_id),
+            BackendError::Unavailable(shard_id) => write!(f, "Error shard {} is unavailable", shard_id),
+            BackendError::InvalidRecord(error) => write!(f, "{}", error),
+            BackendError::StoreFull { used, limit } => write!(f, "Error the store is full: {} bytes used, the limit is {} bytes", used, limit),
+        }
+    }
+}
+
+/// Whether sending to a full shard queue waits or fails
+#[derive(Clone, Copy)]
+pub(crate) enum Admission {
+    Block,
+    Reject,
+}
+
+/// Options used to spawn the shards of a backend
+#[derive(Clone, Debug)]
+pub struct ShardedStorageBackendConfig {
+    pub shards: u16,
+    pub pinning: CpuPinning,
+    /// Capacity of each of the two (read and write) queues of a shard
+    pub queue_size: usize,
+    /// Bytes the local shards may use before new records are rejected
+    pub max_memory: Option<usize>,
+    pub layout: StoreLayout,
+    pub index: IndexConfig,
+}
+
+impl Default for ShardedStorageBackendConfig {
+    fn default() -> Self {
+        ShardedStorageBackendConfig {
+            shards: available_cpus(),
+            pinning: CpuPinning::None,
+            queue_size: 10000,
+            max_memory: None,
+            layout: StoreLayout::default(),
+            index: IndexConfig::default(),
+        }
+    }
+}
+
+/// A shard serves the requests sent to it, either on a thread of this process
+/// or on a remote node of the cluster
+pub(crate) trait ShardHandle: Send + Sync {
+    /// Queues of the requests waiting to be served by the shard
+    fn lanes(&self) -> &ShardLanes;
+
+    fn health(&self) -> ShardHealth;
+
+    fn shard_id(&self) -> u16 {
+        self.lanes().shard_id
+    }
+
+    fn send(&self, request: BackendRequest, admission: Admission) -> Result<(), BackendError> {
+        self.lanes().send(request, admission)
+    }
+
+    /// Send a request that is part of a query fanned out to every shard. An unavailable
+    /// shard doesn't fail the query, it is reported as unfinished instead.
+    fn fan_out(&self, request: BackendRequest, admission: Admission) -> Result<(), BackendError> {
+        match self.send(request, admission) {
+            Err(BackendError::Unavailable(_)) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.health().healthy
+    }
+
+    /// Writes the handle accepted but which never reached the shard, e.g. because its node was
+    /// unreachable. Their senders aren't told about it, the shard missed them for good.
+    fn lost_writes(&self) -> usize {
+        0
+    }
+
+    fn replication_status(&self) -> Option<ReplicationStatus> {
+        None
+    }
+
+    fn promote(&self, _replica: usize) -> Result<(), String> {
+        Err(format!("Error shard {} is not replicated", self.shard_id()))
+    }
+}
+
+/// Sending side of the two priority lanes of a shard
+pub(crate) struct ShardLanes {
+    shard_id: u16,
+    reads: Sender<BackendRequest>,
+    writes: Sender<BackendRequest>,
+    read_depth: IntGauge,
+    write_depth: IntGauge,
+}
+
+impl ShardLanes {
+    /// Create the lanes of a shard, returning the read and write receivers along with them
+    pub(crate) fn new(shard_id: u16, queue_size: usize) -> (ShardLanes, Receiver<BackendRequest>, Receiver<BackendRequest>) {
+        let (reads, read_r) = bounded(queue_size);
+        let (writes, write_r) = bounded(queue_size);
+        let lanes = ShardLanes {
+            shard_id,
+            reads,
+            writes,
+            read_depth: SHARD_QUEUE_DEPTH.with_label_values(&[&shard_id.to_string(), "read"]),
+            write_depth: SHARD_QUEUE_DEPTH.with_label_values(&[&shard_id.to_string(), "write"]),
+        };
+        (lanes, read_r, write_r)
+    }
+
+    pub(crate) fn send(&self, request: BackendRequest, admission: Admission) -> Result<(), BackendError> {
+        let (lane, depth) = match request.is_write() {
+            true => (&self.writes, &self.write_depth),
+            false => (&self.reads, &self.read_depth),
+        };
+        match admission {
+            Admission::Block => lane.send(request).map_err(|_| BackendError::Unavailable(self.shard_id))?,
+            Admission::Reject => match lane.try_send(request) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    SHARD_REJECTED_REQUESTS.inc();
+                    return Err(BackendError::Overloaded(self.shard_id));
+                }
+                Err(TrySendError::Disconnected(_)) => return Err(BackendError::Unavailable(self.shard_id)),
+            },
+        };
+        depth.set(lane.len() as i64);
+        Ok(())
+    }
+
+    pub(crate) fn pending_reads(&self) -> usize {
+        self.reads.len()
+    }
+
+    pub(crate) fn pending_writes(&self) -> usize {
+        self.writes.len()
+    }
+}
+
+/// Handle to a shard thread of this process
+struct LocalShard {
+    lanes: ShardLanes,
+    health: Arc<Mutex<ShardHealth>>,
+    budget: Arc<MemoryBudget>,
+}
+
+/// Memory used by the local shards and the symbols they share, checked before accepting
+/// new records. The usage is reported by the shards after each write so a limit can be
+/// overshot by the writes already queued.
+pub(crate) struct MemoryBudget {
+    limit: Option<usize>,
+    // Summed over the shards
+    used: AtomicUsize,
+    symbols: Arc<SymbolTable>,
+}
+
+impl MemoryBudget {
+    fn new(limit: Option<usize>) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            used: AtomicUsize::new(0),
+            symbols: Arc::new(SymbolTable::new()),
+        }
+    }
+
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed) + self.symbols.memory_usage()
+    }
+
+    fn check(&self) -> Result<(), BackendError> {
+        match self.limit {
+            Some(limit) if self.used() >= limit => Err(BackendError::StoreFull { used: self.used(), limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Memory usage of a shard as last reported to the gauges and the budget,
+/// withdrawn from the budget when the shard stops or crashes
+struct MemoryReport {
+    budget: Arc<MemoryBudget>,
+    reported: usize,
+    gauges: [IntGauge; 3],
+    symbols_gauge: IntGauge,
+}
+
+impl MemoryReport {
+    fn new(shard_id: u16, budget: Arc<MemoryBudget>) -> MemoryReport {
+        let shard = shard_id.to_string();
+        let gauge = |component| MEMORY_USAGE_BYTES.with_label_values(&[&shard, component]);
+        MemoryReport {
+            budget,
+            reported: 0,
+            gauges: [gauge("records"), gauge("record_table"), gauge("index")],
+            symbols_gauge: MEMORY_USAGE_BYTES.with_label_values(&["shared", "symbols"]),
+        }
+    }
+
+    fn update(&mut self, usage: MemoryUsage) {
+        let components = [usage.records, usage.record_table, usage.index];
+        self.gauges.iter().zip(components).for_each(|(gauge, bytes)| gauge.set(bytes as i64));
+        let total = usage.total();
+        if total >= self.reported {
+            self.budget.used.fetch_add(total - self.reported, Ordering::Relaxed);
+        } else {
+            self.budget.used.fetch_sub(self.reported - total, Ordering::Relaxed);
+        }
+        self.reported = total;
+        self.symbols_gauge.set(self.budget.symbols.memory_usage() as i64);
+    }
+}
+
+impl Drop for MemoryReport {
+    fn drop(&mut self) {
+        self.update(MemoryUsage::default());
+    }
+}
+
+/// Receiving side of the shard queues, kept by the supervisor across restarts
+struct ShardQueues {
+    reads: Receiver<BackendRequest>,
+    writes: Receiver<BackendRequest>,
+    read_depth: IntGauge,
+    write_depth: IntGauge,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ShardHealth {
+    pub shard_id: u16,
+    /// False once the shard crashed: it was restarted but lost the records it held,
+    /// or when the node hosting a remote shard can't be reached
+    pub healthy: bool,
+    pub crashes: u32,
+    pub last_crash: Option<String>,
+}
+
+impl ShardHealth {
+    pub(crate) fn new(shard_id: u16) -> ShardHealth {
+        ShardHealth {
+            shard_id,
+            healthy: true,
+            crashes: 0,
+            last_crash: None,
+        }
+    }
+}
+
+impl ShardHandle for LocalShard {
+    fn lanes(&self) -> &ShardLanes {
+        &self.lanes
+    }
+
+    fn health(&self) -> ShardHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    fn send(&self, request: BackendRequest, admission: Admission) -> Result<(), BackendError> {
+        // Migrated records were already accepted, only new records are limited
+        if let BackendRequest::AddRequest { .. } | BackendRequest::RawAddRequest { .. } | BackendRequest::BulkAddRequest { .. } = request {
+            self.budget.check()?;
+        }
+        self.lanes.send(request, admission)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ShardedStorageBackendStatus {
     shard_status: SingleStorageBackendStatus,
-    shard_id: u16
+    pub(crate) shard_id: u16,
+    placement: ShardPlacement,
+    // Address of the node hosting the shard, None when it runs in this process
+    #[serde(default)]
+    pub(crate) node: Option<String>,
+}
+
+/// Index statistics of a key on one shard
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ShardKeyStatus {
+    pub(crate) shard_id: u16,
+    // None when no record of the shard has the key
+    pub(crate) status: Option<index::KeyStatus>,
+}
+
+/// Index statistics of a key over every shard
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyStatusReport {
+    pub(crate) key: String,
+    // Summed over the shards, a value stored by several shards is counted several times
+    pub(crate) values: usize,
+    pub(crate) postings: u64,
+    pub(crate) bitmap_bytes: usize,
+    pub(crate) tree_bytes: usize,
+    // Merged from the top values of each shard, so values just below the top of every shard are missed
+    pub(crate) top_values: Vec<(Arc<str>, u64)>,
+    pub(crate) shards: Vec<ShardKeyStatus>,
+}
+
+/// Where a shard thread effectively runs
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ShardPlacement {
+    thread_name: String,
+    // Core the thread is pinned to, None if it is free to move
+    core: Option<usize>,
+    numa_node: Option<usize>,
+}
+
+/// How shard threads are pinned to CPU cores
+#[derive(Clone, Debug, PartialEq)]
+pub enum CpuPinning {
+    None,
+    /// Spread shards over all the cores, one shard per core
+    Auto,
+    /// Pin shard N to the Nth core of the list (wrapping around)
+    Cores(Vec<usize>),
+}
+
+impl FromStr for CpuPinning {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(CpuPinning::None),
+            "auto" => Ok(CpuPinning::Auto),
+            cores => cores
+                .split(',')
+                .map(|core| {
+                    core.trim()
+                        .parse()
+                        .map_err(|_| format!("Error bad core id: {} (expecting none, auto or a list like 0,2,4)", core))
+                })
+                .collect::<Result<Vec<usize>, String>>()
+                .map(CpuPinning::Cores),
+        }
+    }
+}
+
+impl CpuPinning {
+    fn core_for(&self, shard_id: u16) -> Option<usize> {
+        let cores = match self {
+            CpuPinning::None => return None,
+            CpuPinning::Auto => core_affinity::get_core_ids()?.into_iter().map(|c| c.id).collect(),
+            CpuPinning::Cores(cores) => cores.clone(),
+        };
+        if cores.is_empty() {
+            return None;
+        }
+        Some(cores[shard_id as usize % cores.len()])
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShardedStorageStatus {
+    shards: Vec<ShardedStorageBackendStatus>,
+    pub(crate) health: Vec<ShardHealth>,
+    pub(crate) replication: Vec<ReplicationStatus>,
+    resharding: Option<ReshardingStatus>,
+    // Keys and values shared by the local shards
+    symbols: SymbolTableStatus,
+}
+
+/// Progress of the last resharding, kept once it is done
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReshardingStatus {
+    from_shards: u16,
+    to_shards: u16,
+    migrated_shards: u16,
+    total_records: usize,
+    migrated_records: usize,
+    elapsed_ms: u128,
+    done: bool,
+    /// Set when the migration failed, the shards being left as they were
+    error: Option<String>,
+}
+
+/// Global ids address a record across all shards: the upper 32 bits hold
+/// the shard id and the lower 32 bits the id local to that shard
+pub fn to_global_id(shard_id: u16, local_id: u32) -> u64 {
+    ((shard_id as u64) << 32) | local_id as u64
 }
 
+pub fn from_global_id(id: u64) -> (u16, u32) {
+    ((id >> 32) as u16, id as u32)
+}
+
+/// Records are routed by a hash of their content so that a given record always
+/// lands on the same shard, whether it is ingested or migrated during a resharding
+fn route<'a>(hasher: &AHasher, label_pairs: impl Iterator<Item = (&'a str, &'a str)>, shard_count: usize) -> usize {
+    let mut hasher = hasher.clone();
+    label_pairs.for_each(|(key, val)| {
+        hasher.write_usize(key.len());
+        hasher.write(key.as_bytes());
+        hasher.write_usize(val.len());
+        hasher.write(val.as_bytes());
+    });
+    hasher.finish() as usize % shard_count
+}
+
+fn spawn_shards(shard_count: u16, config: &ShardedStorageBackendConfig, budget: &Arc<MemoryBudget>) -> Vec<Box<dyn ShardHandle>> {
+    (0..shard_count)
+        .map(|i| {
+            let (lanes, read_r, write_r) = ShardLanes::new(i, config.queue_size);
+            let queues = ShardQueues {
+                reads: read_r,
+                writes: write_r,
+                read_depth: lanes.read_depth.clone(),
+                write_depth: lanes.write_depth.clone(),
+            };
+            let shard = LocalShard {
+                lanes,
+                health: Arc::new(Mutex::new(ShardHealth::new(i))),
+                budget: budget.clone(),
+            };
+            let core = config.pinning.core_for(i);
+            let health = shard.health.clone();
+            let budget = budget.clone();
+            let layout = config.layout;
+            let index_config = config.index.clone();
+            install_crash_hook();
+            thread::Builder::new()
+                .name(format!("shard-{}", i))
+                .spawn(move || supervise_shard(queues, i, core, health, budget, layout, index_config))
+                .unwrap();
+            Box::new(shard) as Box<dyn ShardHandle>
+        })
+        .collect()
+}
+
+/// Pin the current thread, returning the core it is effectively pinned to
+fn pin_current_thread(core: Option<usize>) -> Option<usize> {
+    let core = core?;
+    if core_affinity::set_for_current(core_affinity::CoreId { id: core }) {
+        Some(core)
+    } else {
+        warn!("Could not pin thread {:?} to core {}", thread::current().name(), core);
+        None
+    }
+}
+
+fn numa_node_of(core: usize) -> Option<usize> {
+    std::fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", core))
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.strip_prefix("node")?.parse().ok())
+        .next()
+}
+
+fn panic_message(cause: &(dyn std::any::Any + Send)) -> String {
+    match cause.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => cause.downcast_ref::<String>().cloned().unwrap_or_else(|| String::from("unknown cause")),
+    }
+}
+
+thread_local! {
+    // Health of the shard running on the current thread, if any
+    static SHARD_HEALTH: RefCell<Option<Arc<Mutex<ShardHealth>>>> = const { RefCell::new(None) };
+}
+
+static CRASH_HOOK: Once = Once::new();
+
+/// Record shard crashes from the panic hook: it runs before unwinding drops the response
+/// channels of the request being served, so callers already see the shard as degraded.
+fn install_crash_hook() {
+    CRASH_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            SHARD_HEALTH.with(|health| {
+                if let Some(health) = health.borrow().as_ref() {
+                    if let Ok(mut health) = health.lock() {
+                        health.healthy = false;
+                        health.crashes += 1;
+                        health.last_crash = Some(panic_message(info.payload()));
+                    }
+                }
+            });
+            default_hook(info);
+        }));
+    });
+}
+
+/// Run the shard and restart it on the same queues if it panics, so that queued
+/// requests are still served and callers never wait on a dead shard. There is no
+/// persistence to reload from yet: a restarted shard starts empty and is flagged unhealthy.
+fn supervise_shard(
+    queues: ShardQueues,
+    shard_id: u16,
+    core: Option<usize>,
+    health: Arc<Mutex<ShardHealth>>,
+    budget: Arc<MemoryBudget>,
+    layout: StoreLayout,
+    index_config: IndexConfig,
+) {
+    // Pin before creating the backend so its memory is allocated on the local NUMA node
+    let core = pin_current_thread(core);
+    let placement = ShardPlacement {
+        thread_name: thread::current().name().unwrap_or_default().to_string(),
+        core,
+        numa_node: core.and_then(numa_node_of),
+    };
+    SHARD_HEALTH.with(|current| *current.borrow_mut() = Some(health));
+    loop {
+        match panic::catch_unwind(AssertUnwindSafe(|| shard_handler(&queues, shard_id, &placement, &budget, layout, &index_config))) {
+            Ok(()) => break,
+            Err(cause) => {
+                error!("Shard {} crashed ({}), restarting it empty", shard_id, panic_message(cause.as_ref()));
+                SHARD_CRASHES.inc();
+                budget.symbols.sweep();
+            }
+        }
+    }
+}
 
-fn shard_handler(request_rcv: Receiver<BackendRequest>, shard_id: u16) {
-    let mut backend = SingleStorageBackend::new();
+fn shard_handler(
+    queues: &ShardQueues,
+    shard_id: u16,
+    placement: &ShardPlacement,
+    budget: &Arc<MemoryBudget>,
+    layout: StoreLayout,
+    index_config: &IndexConfig,
+) {
+    let ShardQueues {
+        reads,
+        writes,
+        read_depth,
+        write_depth,
+    } = queues;
+    let mut backend = SingleStorageBackend::new_with(budget.symbols.clone(), layout, index_config.clone());
+    let mut memory = MemoryReport::new(shard_id, budget.clone());
     let mut start;
-    let mut request;
     loop {
-        request = request_rcv.recv().unwrap();
+        // Reads are served first, writes are picked up when no read is pending
+        let request = match reads.try_recv() {
+            Ok(request) => Ok(request),
+            Err(_) => select! {
+                recv(reads) -> request => request,
+                recv(writes) -> request => request,
+            },
+        };
+        // The shard stops once its senders are dropped (e.g. after a resharding)
+        let request = match request {
+            Ok(request) => request,
+            Err(_) => break,
+        };
+        read_depth.set(reads.len() as i64);
+        write_depth.set(writes.len() as i64);
+        let is_write = request.is_write();
         start = Instant::now();
         match request {
             BackendRequest::StatusRequest { response_chan } => {
-                response_chan.send(ShardedStorageBackendStatus {shard_status: backend.get_status(), shard_id}).unwrap();
+                response_chan
+                    .send(ShardedStorageBackendStatus {
+                        shard_status: backend.get_status(),
+                        shard_id,
+                        placement: placement.clone(),
+                        node: None,
+                    })
+                    .ok();
             }
-            BackendRequest::RawAddRequest { line } => {
-                backend.raw_add(line);
+            BackendRequest::RawAddRequest { record } => {
+                backend.add(record);
                 LOCAL_SHARD_LATENCY_HISTOGRAM.raw_add.observe(start.elapsed().as_secs_f64());
             }
+            BackendRequest::MigrateRequest { record } => {
+                backend.add_rcrecord(&record);
+                LOCAL_SHARD_LATENCY_HISTOGRAM.migrate.observe(start.elapsed().as_secs_f64());
+            }
+            BackendRequest::ScanRequest {
+                query,
+                from,
+                limit,
+                response_chan,
+            } => {
+                backend.scan(&query, from, limit).into_iter().try_for_each(|x| response_chan.send(x)).ok();
+                LOCAL_SHARD_LATENCY_HISTOGRAM.scan.observe(start.elapsed().as_secs_f64());
+            }
+            BackendRequest::IdsRequest { query, response_chan } => {
+                response_chan.send(backend.ids(&query)).ok();
+                LOCAL_SHARD_LATENCY_HISTOGRAM.scan.observe(start.elapsed().as_secs_f64());
+            }
+            BackendRequest::FlushRequest { response_chan } => {
+                response_chan.send(()).ok();
+            }
             BackendRequest::AddRequest { record, response_chan } => {
-                response_chan.send(backend.add(record)).unwrap();
+                response_chan.send(backend.add(record)).ok();
                 LOCAL_SHARD_LATENCY_HISTOGRAM.add.observe(start.elapsed().as_secs_f64());
             }
+            BackendRequest::BulkAddRequest { records, response_chan } => {
+                let added = records.into_iter().filter_map(|record| backend.add(record)).count();
+                response_chan.send(added).ok();
+                LOCAL_SHARD_LATENCY_HISTOGRAM.bulk_add.observe(start.elapsed().as_secs_f64());
+            }
+            BackendRequest::GetRequest { ids, response_chan } => {
+                // Sending stops early if the caller gave up on the response
+                backend
+                    .multi_get(ids)
+                    .into_iter()
+                    .try_for_each(|(id, record)| {
+                        response_chan.send(record::IdentifiedRecord {
+                            id: to_global_id(shard_id, id),
+                            record,
+                            score: None,
+                            fuzzy_matches: Vec::new(),
+                        })
+                    })
+                    .ok();
+                LOCAL_SHARD_LATENCY_HISTOGRAM.get.observe(start.elapsed().as_secs_f64());
+            }
             BackendRequest::SearchRequest { query, response_chan } => {
-                backend.search(query).into_iter().for_each(|x| {
-                    response_chan.send(x).unwrap();
-                });
+                // Queries which expired while queued are not even started
+                if !query.is_expired() {
+                    let deadline = query.deadline;
+                    // Only the first record of each distinct tuple counts against the limit, the
+                    // tuples are deduped after reading every matching record
+                    let distinct = query.projection.clone().filter(|projection| projection.distinct);
+                    let limit = query.limit.filter(|_| distinct.is_none());
+                    // Text searches keep their best records, the others the first ones
+                    let mut records: Vec<_> = match (&query.text_stats, limit) {
+                        (Some(stats), limit) => backend
+                            .ranked_search(&query, stats, limit.unwrap_or(usize::MAX))
+                            .into_iter()
+                            .map(|(id, record, score)| (id, record, Some(score)))
+                            .collect(),
+                        (None, Some(limit)) => backend.scan(&query, 0, limit).into_iter().map(|(id, record)| (id, record, None)).collect(),
+                        (None, None) => backend.search(query.clone()).into_iter().map(|(id, record)| (id, record, None)).collect(),
+                    };
+                    if let Some(projection) = distinct {
+                        let mut tuples = HashSet::new();
+                        records.retain(|(_, record, _)| tuples.insert(record.project(&projection.keys)));
+                        records.truncate(query.limit.unwrap_or(usize::MAX));
+                    }
+                    records
+                        .into_iter()
+                        .try_for_each(|(id, record, score)| {
+                            response_chan.send(ShardMessage::Item(record::IdentifiedRecord {
+                                id: to_global_id(shard_id, id),
+                                record,
+                                score,
+                                fuzzy_matches: Vec::new(),
+                            }))
+                        })
+                        .ok();
+                    response_chan
+                        .send(ShardMessage::Done {
+                            shard_id,
+                            timed_out: query::is_expired(deadline),
+                        })
+                        .ok();
+                } else {
+                    response_chan.send(ShardMessage::Done { shard_id, timed_out: true }).ok();
+                }
                 LOCAL_SHARD_LATENCY_HISTOGRAM.search.observe(start.elapsed().as_secs_f64());
             }
+            BackendRequest::CountRequest { query, response_chan } => {
+                if !query.is_expired() {
+                    let deadline = query.deadline;
+                    response_chan.send(ShardMessage::Item(backend.count(query))).ok();
+                    response_chan
+                        .send(ShardMessage::Done {
+                            shard_id,
+                            timed_out: query::is_expired(deadline),
+                        })
+                        .ok();
+                } else {
+                    response_chan.send(ShardMessage::Done { shard_id, timed_out: true }).ok();
+                }
+                LOCAL_SHARD_LATENCY_HISTOGRAM.count.observe(start.elapsed().as_secs_f64());
+            }
+            BackendRequest::TextStatsRequest { query, response_chan } => {
+                if !query.is_expired() {
+                    let deadline = query.deadline;
+                    response_chan.send(ShardMessage::Item(backend.text_stats(&query))).ok();
+                    response_chan
+                        .send(ShardMessage::Done {
+                            shard_id,
+                            timed_out: query::is_expired(deadline),
+                        })
+                        .ok();
+                } else {
+                    response_chan.send(ShardMessage::Done { shard_id, timed_out: true }).ok();
+                }
+                LOCAL_SHARD_LATENCY_HISTOGRAM.text_stats.observe(start.elapsed().as_secs_f64());
+            }
             BackendRequest::KeyValuesSearchRequest { query, response_chan } => {
-                backend.key_values_search(query).into_iter().for_each(|x| {
-                    response_chan.send(x).unwrap();
-                });
+                if !query.is_expired() {
+                    let deadline = query.deadline;
+                    backend
+                        .key_values_search(query)
+                        .into_iter()
+                        .try_for_each(|x| response_chan.send(ShardMessage::Item(x)))
+                        .ok();
+                    response_chan
+                        .send(ShardMessage::Done {
+                            shard_id,
+                            timed_out: query::is_expired(deadline),
+                        })
+                        .ok();
+                } else {
+                    response_chan.send(ShardMessage::Done { shard_id, timed_out: true }).ok();
+                }
                 LOCAL_SHARD_LATENCY_HISTOGRAM.key_values_search.observe(start.elapsed().as_secs_f64());
             }
+            BackendRequest::KeyStatusRequest { key, top, response_chan } => {
+                response_chan
+                    .send(ShardKeyStatus {
+                        shard_id,
+                        status: backend.get_key_status(&key, top),
+                    })
+                    .ok();
+            }
         };
+        if is_write {
+            memory.update(backend.memory_usage());
+        }
     }
+    // Release the symbols of the records this shard held (e.g. after a resharding)
+    drop(backend);
+    drop(memory);
+    budget.symbols.sweep();
 }
 
 pub struct ShardedStorageBackend {
-    shards: Vec<Sender<BackendRequest>>,
+    shards: Vec<Box<dyn ShardHandle>>,
+    // Shards being populated during a resharding, writes go to both layouts
+    next_shards: Option<Vec<Box<dyn ShardHandle>>>,
+    resharding: Mutex<Option<ReshardingStatus>>,
+    config: ShardedStorageBackendConfig,
+    // Memory used by the local shards, including the ones being populated by a resharding
+    budget: Arc<MemoryBudget>,
+    // Membership of the cluster when shards are hosted by remote nodes
+    cluster: Option<ClusterConfig>,
     hasher: AHasher,
+    // Bumped each time a resharding swaps the shards, the ids read before it are meaningless after
+    layout: u64,
+}
+
+/// Ids of the records matching an export selector on each shard, read back batch by batch so
+/// that every pass over an export sees the same records
+pub struct ExportSnapshot {
+    layout: u64,
+    shard_ids: Vec<Vec<u32>>,
 }
 
 impl ShardedStorageBackend {
+    /// Create a backend with one shard per available CPU
+    pub fn new() -> ShardedStorageBackend {
+        ShardedStorageBackend::new_with_config(ShardedStorageBackendConfig::default())
+    }
+
     pub fn new_with_cpus(num_cpu: u16) -> ShardedStorageBackend {
-        // TODO add auto discover feature
-        let mut shards: Vec<Sender<BackendRequest>> = vec![];
-        for i in 0..num_cpu {
-            let (s, r) = bounded(10000);
-            spawn(move || shard_handler(r, i));
-            shards.push(s);
+        ShardedStorageBackend::new_with_config(ShardedStorageBackendConfig {
+            shards: num_cpu,
+            ..ShardedStorageBackendConfig::default()
+        })
+    }
+
+    pub fn new_with_config(config: ShardedStorageBackendConfig) -> ShardedStorageBackend {
+        let budget = Arc::new(MemoryBudget::new(config.max_memory));
+        ShardedStorageBackend {
+            shards: spawn_shards(config.shards, &config, &budget),
+            next_shards: None,
+            resharding: Mutex::new(None),
+            config,
+            budget,
+            cluster: None,
+            hasher: AHasher::new_with_keys(0, 0),
+            layout: 0,
         }
+    }
+
+    /// Create a coordinator whose shards are hosted by the nodes of the cluster.
+    /// Shard ids are given in the order of the membership, node after node.
+    pub fn new_cluster(cluster: ClusterConfig, config: ShardedStorageBackendConfig) -> ShardedStorageBackend {
         ShardedStorageBackend {
-            shards,
+            shards: cluster::connect(&cluster, &config),
+            next_shards: None,
+            resharding: Mutex::new(None),
+            budget: Arc::new(MemoryBudget::new(config.max_memory)),
+            config,
+            cluster: Some(cluster),
             hasher: AHasher::new_with_keys(0, 0),
+            layout: 0,
         }
     }
 
+    /// Make a replica of the shard its leader
+    pub fn promote(&self, shard_id: u16, replica: usize) -> Result<(), String> {
+        match self.shards.get(shard_id as usize) {
+            Some(shard) => shard.promote(replica),
+            None => Err(format!("Error shard {} doesn't exist", shard_id)),
+        }
+    }
+
+    pub(crate) fn shard(&self, shard_id: u16) -> Option<&dyn ShardHandle> {
+        self.shards.get(shard_id as usize).map(|shard| shard.as_ref())
+    }
+
+    /// Add a record in the text format, waiting if the shard queue is full.
+    /// Invalid records are logged and dropped.
     pub fn raw_add(&self, line: String) {
-        let mut hasher = self.hasher.clone();
-        hasher.write(line.as_bytes());
-        let hash = hasher.finish();
-        self.shards[hash as usize % self.shards.len()]
-            .send(BackendRequest::RawAddRequest { line })
-            .unwrap();
+        if let Err(e) = self.add_with(line.as_str(), Admission::Block) {
+            error!("‡{} (on record {})", e, line);
+        }
+    }
+
+    /// Same as raw_add but fails instead of waiting if the shard queue is full
+    pub fn try_raw_add(&self, line: &str) -> Result<(), BackendError> {
+        self.add_with(line, Admission::Reject)
+    }
+
+    fn add_with(&self, line: &str, admission: Admission) -> Result<(), BackendError> {
+        let record = lexer::parse_record(line).map_err(BackendError::InvalidRecord)?;
+        let pairs = record.label_pairs.iter().map(|l| (l.key.as_str(), l.val.as_str()));
+        let shard = &self.shards[route(&self.hasher, pairs, self.shards.len())];
+        match &self.next_shards {
+            Some(next_shards) => {
+                // Admission is checked on the current layout only: once accepted the
+                // record must reach the next layout as well or it would be lost on swap
+                shard.send(BackendRequest::RawAddRequest { record: record.clone() }, admission)?;
+                let pairs = record.label_pairs.iter().map(|l| (l.key.as_str(), l.val.as_str()));
+                next_shards[route(&self.hasher, pairs, next_shards.len())].send(BackendRequest::RawAddRequest { record }, Admission::Block)
+            }
+            None => shard.send(BackendRequest::RawAddRequest { record }, admission),
+        }
+    }
+
+    /// Add parsed records with one request per shard, waiting if shard queues are full.
+    /// Once a shard applied its part, it sends the number of records it actually added on `added`.
+    /// On error, the number of records which weren't sent along with the first error, the parts of
+    /// the other shards being added all the same.
+    pub(crate) fn bulk_add(&self, records: Vec<record::SmallRecord>, added: &Sender<usize>) -> Result<(), (usize, BackendError)> {
+        if let Some(next_shards) = &self.next_shards {
+            // Only the current layout reports what it added
+            let (ignored, _) = bounded(1);
+            let count = records.len();
+            self.send_batches(next_shards, self.split_per_shard(next_shards, records.clone()), &ignored).map_err(|(_, e)| (count, e))?;
+        }
+        self.send_batches(&self.shards, self.split_per_shard(&self.shards, records), added)
+    }
+
+    fn split_per_shard(&self, shards: &[Box<dyn ShardHandle>], records: Vec<record::SmallRecord>) -> Vec<Vec<record::SmallRecord>> {
+        let mut batches: Vec<Vec<record::SmallRecord>> = shards.iter().map(|_| Vec::new()).collect();
+        records.into_iter().for_each(|record| {
+            let pairs = record.label_pairs.iter().map(|l| (l.key.as_str(), l.val.as_str()));
+            batches[route(&self.hasher, pairs, shards.len())].push(record);
+        });
+        batches
+    }
+
+    fn send_batches(
+        &self,
+        shards: &[Box<dyn ShardHandle>],
+        batches: Vec<Vec<record::SmallRecord>>,
+        added: &Sender<usize>,
+    ) -> Result<(), (usize, BackendError)> {
+        let mut failed = 0;
+        let mut first_error = None;
+        for (shard, records) in shards.iter().zip(batches).filter(|(_, records)| !records.is_empty()) {
+            let count = records.len();
+            let request = BackendRequest::BulkAddRequest {
+                records,
+                response_chan: added.clone(),
+            };
+            if let Err(e) = shard.send(request, Admission::Block) {
+                failed += count;
+                first_error.get_or_insert(e);
+            }
+        }
+        match first_error {
+            Some(e) => Err((failed, e)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn shard_count(&self) -> u16 {
+        self.shards.len() as u16
+    }
+
+    pub fn is_resharding(&self) -> bool {
+        self.resharding.lock().unwrap().as_ref().is_some_and(|status| !status.done)
+    }
+
+    /// Change the number of shards while the current layout keeps serving requests.
+    /// Records are migrated in the background to a new set of shards which replaces
+    /// the current one when the migration is over. Global ids change in the process.
+    pub fn reshard(storage: &Arc<RwLock<ShardedStorageBackend>>, shard_count: u16) -> Result<(), String> {
+        if shard_count == 0 {
+            return Err(String::from("Error a backend needs at least one shard"));
+        }
+        // Checked under a read lock first: waiting for the write lock while a
+        // migration holds the read one would block every search
+        if storage.read().unwrap().cluster.is_some() {
+            return Err(String::from("Error resharding is not supported in cluster mode, change the membership instead"));
+        }
+        if storage.read().unwrap().is_resharding() {
+            return Err(String::from("Error a resharding is already in progress"));
+        }
+        {
+            let mut backend = storage.write().unwrap();
+            if backend.next_shards.is_some() {
+                return Err(String::from("Error a resharding is already in progress"));
+            }
+            let total_records = backend.get_shards_status().iter().map(|s| s.shard_status.record_count()).sum();
+            info!("Resharding {} records from {} to {} shards", total_records, backend.shards.len(), shard_count);
+            *backend.resharding.lock().unwrap() = Some(ReshardingStatus {
+                from_shards: backend.shard_count(),
+                to_shards: shard_count,
+                migrated_shards: 0,
+                total_records,
+                migrated_records: 0,
+                elapsed_ms: 0,
+                done: false,
+                error: None,
+            });
+            backend.next_shards = Some(spawn_shards(shard_count, &backend.config, &backend.budget));
+        }
+        let storage = storage.clone();
+        spawn(move || migrate(storage));
+        Ok(())
     }
 
     // pub fn add(&self, record: record::Record) -> Option<u32> {
@@ -116,51 +1107,677 @@ impl ShardedStorageBackend {
     //     r.recv().unwrap()
     // }
 
-    pub fn get_status(&self) -> Vec<ShardedStorageBackendStatus> {
+    pub fn get_status(&self) -> ShardedStorageStatus {
+        ShardedStorageStatus {
+            shards: self.get_shards_status(),
+            health: self.shards.iter().map(|shard| shard.health()).collect(),
+            replication: self.shards.iter().filter_map(|shard| shard.replication_status()).collect(),
+            resharding: self.resharding.lock().unwrap().clone(),
+            symbols: self.budget.symbols.get_status(),
+        }
+    }
+
+    /// Drill into a key of the index, with its `top` values having the largest posting lists
+    pub fn get_key_status(&self, key: &str, top: usize) -> KeyStatusReport {
         let (s, r) = bounded(self.shards.len());
-        (&self.shards).iter().for_each(|shard| {
-            shard.send(BackendRequest::StatusRequest { response_chan: s.clone() }).unwrap();
+        self.shards.iter().for_each(|shard| {
+            let request = BackendRequest::KeyStatusRequest {
+                key: key.to_string(),
+                top,
+                response_chan: s.clone(),
+            };
+            shard.fan_out(request, Admission::Block).ok();
+        });
+        drop(s);
+        let mut shards: Vec<ShardKeyStatus> = r.iter().collect();
+        shards.sort_by_key(|shard| shard.shard_id);
+        let statuses = || shards.iter().filter_map(|shard| shard.status.as_ref());
+        let mut top_values: HashMap<Arc<str>, u64> = HashMap::new();
+        statuses()
+            .flat_map(|status| status.top_values.iter())
+            .for_each(|(value, postings)| *top_values.entry(value.clone()).or_default() += postings);
+        let mut top_values: Vec<(Arc<str>, u64)> = top_values.into_iter().collect();
+        top_values.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_values.truncate(top);
+        KeyStatusReport {
+            key: key.to_string(),
+            values: statuses().map(|status| status.values).sum(),
+            postings: statuses().map(|status| status.postings).sum(),
+            bitmap_bytes: statuses().map(|status| status.bitmap_bytes).sum(),
+            tree_bytes: statuses().map(|status| status.tree_bytes).sum(),
+            top_values,
+            shards,
+        }
+    }
+
+    fn get_shards_status(&self) -> Vec<ShardedStorageBackendStatus> {
+        let (s, r) = bounded(self.shards.len());
+        self.shards.iter().for_each(|shard| {
+            shard
+                .fan_out(BackendRequest::StatusRequest { response_chan: s.clone() }, Admission::Block)
+                .ok();
         });
         drop(s);
         r.iter().collect()
     }
 
-    pub fn search(&self, search_query: query::Search) -> Vec<Arc<record::RCRecord>> {
+    pub fn get(&self, id: u64) -> Option<record::IdentifiedRecord> {
+        self.multi_get(vec![id]).pop()
+    }
+
+    pub fn try_get(&self, id: u64) -> Result<Option<record::IdentifiedRecord>, BackendError> {
+        Ok(self.try_multi_get(vec![id])?.pop())
+    }
+
+    /// Fetch records by global id, each id being routed to its owning shard.
+    /// Records are returned in the requested order, unknown ids are skipped.
+    pub fn multi_get(&self, ids: Vec<u64>) -> Vec<record::IdentifiedRecord> {
+        self.multi_get_with(ids, Admission::Block).unwrap()
+    }
+
+    pub fn try_multi_get(&self, ids: Vec<u64>) -> Result<Vec<record::IdentifiedRecord>, BackendError> {
+        self.multi_get_with(ids, Admission::Reject)
+    }
+
+    fn multi_get_with(&self, ids: Vec<u64>, admission: Admission) -> Result<Vec<record::IdentifiedRecord>, BackendError> {
+        let mut per_shard_ids: HashMap<u16, Vec<u32>> = HashMap::new();
+        ids.iter().for_each(|id| {
+            let (shard_id, local_id) = from_global_id(*id);
+            if (shard_id as usize) < self.shards.len() {
+                per_shard_ids.entry(shard_id).or_default().push(local_id);
+            }
+        });
+
         let (s, r) = bounded(1000);
-        (&self.shards).iter().for_each(|shard| {
-            shard
-                .send(BackendRequest::SearchRequest {
+        per_shard_ids.into_iter().try_for_each(|(shard_id, ids)| {
+            self.shards[shard_id as usize].send(
+                BackendRequest::GetRequest {
+                    ids,
+                    response_chan: s.clone(),
+                },
+                admission,
+            )
+        })?;
+        drop(s);
+        let found: HashMap<u64, record::IdentifiedRecord> = r.iter().map(|x| (x.id, x)).collect();
+        Ok(ids.iter().filter_map(|id| found.get(id).cloned()).collect())
+    }
+
+    pub fn search(&self, search_query: query::Search) -> Vec<record::IdentifiedRecord> {
+        self.search_with(search_query, Admission::Block).unwrap().data
+    }
+
+    /// Same as search but fails instead of waiting if a shard queue is full. Once
+    /// the deadline of the query is reached, the results found so far are returned.
+    /// Text searches are ranked by relevance, best records first.
+    pub fn try_search(&self, search_query: query::Search) -> Result<ShardedResult<Vec<record::IdentifiedRecord>>, BackendError> {
+        self.search_with(search_query, Admission::Reject)
+    }
+
+    fn search_with(
+        &self,
+        search_query: query::Search,
+        admission: Admission,
+    ) -> Result<ShardedResult<Vec<record::IdentifiedRecord>>, BackendError> {
+        let mut search_query = search_query;
+        let deadline = search_query.deadline;
+        // Shards score their records with the statistics of every shard, so that scores compare
+        let mut stats_unfinished_shards = Vec::new();
+        if search_query.has_text() && search_query.text_stats.is_none() {
+            let stats = self.text_stats_with(&search_query, admission)?;
+            stats_unfinished_shards = stats.unfinished_shards;
+            search_query.text_stats = Some(stats.data);
+        }
+        let (s, r) = bounded(1000);
+        self.shards.iter().try_for_each(|shard| {
+            shard.fan_out(
+                BackendRequest::SearchRequest {
                     query: search_query.clone(),
                     response_chan: s.clone(),
-                })
-                .unwrap();
-        });
+                },
+                admission,
+            )
+        })?;
         drop(s);
-        r.iter().collect()
+        let mut result = collect_shard_messages(r, self.shards.len(), deadline);
+        if search_query.text_stats.is_some() {
+            let score = |record: &record::IdentifiedRecord| record.score.unwrap_or(0.0);
+            result.data.sort_unstable_by(|a, b| score(b).total_cmp(&score(a)).then_with(|| a.id.cmp(&b.id)));
+        }
+        if let Some(projection) = search_query.projection.as_ref().filter(|projection| projection.distinct) {
+            // Tuples found by several shards, deduped before the limit applies
+            let mut tuples = HashSet::new();
+            result.data.retain(|record| tuples.insert(record.record.project(&projection.keys)));
+        }
+        if let Some(limit) = search_query.limit {
+            result.data.truncate(limit);
+        }
+        if search_query.query_flags.contains(query::SearchFlags::REPORT_FUZZY_MATCHES) {
+            let matcher = index::FuzzyMatcher::new(&search_query);
+            result.data.iter_mut().for_each(|record| record.fuzzy_matches = matcher.matches(&record.record));
+        }
+        if let Some(projection) = &search_query.projection {
+            result.data.iter_mut().for_each(|record| record.record = Arc::new(record.record.project(&projection.keys)));
+        }
+        result.unfinished_shards.extend(stats_unfinished_shards);
+        result.unfinished_shards.sort_unstable();
+        result.unfinished_shards.dedup();
+        result.degraded_shards = self.degraded_shards();
+        Ok(result)
+    }
+
+    fn text_stats_with(&self, search_query: &query::Search, admission: Admission) -> Result<ShardedResult<query::TextStats>, BackendError> {
+        let (s, r) = bounded(self.shards.len());
+        self.shards.iter().try_for_each(|shard| {
+            shard.fan_out(
+                BackendRequest::TextStatsRequest {
+                    query: search_query.clone(),
+                    response_chan: s.clone(),
+                },
+                admission,
+            )
+        })?;
+        drop(s);
+        let result = collect_shard_messages(r, self.shards.len(), search_query.deadline);
+        let mut stats = query::TextStats::default();
+        result.data.into_iter().for_each(|shard_stats| stats.merge(shard_stats));
+        Ok(ShardedResult {
+            data: stats,
+            unfinished_shards: result.unfinished_shards,
+            degraded_shards: Vec::new(),
+        })
+    }
+
+    /// Count the records matching the query, summing the counts of every shard
+    pub fn count(&self, search_query: query::Search) -> usize {
+        self.count_with(search_query, Admission::Block).unwrap().data
+    }
+
+    pub fn try_count(&self, search_query: query::Search) -> Result<ShardedResult<usize>, BackendError> {
+        self.count_with(search_query, Admission::Reject)
+    }
+
+    fn count_with(&self, search_query: query::Search, admission: Admission) -> Result<ShardedResult<usize>, BackendError> {
+        let deadline = search_query.deadline;
+        let (s, r) = bounded(self.shards.len());
+        self.shards.iter().try_for_each(|shard| {
+            shard.fan_out(
+                BackendRequest::CountRequest {
+                    query: search_query.clone(),
+                    response_chan: s.clone(),
+                },
+                admission,
+            )
+        })?;
+        drop(s);
+        let result = collect_shard_messages(r, self.shards.len(), deadline);
+        Ok(ShardedResult {
+            data: result.data.into_iter().sum(),
+            unfinished_shards: result.unfinished_shards,
+            degraded_shards: self.degraded_shards(),
+        })
     }
 
     pub fn key_values_search(&self, search_query: query::KeyValuesSearch) -> Vec<Arc<str>> {
+        self.key_values_search_with(search_query, Admission::Block).unwrap().data
+    }
+
+    pub fn try_key_values_search(&self, search_query: query::KeyValuesSearch) -> Result<ShardedResult<Vec<Arc<str>>>, BackendError> {
+        self.key_values_search_with(search_query, Admission::Reject)
+    }
+
+    fn key_values_search_with(
+        &self,
+        search_query: query::KeyValuesSearch,
+        admission: Admission,
+    ) -> Result<ShardedResult<Vec<Arc<str>>>, BackendError> {
+        let deadline = search_query.deadline;
         let (s, r) = bounded(1000);
-        (&self.shards).iter().for_each(|shard| {
-            shard
-                .send(BackendRequest::KeyValuesSearchRequest {
+        self.shards.iter().try_for_each(|shard| {
+            shard.fan_out(
+                BackendRequest::KeyValuesSearchRequest {
                     query: search_query.clone(),
                     response_chan: s.clone(),
-                })
-                .unwrap();
-        });
+                },
+                admission,
+            )
+        })?;
         drop(s);
-        let result: HashSet<Arc<str>> = r.iter().collect();
-        result.into_iter().collect()
+        let result = collect_shard_messages(r, self.shards.len(), deadline);
+        let values: HashSet<Arc<str>> = result.data.into_iter().collect();
+        Ok(ShardedResult {
+            data: values.into_iter().collect(),
+            unfinished_shards: result.unfinished_shards,
+            degraded_shards: self.degraded_shards(),
+        })
+    }
+
+    fn degraded_shards(&self) -> Vec<u16> {
+        self.shards.iter().filter(|shard| !shard.is_healthy()).map(|shard| shard.shard_id()).collect()
     }
 
+    /// Search each shard once for the ids of the records to export
+    pub fn export_snapshot(&self, query: &query::Search) -> Result<ExportSnapshot, String> {
+        let shard_ids = self
+            .shards
+            .iter()
+            .map(|shard| {
+                let (s, r) = bounded(1);
+                shard.send(BackendRequest::IdsRequest { query: query.clone(), response_chan: s }, Admission::Block).map_err(|e| e.to_string())?;
+                r.recv().map_err(|_| format!("Error shard {} failed during the export", shard.shard_id()))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(ExportSnapshot { layout: self.layout, shard_ids })
+    }
+
+    /// Stream the records of the snapshot to `each`, batch by batch so that only a batch is held
+    /// in memory. The backend is only locked while a batch is read, a slow `each` (writing to a
+    /// slow client) doesn't hold back reshardings or promotions. Stops at the first error of `each`.
+    pub fn export(
+        storage: &RwLock<ShardedStorageBackend>,
+        snapshot: &ExportSnapshot,
+        mut each: impl FnMut(Arc<record::RCRecord>) -> Result<(), String>,
+    ) -> Result<usize, String> {
+        let mut exported = 0;
+        for (shard_index, ids) in snapshot.shard_ids.iter().enumerate() {
+            for batch in ids.chunks(EXPORT_BATCH_SIZE) {
+                let records = storage.read().unwrap().export_batch(snapshot, shard_index, batch)?;
+                exported += records.len();
+                records.into_iter().try_for_each(&mut each)?;
+            }
+        }
+        Ok(exported)
+    }
+
+    fn export_batch(&self, snapshot: &ExportSnapshot, shard_index: usize, ids: &[u32]) -> Result<Vec<Arc<record::RCRecord>>, String> {
+        if snapshot.layout != self.layout {
+            return Err(String::from("Error the shards were resharded during the export"));
+        }
+        let shard = &self.shards[shard_index];
+        let (s, r) = bounded(ids.len());
+        shard.send(BackendRequest::GetRequest { ids: ids.to_vec(), response_chan: s }, Admission::Block).map_err(|e| e.to_string())?;
+        let records: Vec<_> = r.iter().map(|x| x.record).collect();
+        // Records are never removed, only a shard which lost them can miss some
+        match records.len() == ids.len() {
+            true => Ok(records),
+            false => Err(format!("Error shard {} failed during the export", shard.shard_id())),
+        }
+    }
+
+    /// Wait until every write sent so far has been applied by the shards
     pub fn wait_pending_operations(&self) {
+        let shards: Vec<&Box<dyn ShardHandle>> = self.shards.iter().chain(self.next_shards.iter().flatten()).collect();
+        let (s, r) = bounded(shards.len());
+        shards.iter().for_each(|shard| {
+            shard
+                .fan_out(BackendRequest::FlushRequest { response_chan: s.clone() }, Admission::Block)
+                .ok();
+        });
+        drop(s);
+        r.iter().for_each(drop);
+    }
+}
+
+impl Default for ShardedStorageBackend {
+    fn default() -> Self {
+        ShardedStorageBackend::new()
+    }
+}
+
+pub fn available_cpus() -> u16 {
+    std::thread::available_parallelism().map(|n| n.get() as u16).unwrap_or(1)
+}
+
+/// Copy every record of the current shards to the next ones, then swap the layouts
+fn migrate(storage: Arc<RwLock<ShardedStorageBackend>>) {
+    let start = Instant::now();
+    let migrated = {
+        let backend = storage.read().unwrap();
+        let migrated = migrate_records(&backend, start);
+        backend.wait_pending_operations();
+        migrated
+    };
+
+    let mut backend = storage.write().unwrap();
+    if let Err(e) = migrated {
+        // The shards being populated are dropped, writes go to the current layout only again
+        backend.next_shards = None;
+        if let Some(status) = backend.resharding.lock().unwrap().as_mut() {
+            status.elapsed_ms = start.elapsed().as_millis();
+            status.done = true;
+            status.error = Some(e.to_string());
+        }
+        error!("Resharding to {} shards failed after {}ms: {}", backend.shards.len(), start.elapsed().as_millis(), e);
+        return;
+    }
+    backend.shards = backend.next_shards.take().unwrap();
+    backend.layout += 1;
+    if let Some(status) = backend.resharding.lock().unwrap().as_mut() {
+        status.elapsed_ms = start.elapsed().as_millis();
+        status.done = true;
+    }
+    info!("Resharding to {} shards done in {}ms", backend.shards.len(), start.elapsed().as_millis());
+}
+
+/// Copy the records of the current shards to the next ones, shard by shard
+fn migrate_records(backend: &ShardedStorageBackend, start: Instant) -> Result<(), BackendError> {
+    let next_shards = backend.next_shards.as_ref().unwrap();
+    backend.shards.iter().try_for_each(|shard| {
+        let mut from = 0;
         loop {
-            let empty = (&self.shards).iter().all(|s| s.is_empty());
-            if empty {
+            let (s, r) = bounded(MIGRATION_BATCH_SIZE);
+            shard.send(
+                BackendRequest::ScanRequest {
+                    query: query::Search::new(Vec::new()),
+                    from,
+                    limit: MIGRATION_BATCH_SIZE,
+                    response_chan: s,
+                },
+                Admission::Block,
+            )?;
+            let mut count = 0;
+            r.iter().try_for_each(|(id, record)| {
+                let pairs = record.label_pairs.iter().map(|l| (l.key.as_ref(), l.val.as_ref()));
+                next_shards[route(&backend.hasher, pairs, next_shards.len())].send(BackendRequest::MigrateRequest { record }, Admission::Block)?;
+                from = id + 1;
+                count += 1;
+                Ok(())
+            })?;
+            if let Some(status) = backend.resharding.lock().unwrap().as_mut() {
+                status.migrated_records += count;
+                status.elapsed_ms = start.elapsed().as_millis();
+            }
+            if count < MIGRATION_BATCH_SIZE {
                 break;
             }
-            std::thread::sleep(std::time::Duration::from_millis(100));
         }
+        if let Some(status) = backend.resharding.lock().unwrap().as_mut() {
+            status.migrated_shards += 1;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_test_data(backend: &ShardedStorageBackend) {
+        backend.raw_add(String::from(r#"{author="tolkien", title="The Silmarillion", language="english"}"#));
+        backend.raw_add(String::from(r#"{author="tolkien", title="The Silmarillion", language="french"}"#));
+        backend.raw_add(String::from(r#"{author="tolkien", title="The Hobbit", language="french"}"#));
+        backend.wait_pending_operations();
+    }
+
+    #[test]
+    fn it_gets_records_by_global_id() {
+        let backend = ShardedStorageBackend::new_with_cpus(3);
+        load_test_data(&backend);
+
+        let found = backend.search(query::Search::new(vec![query::Field::new_eq("author", "tolkien")]));
+        assert_eq!(found.len(), 3);
+        found.iter().for_each(|x| {
+            assert_eq!(backend.get(x.id), Some(x.clone()));
+        });
+
+        let mut ids: Vec<u64> = found.iter().map(|x| x.id).collect();
+        ids.reverse();
+        ids.push(to_global_id(42, 0));
+        let records = backend.multi_get(ids.clone());
+        assert_eq!(records.iter().map(|x| x.id).collect::<Vec<u64>>(), ids[..3].to_vec());
+    }
+
+    #[test]
+    fn it_rejects_writes_when_overloaded() {
+        let backend = ShardedStorageBackend::new_with_config(ShardedStorageBackendConfig {
+            shards: 1,
+            pinning: CpuPinning::None,
+            queue_size: 1,
+            max_memory: None,
+            layout: StoreLayout::Rows,
+            index: IndexConfig::default(),
+        });
+        // Keep the shard busy sending a status nobody reads
+        let (s, r) = bounded(0);
+        backend.shards[0]
+            .send(BackendRequest::StatusRequest { response_chan: s }, Admission::Block)
+            .unwrap();
+        while backend.shards[0].lanes().pending_reads() > 0 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        assert_eq!(backend.try_raw_add(r#"{author="tolkien"}"#), Ok(()));
+        assert_eq!(backend.try_raw_add(r#"{author="tolstoy"}"#), Err(BackendError::Overloaded(0)));
+        assert!(matches!(backend.try_raw_add("{author=tolkien}"), Err(BackendError::InvalidRecord(_))));
+
+        drop(r);
+        backend.wait_pending_operations();
+        assert_eq!(backend.search(query::Search::new(vec![query::Field::new_re("author", ".*")])).len(), 1);
+    }
+
+    #[test]
+    fn it_rejects_writes_past_the_memory_limit() {
+        let backend = ShardedStorageBackend::new_with_config(ShardedStorageBackendConfig {
+            shards: 2,
+            max_memory: Some(4096),
+            ..ShardedStorageBackendConfig::default()
+        });
+        let mut added = 0;
+        let error = loop {
+            match backend.try_raw_add(&format!(r#"{{author="tolkien", title="Volume {}"}}"#, added)) {
+                Ok(()) => added += 1,
+                Err(error) => break error,
+            }
+            backend.wait_pending_operations();
+        };
+        assert!(matches!(error, BackendError::StoreFull { limit: 4096, .. }));
+        assert!(added > 0);
+        assert!(backend.budget.used() >= 4096);
+        let used: usize = backend.get_shards_status().iter().map(|s| s.shard_status.memory.total()).sum();
+        assert_eq!(used + backend.budget.symbols.memory_usage(), backend.budget.used());
+        // Reads are still served
+        assert_eq!(backend.search(query::Search::new(vec![query::Field::new_eq("author", "tolkien")])).len(), added);
+    }
+
+    #[test]
+    fn it_returns_partial_results_at_deadline() {
+        let backend = ShardedStorageBackend::new_with_cpus(2);
+        load_test_data(&backend);
+        let search = query::Search::new(vec![query::Field::new_eq("author", "tolkien")]);
+
+        let result = backend.try_search(search.clone()).unwrap();
+        assert!(!result.timed_out());
+        assert_eq!(result.data.len(), 3);
+
+        // Keep shard 1 busy sending a status nobody reads
+        let (s, r) = bounded(0);
+        backend.shards[1]
+            .send(BackendRequest::StatusRequest { response_chan: s }, Admission::Block)
+            .unwrap();
+        let mut search = search;
+        search.deadline = Some(Instant::now() + Duration::from_millis(50));
+        let result = backend.try_search(search).unwrap();
+        assert_eq!(result.unfinished_shards, vec![1]);
+        assert!(result.data.len() < 3);
+        drop(r);
+    }
+
+    #[test]
+    fn it_restarts_crashed_shards() {
+        let backend = ShardedStorageBackend::new_with_cpus(1);
+        load_test_data(&backend);
+        // An invalid regex built without the lexer makes the shard panic
+        let search = query::Search::new(vec![query::Field::new_re("author", "(tolkien")]);
+        let result = backend.try_search(search).unwrap();
+        assert_eq!(result.unfinished_shards, vec![0]);
+        assert_eq!(result.degraded_shards, vec![0]);
+
+        let status = backend.get_status();
+        assert_eq!(status.health[0].crashes, 1);
+        assert!(!status.health[0].healthy);
+
+        // The restarted shard is empty but keeps serving requests
+        load_test_data(&backend);
+        let search = query::Search::new(vec![query::Field::new_eq("author", "tolkien")]);
+        assert_eq!(backend.search(search).len(), 3);
+    }
+
+    /// A shard whose thread is gone, every request to it fails
+    struct StoppedShard(ShardLanes);
+
+    impl ShardHandle for StoppedShard {
+        fn lanes(&self) -> &ShardLanes {
+            &self.0
+        }
+
+        fn health(&self) -> ShardHealth {
+            ShardHealth::new(self.0.shard_id)
+        }
+    }
+
+    #[test]
+    fn it_counts_the_records_added_by_the_other_shards() {
+        let mut backend = ShardedStorageBackend::new_with_cpus(2);
+        backend.shards[1] = Box::new(StoppedShard(ShardLanes::new(1, 1).0));
+        let records: Vec<_> = (0..20).map(|i| lexer::parse_record(&format!(r#"{{title="Volume {}"}}"#, i)).unwrap()).collect();
+        let to_stopped = backend.split_per_shard(&backend.shards, records.clone())[1].len();
+        assert!(to_stopped > 0 && to_stopped < 20);
+
+        let (s, r) = bounded(2);
+        let (failed, error) = backend.bulk_add(records, &s).unwrap_err();
+        backend.wait_pending_operations();
+        drop(s);
+        assert_eq!((failed, error), (to_stopped, BackendError::Unavailable(1)));
+        assert_eq!(r.iter().sum::<usize>(), 20 - to_stopped);
+    }
+
+    #[test]
+    fn it_ranks_text_searches_across_shards() {
+        let titles = ["The Hobbit", "Back to the Hobbit hole", "The Fellowship of the Ring", "A hobbit, a wizard and a long journey"];
+        let ranked = |shards| {
+            let backend = ShardedStorageBackend::new_with_config(ShardedStorageBackendConfig {
+                shards,
+                index: IndexConfig {
+                    text_keys: vec![String::from("title")],
+                    ..IndexConfig::default()
+                },
+                ..ShardedStorageBackendConfig::default()
+            });
+            titles.iter().for_each(|title| backend.raw_add(format!(r#"{{title="{}"}}"#, title)));
+            backend.wait_pending_operations();
+            let mut search = query::Search::new(vec![query::Field::new_text("title", "hobbit")]);
+            search.limit = Some(2);
+            let result = backend.try_search(search).unwrap();
+            assert!(!result.timed_out());
+            result.data.into_iter().map(|x| (x.record.label_pairs[0].val.to_string(), x.score.unwrap())).collect::<Vec<_>>()
+        };
+        // Shards score with the statistics of all of them, as a single shard would
+        let ranked_by_one = ranked(1);
+        assert_eq!(ranked_by_one.iter().map(|(title, _)| title.as_str()).collect::<Vec<_>>(), vec!["The Hobbit", "Back to the Hobbit hole"]);
+        assert!(ranked_by_one[0].1 > ranked_by_one[1].1);
+        assert_eq!(ranked(3), ranked_by_one);
+    }
+
+    #[test]
+    fn it_reports_fuzzy_matches() {
+        let backend = ShardedStorageBackend::new_with_cpus(2);
+        ["Tolkien", "Tolstoy", "J.R.R. Tolkien"].iter().for_each(|author| backend.raw_add(format!(r#"{{author="{}"}}"#, author)));
+        backend.wait_pending_operations();
+        let fields = vec![query::Field::new_fuzzy("author", "Tolkein", 2)];
+        let search = query::Search::new_with_flags(fields, query::SearchFlags::DEFAULT | query::SearchFlags::REPORT_FUZZY_MATCHES);
+        let result = backend.try_search(search).unwrap();
+        let matches: Vec<_> = result.data.iter().flat_map(|x| x.fuzzy_matches.iter().map(|m| (m.value.as_ref(), m.distance))).collect();
+        assert_eq!(matches, vec![("Tolkien", 2)]);
+    }
+
+    #[test]
+    fn it_projects_records() {
+        let backend = ShardedStorageBackend::new_with_cpus(3);
+        for (title, language) in [("The Hobbit", "English"), ("Bilbo le Hobbit", "French"), ("The Silmarillion", "English")] {
+            backend.raw_add(format!(r#"{{title="{}", language="{}", author="Tolkien"}}"#, title, language));
+        }
+        backend.wait_pending_operations();
+        let projected = |keys: &[&str], distinct| {
+            let mut search = query::Search::new(vec![query::Field::new_eq("author", "Tolkien")]);
+            let keys = keys.iter().map(|key| Box::from(*key)).collect();
+            search.projection = Some(query::Projection { keys, distinct });
+            let mut records: Vec<_> = backend.try_search(search).unwrap().data.into_iter().map(|x| x.record.to_string()).collect();
+            records.sort_unstable();
+            records
+        };
+        assert_eq!(projected(&["language", "title"], false)[0], "language==English, title==The Hobbit");
+        assert_eq!(projected(&["language"], false).len(), 3);
+        assert_eq!(projected(&["language"], true), vec!["language==English", "language==French"]);
+    }
+
+    #[test]
+    fn it_limits_distinct_tuples() {
+        let backend = ShardedStorageBackend::new_with_cpus(3);
+        for i in 0..30 {
+            let language = if i == 29 { "French" } else { "English" };
+            backend.raw_add(format!(r#"{{title="Volume {}", language="{}", author="Tolkien"}}"#, i, language));
+        }
+        backend.wait_pending_operations();
+        let mut search = query::Search::new(vec![query::Field::new_eq("author", "Tolkien")]);
+        search.limit = Some(2);
+        search.projection = Some(query::Projection {
+            keys: vec![Box::from("language")],
+            distinct: true,
+        });
+        let mut records: Vec<_> = backend.try_search(search).unwrap().data.into_iter().map(|x| x.record.to_string()).collect();
+        records.sort_unstable();
+        assert_eq!(records, vec!["language==English", "language==French"]);
+    }
+
+    #[test]
+    fn it_parses_cpu_pinning() {
+        assert_eq!("none".parse::<CpuPinning>(), Ok(CpuPinning::None));
+        assert_eq!("auto".parse::<CpuPinning>(), Ok(CpuPinning::Auto));
+        assert_eq!("0, 2,4".parse::<CpuPinning>(), Ok(CpuPinning::Cores(vec![0, 2, 4])));
+        assert!("0,two".parse::<CpuPinning>().is_err());
+        assert_eq!(CpuPinning::Cores(vec![0, 2]).core_for(3), Some(2));
+    }
+
+    #[test]
+    fn it_reshards() {
+        let storage = Arc::new(RwLock::new(ShardedStorageBackend::new_with_cpus(2)));
+        load_test_data(&storage.read().unwrap());
+
+        ShardedStorageBackend::reshard(&storage, 5).unwrap();
+        while storage.read().unwrap().is_resharding() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let backend = storage.read().unwrap();
+        assert_eq!(backend.shard_count(), 5);
+        let found = backend.search(query::Search::new(vec![query::Field::new_eq("author", "tolkien")]));
+        assert_eq!(found.len(), 3);
+
+        // Records are routed by content, so adding an existing record again is deduplicated
+        load_test_data(&backend);
+        let found = backend.search(query::Search::new(vec![query::Field::new_eq("author", "tolkien")]));
+        assert_eq!(found.len(), 3);
+    }
+
+    #[test]
+    fn it_rolls_back_failed_reshardings() {
+        let mut backend = ShardedStorageBackend::new_with_cpus(2);
+        load_test_data(&backend);
+        backend.shards[1] = Box::new(StoppedShard(ShardLanes::new(1, 1).0));
+        let storage = Arc::new(RwLock::new(backend));
+
+        ShardedStorageBackend::reshard(&storage, 3).unwrap();
+        while storage.read().unwrap().is_resharding() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let backend = storage.read().unwrap();
+        assert_eq!(backend.shard_count(), 2);
+        assert!(backend.next_shards.is_none());
+        let status = backend.get_status().resharding.unwrap();
+        assert!(status.done);
+        assert_eq!(status.error, Some(BackendError::Unavailable(1).to_string()));
+        // Another resharding can be started
+        assert!(!backend.is_resharding());
     }
 }
@@ -0,0 +1,371 @@
+use super::multithread_backend::{Admission, BackendError, BackendRequest, ShardHandle, ShardHealth, ShardLanes};
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Writes waiting to be shipped to a follower, past which it stops following the leader
+pub(crate) const REPLICATION_LOG_SIZE: usize = 100_000;
+
+/// One copy of a replicated shard
+struct Replica {
+    node: String,
+    handle: Arc<dyn ShardHandle>,
+    // Writes of the leader waiting to be shipped to this replica while it is a follower
+    log: Sender<BackendRequest>,
+    // Set once a write couldn't be shipped to this replica, it misses writes and must be rebuilt
+    degraded: Arc<AtomicBool>,
+}
+
+impl Replica {
+    /// Holds every write shipped so far, though it may not have received the last ones yet
+    fn is_usable(&self) -> bool {
+        self.handle.is_healthy() && !self.is_degraded()
+    }
+
+    /// Whether the replica missed writes, either left unshipped or lost on their way to its node
+    fn is_degraded(&self) -> bool {
+        if self.handle.lost_writes() > 0 {
+            self.degrade("writes were lost on the way to its node");
+        }
+        self.degraded.load(Ordering::SeqCst)
+    }
+
+    fn lag_writes(&self) -> usize {
+        self.log.len() + self.handle.lanes().pending_writes()
+    }
+
+    fn degrade(&self, cause: &str) {
+        if !self.degraded.swap(true, Ordering::SeqCst) {
+            error!("Replica of shard {} on {} is degraded ({}), it must be rebuilt", self.handle.shard_id(), self.node, cause);
+        }
+    }
+}
+
+/// A shard kept on several replicas. Writes go to the leader, which ships them in order to
+/// the followers, so that every replica assigns the same local ids. Reads are served by
+/// the least loaded healthy replica, followers may lag behind the leader.
+pub(crate) struct ReplicatedShard {
+    replicas: Vec<Replica>,
+    leader: AtomicUsize,
+    // Held while a write is sent to the leader and queued for the followers, so that
+    // concurrent writes reach every replica in the same order
+    writes: Mutex<()>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplicationStatus {
+    pub(crate) shard_id: u16,
+    pub(crate) leader: usize,
+    pub(crate) replicas: Vec<ReplicaStatus>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplicaStatus {
+    pub(crate) node: String,
+    pub(crate) leader: bool,
+    pub(crate) healthy: bool,
+    /// Missed writes of the leader, the replica isn't read from nor promoted anymore
+    #[serde(default)]
+    pub(crate) degraded: bool,
+    // Writes accepted by the leader which this replica did not receive yet
+    pub(crate) lag_writes: usize,
+}
+
+/// Copy of a write to ship to a follower: followers apply the writes without answering
+/// the client, except for flushes which complete once every replica is flushed
+fn replicate(request: &BackendRequest) -> Option<BackendRequest> {
+    match request {
+        BackendRequest::RawAddRequest { record } | BackendRequest::AddRequest { record, .. } => {
+            Some(BackendRequest::RawAddRequest { record: record.clone() })
+        }
+        BackendRequest::MigrateRequest { record } => Some(BackendRequest::MigrateRequest { record: record.clone() }),
+        BackendRequest::BulkAddRequest { records, .. } => {
+            let (ignored, _) = bounded(1);
+            Some(BackendRequest::BulkAddRequest {
+                records: records.clone(),
+                response_chan: ignored,
+            })
+        }
+        BackendRequest::FlushRequest { response_chan } => Some(BackendRequest::FlushRequest {
+            response_chan: response_chan.clone(),
+        }),
+        _ => None,
+    }
+}
+
+/// Ship the writes of the log to the replica, stops once the shard is dropped
+fn ship(node: String, handle: Arc<dyn ShardHandle>, log: Receiver<BackendRequest>, degraded: Arc<AtomicBool>) {
+    for request in log.iter() {
+        if let Err(e) = handle.send(request, Admission::Block) {
+            warn!("Error shipping a write to the replica of shard {} on {}: {}", handle.shard_id(), node, e);
+            degraded.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl ReplicatedShard {
+    /// The first replica is the leader. A follower whose log holds `log_size` writes is degraded.
+    pub(crate) fn new(replicas: Vec<(String, Arc<dyn ShardHandle>)>, log_size: usize) -> ReplicatedShard {
+        let replicas = replicas
+            .into_iter()
+            .map(|(node, handle)| {
+                let (log, log_r) = bounded(log_size);
+                let degraded = Arc::new(AtomicBool::new(false));
+                let (shipped_node, shipped_handle, shipped_degraded) = (node.clone(), handle.clone(), degraded.clone());
+                thread::Builder::new()
+                    .name(format!("replication-{}", handle.shard_id()))
+                    .spawn(move || ship(shipped_node, shipped_handle, log_r, shipped_degraded))
+                    .unwrap();
+                Replica { node, handle, log, degraded }
+            })
+            .collect();
+        ReplicatedShard {
+            replicas,
+            leader: AtomicUsize::new(0),
+            writes: Mutex::new(()),
+        }
+    }
+
+    fn leader(&self) -> &Replica {
+        &self.replicas[self.leader.load(Ordering::SeqCst)]
+    }
+
+    /// Usable replica with the fewest pending reads, the leader if none is usable
+    fn read_replica(&self) -> &Replica {
+        let leader = self.leader.load(Ordering::SeqCst);
+        self.replicas
+            .iter()
+            .enumerate()
+            .filter(|(_, replica)| replica.is_usable())
+            .min_by_key(|(i, replica)| (replica.handle.lanes().pending_reads(), *i != leader))
+            .map(|(_, replica)| replica)
+            .unwrap_or_else(|| self.leader())
+    }
+}
+
+impl ShardHandle for ReplicatedShard {
+    fn lanes(&self) -> &ShardLanes {
+        self.leader().handle.lanes()
+    }
+
+    /// Healthy as long as one replica is usable
+    fn health(&self) -> ShardHealth {
+        let leader = self.leader().handle.health();
+        match leader.healthy {
+            true => leader,
+            false => self
+                .replicas
+                .iter()
+                .filter(|replica| !replica.is_degraded())
+                .map(|replica| replica.handle.health())
+                .find(|health| health.healthy)
+                .unwrap_or(leader),
+        }
+    }
+
+    fn send(&self, request: BackendRequest, admission: Admission) -> Result<(), BackendError> {
+        if !request.is_write() {
+            return self.read_replica().handle.send(request, admission);
+        }
+        let _writing = self.writes.lock().unwrap();
+        let leader = self.leader.load(Ordering::SeqCst);
+        let copies: Vec<(&Replica, Option<BackendRequest>)> = self
+            .replicas
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != leader)
+            .map(|(_, replica)| (replica, replicate(&request)))
+            .collect();
+        self.replicas[leader].handle.send(request, admission)?;
+        copies.into_iter().for_each(|(replica, copy)| {
+            // A degraded replica already diverged, shipping it more writes is pointless
+            if let Some(copy) = copy.filter(|_| !replica.is_degraded()) {
+                match replica.log.try_send(copy) {
+                    Err(TrySendError::Full(_)) => replica.degrade("its replication log is full"),
+                    Err(TrySendError::Disconnected(_)) => replica.degrade("its replication log is closed"),
+                    Ok(()) => (),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn replication_status(&self) -> Option<ReplicationStatus> {
+        let leader = self.leader.load(Ordering::SeqCst);
+        Some(ReplicationStatus {
+            shard_id: self.shard_id(),
+            leader,
+            replicas: self
+                .replicas
+                .iter()
+                .enumerate()
+                .map(|(i, replica)| ReplicaStatus {
+                    node: replica.node.clone(),
+                    leader: i == leader,
+                    healthy: replica.handle.is_healthy(),
+                    degraded: replica.is_degraded(),
+                    lag_writes: match i == leader {
+                        true => 0,
+                        false => replica.lag_writes(),
+                    },
+                })
+                .collect(),
+        })
+    }
+
+    /// Make a follower the leader, e.g. when the leader died. The former leader is degraded:
+    /// writes it missed while unreachable are not replayed, it must be rebuilt.
+    /// Only a healthy follower which received every write of the leader can be promoted.
+    fn promote(&self, replica: usize) -> Result<(), String> {
+        let _writing = self.writes.lock().unwrap();
+        let follower = match self.replicas.get(replica) {
+            Some(follower) => follower,
+            None => return Err(format!("Error shard {} has no replica {}", self.shard_id(), replica)),
+        };
+        if !follower.is_usable() {
+            return Err(format!("Error replica {} of shard {} on {} is unhealthy or missed writes", replica, self.shard_id(), follower.node));
+        }
+        let lag_writes = follower.lag_writes();
+        if lag_writes > 0 && replica != self.leader.load(Ordering::SeqCst) {
+            return Err(format!(
+                "Error replica {} of shard {} on {} lags {} writes behind the leader, retry once it caught up",
+                replica,
+                self.shard_id(),
+                follower.node,
+                lag_writes
+            ));
+        }
+        let previous = self.leader.swap(replica, Ordering::SeqCst);
+        if previous != replica {
+            self.replicas[previous].degrade("demoted");
+        }
+        info!(
+            "Promoted replica {} of shard {} on {} to leader (was {} on {})",
+            replica,
+            self.shard_id(),
+            self.replicas[replica].node,
+            previous,
+            self.replicas[previous].node
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer;
+
+    /// A shard nobody serves, its requests pile up in its lanes
+    struct ParkedShard {
+        lanes: ShardLanes,
+        _receivers: (Receiver<BackendRequest>, Receiver<BackendRequest>),
+        healthy: bool,
+    }
+
+    fn parked(queue_size: usize, healthy: bool) -> (String, Arc<dyn ShardHandle>) {
+        let (lanes, reads, writes) = ShardLanes::new(0, queue_size);
+        let shard = ParkedShard {
+            lanes,
+            _receivers: (reads, writes),
+            healthy,
+        };
+        (String::from("parked"), Arc::new(shard))
+    }
+
+    /// A healthy parked shard along with its writes, in the order they would be applied
+    fn parked_writes(queue_size: usize) -> ((String, Arc<dyn ShardHandle>), Receiver<BackendRequest>) {
+        let (lanes, reads, writes) = ShardLanes::new(0, queue_size);
+        let shard = ParkedShard {
+            lanes,
+            _receivers: (reads, writes.clone()),
+            healthy: true,
+        };
+        ((String::from("parked"), Arc::new(shard)), writes)
+    }
+
+    impl ShardHandle for ParkedShard {
+        fn lanes(&self) -> &ShardLanes {
+            &self.lanes
+        }
+
+        fn health(&self) -> ShardHealth {
+            ShardHealth {
+                healthy: self.healthy,
+                ..ShardHealth::new(0)
+            }
+        }
+    }
+
+    fn add(shard: &ReplicatedShard, i: usize) {
+        let record = lexer::parse_record(&format!(r#"{{title="Volume {}"}}"#, i)).unwrap();
+        shard.send(BackendRequest::RawAddRequest { record }, Admission::Reject).unwrap();
+    }
+
+    #[test]
+    fn it_degrades_followers_missing_writes() {
+        let shard = ReplicatedShard::new(vec![parked(16, true), parked(1, true)], 1);
+        add(&shard, 0);
+        // Until shipped to the lanes of the follower, the write is held by the shipping thread
+        while shard.replicas[1].lag_writes() == 0 {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(shard.promote(1).unwrap_err().contains("lags"));
+
+        // One write in the lanes of the follower, one being shipped and one in the log at most
+        (1..5).for_each(|i| add(&shard, i));
+        let status = shard.replication_status().unwrap();
+        assert!(status.replicas[1].degraded && !status.replicas[0].degraded);
+        assert!(shard.promote(1).unwrap_err().contains("missed writes"));
+        assert_eq!(shard.leader.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn it_promotes_healthy_followers_only() {
+        let shard = ReplicatedShard::new(vec![parked(16, true), parked(16, false), parked(16, true)], 16);
+        assert!(shard.promote(1).is_err());
+        assert!(shard.promote(3).is_err());
+        assert!(shard.promote(2).is_ok());
+        let status = shard.replication_status().unwrap();
+        assert_eq!(status.leader, 2);
+        // The former leader doesn't receive the writes of the new one
+        assert!(status.replicas[0].degraded && !status.replicas[2].degraded);
+        assert!(shard.promote(0).unwrap_err().contains("missed writes"));
+    }
+
+    #[test]
+    fn it_ships_concurrent_writes_in_the_order_of_the_leader() {
+        let (replicas, writes): (Vec<_>, Vec<_>) = (0..3).map(|_| parked_writes(8192)).unzip();
+        let shard = ReplicatedShard::new(replicas, 8192);
+        thread::scope(|s| {
+            for writer in 0..8 {
+                let shard = &shard;
+                s.spawn(move || (0..500).for_each(|i| add(shard, writer * 500 + i)));
+            }
+        });
+        while writes.iter().any(|w| w.len() < 4000) {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        // Local ids are given in the order of the writes, every replica gives the same ids
+        let titles: Vec<Vec<String>> = writes
+            .iter()
+            .map(|w| {
+                w.try_iter()
+                    .map(|request| match request {
+                        BackendRequest::RawAddRequest { record } => record.label_pairs[0].val.to_string(),
+                        _ => panic!("Expecting raw adds only"),
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(titles[0].len(), 4000);
+        assert_eq!(titles[0], titles[1]);
+        assert_eq!(titles[0], titles[2]);
+    }
+}
@@ -3,8 +3,8 @@ use crate::lexer;
 use crate::record;
 use crate::record::query;
 use crate::store;
+use crate::store::symbols::SymbolTable;
 
-use hashbrown::HashSet;
 use log::{debug, error};
 use serde::{Deserialize, Serialize};
 
@@ -14,45 +14,98 @@ pub trait SingleThreadBackend {
     fn new() -> Self;
     fn raw_add(&mut self, line: String);
     fn add(&mut self, record: record::SmallRecord) -> Option<u32>;
-    fn search(&self, search_query: query::Search) -> Vec<Arc<record::RCRecord>>;
+    fn add_rcrecord(&mut self, record: &record::RCRecord) -> Option<u32>;
+    fn get(&self, id: u32) -> Option<Arc<record::RCRecord>>;
+    fn multi_get(&self, ids: Vec<u32>) -> Vec<(u32, Arc<record::RCRecord>)>;
+    /// Up to `limit` records matching the query, by increasing id starting at `from`
+    fn scan(&self, search_query: &query::Search, from: u32, limit: usize) -> Vec<(u32, Arc<record::RCRecord>)>;
+    fn search(&self, search_query: query::Search) -> Vec<(u32, Arc<record::RCRecord>)>;
+    fn count(&self, search_query: query::Search) -> usize;
+    /// Ids of the records matching the query, in order
+    fn ids(&self, search_query: &query::Search) -> Vec<u32>;
+    /// Statistics of the words searched as text, summed over the shards to rank their records alike
+    fn text_stats(&self, search_query: &query::Search) -> query::TextStats;
+    /// The `limit` records matching the query having the best scores given `stats`, best first
+    fn ranked_search(&self, search_query: &query::Search, stats: &query::TextStats, limit: usize) -> Vec<(u32, Arc<record::RCRecord>, f64)>;
     fn key_values_search(&self, key_values_search_query: query::KeyValuesSearch) -> Vec<Arc<str>>;
     fn print_status(&self);
     fn get_status(&self) -> SingleStorageBackendStatus;
+    fn get_key_status(&self, key: &str, top: usize) -> Option<index::KeyStatus>;
+    fn memory_usage(&self) -> MemoryUsage;
 }
 
 pub struct SingleStorageBackend {
     store: store::RecordStore,
     index: index::Index,
-    symbol_store: HashSet<Arc<str>>,
+    // Possibly shared with other backends, it is accounted separately
+    symbols: Arc<SymbolTable>,
+}
+
+/// Estimated bytes used by a backend, per component
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    /// The records, their strings excluded
+    pub records: usize,
+    /// Id chunks and the table deduplicating records
+    pub record_table: usize,
+    pub index: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.records + self.record_table + self.index
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SingleStorageBackendStatus {
     store_status: store::RecordStoreStatus,
     index_status: index::IndexStatus,
+    pub(crate) memory: MemoryUsage,
+}
+
+impl SingleStorageBackendStatus {
+    pub fn record_count(&self) -> usize {
+        self.store_status.record_count()
+    }
 }
 
 impl SingleStorageBackend {
-    fn new_rcrecord_from(&mut self, record: &record::SmallRecord) -> record::RCRecord {
-        let label_pairs = (&record.label_pairs)
-            .iter()
-            .map(|l| {
-                let key = self.symbol_store.get_or_insert_with(l.key.as_str(), |x| Arc::from(x)).clone();
-                let val = self.symbol_store.get_or_insert_with(l.val.as_str(), |x| Arc::from(x)).clone();
+    /// Create a backend interning its strings in a table shared with other backends
+    pub fn new_with(symbols: Arc<SymbolTable>, layout: store::StoreLayout, index_config: index::IndexConfig) -> SingleStorageBackend {
+        SingleStorageBackend {
+            store: store::RecordStore::new_with_layout(layout, &symbols),
+            index: index::Index::new_with_config(index_config),
+            symbols,
+        }
+    }
+
+    fn new_rcrecord_from<'a>(&self, label_pairs: impl Iterator<Item = (&'a str, &'a str)>) -> record::RCRecord {
+        let label_pairs = label_pairs
+            .map(|(key, val)| {
+                let key = self.symbols.intern(key);
+                let val = self.symbols.intern(val);
                 record::RCLabelPair { key, val }
             })
             .collect();
         record::RCRecord::new(label_pairs)
     }
+
+    fn insert(&mut self, new_record: record::RCRecord) -> Option<u32> {
+        let tuple = self.store.add(new_record);
+        match tuple {
+            Some(tuple) => {
+                self.index.insert_record(tuple.0, &tuple.1);
+                Some(tuple.0)
+            }
+            _ => None,
+        }
+    }
 }
 
 impl SingleThreadBackend for SingleStorageBackend {
     fn new() -> SingleStorageBackend {
-        SingleStorageBackend {
-            store: store::RecordStore::new(),
-            index: index::Index::new(),
-            symbol_store: HashSet::new(),
-        }
+        SingleStorageBackend::new_with(Arc::new(SymbolTable::new()), store::StoreLayout::default(), index::IndexConfig::default())
     }
 
     fn raw_add(&mut self, line: String) {
@@ -68,18 +121,33 @@ impl SingleThreadBackend for SingleStorageBackend {
     }
 
     fn add(&mut self, record: record::SmallRecord) -> Option<u32> {
-        let new_record = self.new_rcrecord_from(&record);
-        let tuple = self.store.add(new_record);
-        match tuple {
-            Some(tuple) => {
-                self.index.insert_record(tuple.0, &tuple.1);
-                Some(tuple.0)
-            }
-            _ => None,
+        let new_record = self.new_rcrecord_from(record.label_pairs.iter().map(|l| (l.key.as_str(), l.val.as_str())));
+        self.insert(new_record)
+    }
+
+    /// Add a record coming from another backend, its strings are interned again
+    /// so they are shared with the records of this backend
+    fn add_rcrecord(&mut self, record: &record::RCRecord) -> Option<u32> {
+        let new_record = self.new_rcrecord_from(record.label_pairs.iter().map(|l| (l.key.as_ref(), l.val.as_ref())));
+        self.insert(new_record)
+    }
+
+    fn get(&self, id: u32) -> Option<Arc<record::RCRecord>> {
+        self.store.get(id)
+    }
+
+    fn multi_get(&self, ids: Vec<u32>) -> Vec<(u32, Arc<record::RCRecord>)> {
+        self.store.multi_get(ids)
+    }
+
+    fn scan(&self, search_query: &query::Search, from: u32, limit: usize) -> Vec<(u32, Arc<record::RCRecord>)> {
+        match search_query.is_match_all() {
+            true => self.store.scan(from, limit),
+            false => self.store.multi_get(self.index.search_from(search_query, from, limit)),
         }
     }
 
-    fn search(&self, search_query: query::Search) -> Vec<Arc<record::RCRecord>> {
+    fn search(&self, search_query: query::Search) -> Vec<(u32, Arc<record::RCRecord>)> {
         match search_query.is_match_all() {
             // TODO: implement dynamic limit
             true => self.store.get_all(10000),
@@ -87,6 +155,33 @@ impl SingleThreadBackend for SingleStorageBackend {
         }
     }
 
+    fn count(&self, search_query: query::Search) -> usize {
+        match search_query.is_match_all() {
+            true => self.store.len(),
+            false => self.index.count(&search_query),
+        }
+    }
+
+    fn ids(&self, search_query: &query::Search) -> Vec<u32> {
+        match search_query.is_match_all() {
+            // Ids are allocated contiguously
+            true => (0..self.store.len() as u32).collect(),
+            false => self.index.search(search_query),
+        }
+    }
+
+    fn text_stats(&self, search_query: &query::Search) -> query::TextStats {
+        self.index.text_stats(search_query)
+    }
+
+    fn ranked_search(&self, search_query: &query::Search, stats: &query::TextStats, limit: usize) -> Vec<(u32, Arc<record::RCRecord>, f64)> {
+        self.index
+            .ranked_search(search_query, stats, limit)
+            .into_iter()
+            .filter_map(|(id, score)| self.store.get(id).map(|record| (id, record, score)))
+            .collect()
+    }
+
     fn key_values_search(&self, key_values_search_query: query::KeyValuesSearch) -> Vec<Arc<str>> {
         match self.index.key_values_search(&key_values_search_query) {
             index::KeyValuesSearchResult::Ok(x) => {
@@ -122,6 +217,19 @@ impl SingleThreadBackend for SingleStorageBackend {
         SingleStorageBackendStatus {
             store_status: self.store.get_status(),
             index_status: self.index.get_status(),
+            memory: self.memory_usage(),
+        }
+    }
+
+    fn get_key_status(&self, key: &str, top: usize) -> Option<index::KeyStatus> {
+        self.index.get_key_status(key, top)
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            records: self.store.record_bytes(),
+            record_table: self.store.table_bytes(),
+            index: self.index.memory_usage(),
         }
     }
 }
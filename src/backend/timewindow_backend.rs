@@ -18,10 +18,47 @@ impl SingleThreadBackend for TimewindowStorageBackend {
         self.backend.add(record)
     }
 
-    fn search(&self, search_query: crate::record::query::Search) -> Vec<std::sync::Arc<crate::record::RCRecord>> {
+    fn get(&self, id: u32) -> Option<std::sync::Arc<crate::record::RCRecord>> {
+        self.backend.get(id)
+    }
+
+    fn multi_get(&self, ids: Vec<u32>) -> Vec<(u32, std::sync::Arc<crate::record::RCRecord>)> {
+        self.backend.multi_get(ids)
+    }
+
+    fn add_rcrecord(&mut self, record: &crate::record::RCRecord) -> Option<u32> {
+        self.backend.add_rcrecord(record)
+    }
+
+    fn scan(&self, search_query: &crate::record::query::Search, from: u32, limit: usize) -> Vec<(u32, std::sync::Arc<crate::record::RCRecord>)> {
+        self.backend.scan(search_query, from, limit)
+    }
+
+    fn search(&self, search_query: crate::record::query::Search) -> Vec<(u32, std::sync::Arc<crate::record::RCRecord>)> {
         self.backend.search(search_query)
     }
 
+    fn count(&self, search_query: crate::record::query::Search) -> usize {
+        self.backend.count(search_query)
+    }
+
+    fn ids(&self, search_query: &crate::record::query::Search) -> Vec<u32> {
+        self.backend.ids(search_query)
+    }
+
+    fn text_stats(&self, search_query: &crate::record::query::Search) -> crate::record::query::TextStats {
+        self.backend.text_stats(search_query)
+    }
+
+    fn ranked_search(
+        &self,
+        search_query: &crate::record::query::Search,
+        stats: &crate::record::query::TextStats,
+        limit: usize,
+    ) -> Vec<(u32, std::sync::Arc<crate::record::RCRecord>, f64)> {
+        self.backend.ranked_search(search_query, stats, limit)
+    }
+
     fn key_values_search(&self, key_values_search_query: crate::record::query::KeyValuesSearch) -> Vec<std::sync::Arc<str>> {
         self.backend.key_values_search(key_values_search_query)
     }
@@ -33,4 +70,12 @@ impl SingleThreadBackend for TimewindowStorageBackend {
     fn get_status(&self) -> super::singlethread_backend::SingleStorageBackendStatus {
         self.backend.get_status()
     }
+
+    fn get_key_status(&self, key: &str, top: usize) -> Option<crate::index::KeyStatus> {
+        self.backend.get_key_status(key, top)
+    }
+
+    fn memory_usage(&self) -> super::singlethread_backend::MemoryUsage {
+        self.backend.memory_usage()
+    }
 }
\ No newline at end of file
@@ -1,17 +1,22 @@
-use crate::backend::multithread_backend::ShardedStorageBackend;
-use clap::{App, Arg};
-use fern;
-use log;
+use crate::backend::cluster::{self, ClusterConfig};
+use crate::backend::multithread_backend::{self, IndexConfig, ShardedStorageBackend, ShardedStorageBackendConfig, StoreLayout};
+use clap::{App, Arg, ArgMatches};
 use log::{debug, error, info};
 use mimalloc::MiMalloc;
 use rusted_post::api;
 use rusted_post::backend;
+use rusted_post::export::{self, ExportFormat};
+use rusted_post::ingest::csv::CsvConfig;
+use rusted_post::ingest::follow::{FollowConfig, Follower};
+use rusted_post::ingest::{BulkLoader, BulkLoaderConfig, Encoding, RecordFormat};
 use rusted_post::record::query;
 use rusted_post::telemetry::INIT_FILE_RECORDS_APPENDED;
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::io::{self, BufWriter, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::vec;
 
 #[global_allocator]
@@ -44,27 +49,76 @@ fn display_timed_key_query(backend: &Arc<RwLock<ShardedStorageBackend>>, query:
     records.iter().for_each(|record| debug!("Found: {}", record))
 }
 
-fn load_data_from_file(backend: &Arc<RwLock<ShardedStorageBackend>>, filename: &str) {
-    info!("Loading dataset from: {}", filename);
-    let file = File::open(filename).unwrap();
-
-    let mut total_count = 0;
-    let now = Instant::now();
+/// Parse a size in bytes, with an optional K, M, G or T (powers of 1024) suffix
+fn parse_size(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let (digits, unit) = match size.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => size.split_at(i),
+        None => (size, ""),
+    };
+    let shift = match unit.trim_end_matches(['b', 'B']).to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("Error unknown size unit {} in {}", unit, size)),
+    };
+    let bytes: usize = digits.parse().map_err(|e| format!("Error while getting size {}: {}", size, e))?;
+    bytes.checked_mul(1 << shift).ok_or_else(|| format!("Error size {} is too large", size))
+}
 
+fn load_data_from_file(backend: &Arc<RwLock<ShardedStorageBackend>>, filename: &str, config: BulkLoaderConfig) {
+    info!("Loading dataset from: {}", filename);
     let storage_guard = backend.read().unwrap();
-    io::BufReader::new(file).lines().for_each(|line| {
-        storage_guard.raw_add(line.unwrap());
-        // Multithread system is fire and forget
-        INIT_FILE_RECORDS_APPENDED.inc();
-        total_count += 1;
-    });
-    storage_guard.wait_pending_operations();
-    info!(
-        "Loaded {} lines in {}ms ({}us per record)",
-        total_count,
-        now.elapsed().as_millis(),
-        ((now.elapsed().as_millis() as f64 / total_count as f64) * 1000_f64) as u32
-    );
+    let loader = BulkLoader::new(&storage_guard, config);
+    let loaded = match filename {
+        "-" => loader.load(io::stdin().lock()),
+        _ => loader.load_file(filename),
+    };
+    match loaded {
+        Ok(summary) => {
+            INIT_FILE_RECORDS_APPENDED.inc_by(summary.parsed as u64);
+            info!(
+                "Loaded {} lines in {}ms ({}us per record)",
+                summary.lines,
+                summary.elapsed_ms,
+                ((summary.elapsed_ms as f64 / summary.lines as f64) * 1000_f64) as u32
+            );
+            summary
+                .errors
+                .iter()
+                .for_each(|rejected| error!("{} (on line {})", rejected.error, rejected.line_number));
+        }
+        Err(err) => error!("{}", err),
+    }
+}
+
+fn export_records(backend: &Arc<RwLock<ShardedStorageBackend>>, matches: &ArgMatches) {
+    let start = Instant::now();
+    let selected = export::parse_selector(matches.value_of("query"))
+        .and_then(|search| Ok((search, matches.value_of("format").unwrap().parse::<ExportFormat>()?)));
+    let (search, format) = match selected {
+        Ok(x) => x,
+        Err(err) => {
+            error!("{}", err);
+            return;
+        }
+    };
+    let out: Box<dyn Write> = match matches.value_of("output") {
+        None | Some("-") => Box::new(io::stdout()),
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(err) => {
+                error!("Error creating {}: {}", path, err);
+                return;
+            }
+        },
+    };
+    match export::export(backend, search, format, BufWriter::new(out)) {
+        Ok(exported) => info!("Exported {} records in {}ms", exported, start.elapsed().as_millis()),
+        Err(err) => error!("Error exporting records: {}", err),
+    }
 }
 
 #[tokio::main]
@@ -79,8 +133,98 @@ async fn main() {
                 .short('t')
                 .long("threads")
                 .value_name("Number of threads")
-                .help("Number of threads to use for shards")
-                .default_value("4")
+                .help("Number of threads to use for shards (auto to use one per CPU)")
+                .default_value("auto")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("pin_cpus")
+                .long("pin-cpus")
+                .value_name("Cores")
+                .help("Pin shard threads to CPU cores: none, auto or a list of core ids (e.g. 0,2,4,6)")
+                .default_value("none")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("queue_size")
+                .long("queue-size")
+                .value_name("Number of requests")
+                .help("Capacity of the read and write queues of each shard")
+                .default_value("10000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("max_memory")
+                .long("max-memory")
+                .value_name("Bytes")
+                .help("Reject new records once the shards use this much memory (e.g. 512M, 4G), unlimited by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("store_layout")
+                .long("store-layout")
+                .value_name("Layout")
+                .help("Layout of the records in memory: rows, or columnar to trade read speed for memory")
+                .default_value("rows")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("trigram_keys")
+                .long("trigram-keys")
+                .value_name("Keys")
+                .help("Index the values of these keys by trigrams (e.g. title,author) to speed up regexes without a literal prefix, at the cost of memory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("text_keys")
+                .long("text-keys")
+                .value_name("Keys")
+                .help("Index the words of the values of these keys (e.g. title) for text searches with ~=, at the cost of memory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("stopwords")
+                .long("stopwords")
+                .value_name("Words")
+                .help("Words left out of text searches (an empty list to keep them all)")
+                .default_value("a,an,and,at,by,for,from,in,of,on,or,the,to,with")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("folded_keys")
+                .long("folded-keys")
+                .value_name("Keys")
+                .help("Keep the values of these keys lowercased and without accents (e.g. author_family_name,title) to speed up ==i and (?i) regexes, at the cost of memory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("query_timeout")
+                .long("query-timeout")
+                .value_name("Milliseconds")
+                .help("Default timeout of API searches, partial results are returned past it (0 to disable)")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("cluster")
+                .long("cluster")
+                .value_name("Path to file")
+                .help("Run as the coordinator of the cluster described in this JSON file, shards being hosted by its nodes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("node_listen")
+                .long("node-listen")
+                .value_name("Address")
+                .help("Serve the shards of this process to cluster coordinators on this address (e.g. 0.0.0.0:7070)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("http_port")
+                .long("http-port")
+                .value_name("Port")
+                .help("Port of the HTTP API")
+                .default_value("8080")
                 .takes_value(true),
         )
         .arg(
@@ -93,10 +237,60 @@ async fn main() {
                 .short('d')
                 .long("load-from-file")
                 .value_name("Path to file")
-                .help("Path of the file to load data at startup")
+                .help("Path of the file to load data at startup (- for stdin)")
                 .takes_value(true)
                 .default_value("data/dataset_custom.txt"),
         )
+        .arg(
+            Arg::new("follow")
+                .long("follow")
+                .help("Keep loading the lines appended to the file (or written to stdin), across rotations and truncations"),
+        )
+        .arg(
+            Arg::new("offset_file")
+                .long("offset-file")
+                .value_name("Path to file")
+                .help("Where the read offset of the followed file is persisted, to resume after a restart (default: <file>.offset)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("error_file")
+                .long("error-file")
+                .value_name("Path to file")
+                .help("Write the lines rejected while loading data to this file, with their line number and error")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("text|json|csv|tsv|auto")
+                .help("Format of the data to load: text records, JSON lines (native records or plain objects), CSV/TSV rows or detected on each line")
+                .default_value("auto")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("encoding")
+                .long("encoding")
+                .value_name("utf-8|iso-8859-1")
+                .help("Encoding of the data to load")
+                .default_value("utf-8")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("csv_config")
+                .long("csv-config")
+                .value_name("Path to file")
+                .help("JSON file mapping the CSV/TSV columns to labels (by default every column is kept under its header name)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("parsers")
+                .long("parsers")
+                .value_name("Number of threads")
+                .help("Number of threads parsing records while loading data (auto to use one per CPU)")
+                .default_value("auto")
+                .takes_value(true),
+        )
         .arg(
             Arg::new("log-level")
                 .short('v')
@@ -105,6 +299,32 @@ async fn main() {
                 .help("Change the verbosity (debug, info, err, warn)")
                 .default_value("info"),
         )
+        .subcommand(
+            App::new("export")
+                .about("Load the data, write the records matching a selector and exit")
+                .arg(
+                    Arg::new("query")
+                        .long("query")
+                        .value_name("Search")
+                        .help("Only export the records matching this search, e.g. {author==\"tolkien\"}")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("text|json|csv")
+                        .default_value("text")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .value_name("Path to file")
+                        .help("Where to write the records (default: stdout, logs then go to stderr)")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
     ////////////// LOG INITIALIZATION //////////////
@@ -132,27 +352,212 @@ async fn main() {
             ))
         })
         .level(level)
-        .chain(std::io::stdout())
+        .chain(match matches.subcommand_matches("export").map(|export| export.value_of("output")) {
+            Some(None) | Some(Some("-")) => fern::Output::from(std::io::stderr()),
+            _ => fern::Output::from(std::io::stdout()),
+        })
         .apply()
         .unwrap();
 
+    // An export runs once the data is loaded, followed data never is
+    if matches.is_present("follow") && matches.subcommand_matches("export").is_some() {
+        error!("Error --follow can't be used with the export subcommand");
+        return;
+    }
+
     ////////////// BACKEND INITIALIZATION //////////////
     info!("Initialising backend storage");
 
-    let threads = match matches.value_of("threads").unwrap().parse() {
+    let threads = match matches.value_of("threads").unwrap() {
+        "auto" => multithread_backend::available_cpus(),
+        threads => match threads.parse() {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Error while getting thread count: {}", err);
+                return;
+            }
+        },
+    };
+
+    let pinning = match matches.value_of("pin_cpus").unwrap().parse() {
+        Ok(x) => x,
+        Err(err) => {
+            error!("{}", err);
+            return;
+        }
+    };
+
+    let queue_size = match matches.value_of("queue_size").unwrap().parse() {
+        Ok(x) => x,
+        Err(err) => {
+            error!("Error while getting queue size: {}", err);
+            return;
+        }
+    };
+
+    let max_memory = match matches.value_of("max_memory").map(parse_size) {
+        None => None,
+        Some(Ok(x)) => Some(x),
+        Some(Err(err)) => {
+            error!("{}", err);
+            return;
+        }
+    };
+
+    let layout: StoreLayout = match matches.value_of("store_layout").unwrap().parse() {
         Ok(x) => x,
         Err(err) => {
-            error!("Error while getting thread count: {}", err);
+            error!("{}", err);
             return;
         }
     };
 
-    let storage = Arc::new(RwLock::new(ShardedStorageBackend::new_with_cpus(threads)));
+    let list = |name| matches.value_of(name).map_or_else(Vec::new, |list: &str| list.split(',').filter(|x| !x.is_empty()).map(String::from).collect());
+    let index = IndexConfig {
+        trigram_keys: list("trigram_keys"),
+        text_keys: list("text_keys"),
+        stopwords: list("stopwords"),
+        folded_keys: list("folded_keys"),
+    };
+
+    let query_timeout = match matches.value_of("query_timeout").unwrap().parse() {
+        Ok(0) => None,
+        Ok(x) => Some(Duration::from_millis(x)),
+        Err(err) => {
+            error!("Error while getting query timeout: {}", err);
+            return;
+        }
+    };
+
+    let parsers = match matches.value_of("parsers").unwrap() {
+        "auto" => multithread_backend::available_cpus() as usize,
+        parsers => match parsers.parse() {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Error while getting parser count: {}", err);
+                return;
+            }
+        },
+    };
+    let format: RecordFormat = match matches.value_of("format").unwrap().parse() {
+        Ok(x) => x,
+        Err(err) => {
+            error!("{}", err);
+            return;
+        }
+    };
+    let encoding: Encoding = match matches.value_of("encoding").unwrap().parse() {
+        Ok(x) => x,
+        Err(err) => {
+            error!("{}", err);
+            return;
+        }
+    };
+    let csv = match matches.value_of("csv_config").map(CsvConfig::from_file) {
+        None => CsvConfig::default(),
+        Some(Ok(x)) => x,
+        Some(Err(err)) => {
+            error!("{}", err);
+            return;
+        }
+    };
+    let loader_config = BulkLoaderConfig {
+        format,
+        encoding,
+        csv,
+        parsers,
+        error_sink: matches.value_of("error_file").map(PathBuf::from),
+        ..BulkLoaderConfig::default()
+    };
+
+    let http_port: u16 = match matches.value_of("http_port").unwrap().parse() {
+        Ok(x) => x,
+        Err(err) => {
+            error!("Error while getting http port: {}", err);
+            return;
+        }
+    };
+
+    let config = ShardedStorageBackendConfig {
+        shards: threads,
+        pinning,
+        queue_size,
+        max_memory,
+        layout,
+        index,
+    };
+    let backend = match matches.value_of("cluster") {
+        Some(path) => match ClusterConfig::from_file(path) {
+            Ok(cluster) => {
+                info!("Coordinating a cluster of {} nodes", cluster.nodes.len());
+                ShardedStorageBackend::new_cluster(cluster, config)
+            }
+            Err(err) => {
+                error!("{}", err);
+                return;
+            }
+        },
+        None => {
+            info!("Using {} shards (cpu pinning: {:?})", threads, config.pinning);
+            ShardedStorageBackend::new_with_config(config)
+        }
+    };
+
+    let storage = Arc::new(RwLock::new(backend));
+
+    if let Some(address) = matches.value_of("node_listen") {
+        let listener = match TcpListener::bind(address) {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Error while listening for coordinators on {}: {}", address, err);
+                return;
+            }
+        };
+        info!("Serving shards to coordinators on {}", address);
+        let storage = storage.clone();
+        std::thread::spawn(move || cluster::serve_node(listener, storage));
+    }
 
     ////////////// DATA LOADING AND EXAMPLE QUERIES //////////////
     if !matches.is_present("skip_startup_load") {
-        load_data_from_file(&storage, matches.value_of("file_to_load").unwrap());
+        let filename = matches.value_of("file_to_load").unwrap().to_string();
+        if matches.is_present("follow") {
+            let follow_config = FollowConfig {
+                offset_file: match (matches.value_of("offset_file"), filename.as_str()) {
+                    (Some(path), _) => Some(PathBuf::from(path)),
+                    (None, "-") => None,
+                    (None, filename) => Some(PathBuf::from(format!("{}.offset", filename))),
+                },
+                ..FollowConfig::default()
+            };
+            let follower = match Follower::new(storage.clone(), loader_config, follow_config) {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("{}", err);
+                    return;
+                }
+            };
+            info!("Following {}", filename);
+            std::thread::spawn(move || {
+                let followed = match filename.as_str() {
+                    "-" => follower.follow_stdin(),
+                    _ => follower.follow_file(&filename),
+                };
+                if let Err(err) = followed {
+                    error!("Stopped following {}: {}", filename, err);
+                }
+            });
+        } else {
+            load_data_from_file(&storage, &filename, loader_config);
+        }
+    }
+
+    if let Some(export_matches) = matches.subcommand_matches("export") {
+        export_records(&storage, export_matches);
+        return;
+    }
 
+    if !matches.is_present("skip_startup_load") {
         display_timed_query(&storage, query::Search::new(vec![query::Field::new_eq("author_family_name", "Tolkien")]));
 
         display_timed_query(
@@ -215,6 +620,26 @@ async fn main() {
             ),
         );
 
+        display_timed_query(
+            &storage,
+            query::Search::new(vec![query::Field::new_re("author_family_name", "(?i)tolkien")]),
+        );
+
+        display_timed_query(
+            &storage,
+            query::Search::new(vec![query::Field::new_folded_eq("author_family_name", "tolkien")]),
+        );
+
+        display_timed_query(
+            &storage,
+            query::Search::new(vec![query::Field::new_fuzzy("author_family_name", "Tolkein", 2)]),
+        );
+
+        display_timed_query(
+            &storage,
+            query::Search::new(vec![query::Field::new_eq("author_family_name", "Tolkien"), query::Field::new_not_empty("publisher")]),
+        );
+
         display_timed_key_query(
             &storage,
             query::KeyValuesSearch::new(vec![query::Field::new_eq("language", "English")], "extension"),
@@ -297,8 +722,16 @@ async fn main() {
                 query::Field::new_eq("title", "Anna Karénine"),
             ]),
         );
+
+        display_timed_query(
+            &storage,
+            query::Search::new(vec![
+                query::Field::new_eq("author_family_name", "Tolstoy"),
+                query::Field::new_folded_eq("title", "anna karenine"),
+            ]),
+        );
     }
 
     // storage.print_status();
-    api::serve(([0, 0, 0, 0], 8080), storage).await;
+    api::serve(([0, 0, 0, 0], http_port), storage, query_timeout).await;
 }
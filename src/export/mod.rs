@@ -0,0 +1,214 @@
+use crate::backend::multithread_backend::ShardedStorageBackend;
+use crate::lexer;
+use crate::record::{query, RCLabelPair, RCRecord};
+
+use serde::Serialize;
+
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/// Format of exported records, one per line (or CSV row)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// `{author="tolkien"}`, fails on the first label which cannot be written this way
+    Text,
+    /// NDJSON in the native `{"label_pairs":[{"key":..,"val":..}]}` form
+    Json,
+    /// One column per label key, lossy: labels are reordered and empty values are skipped on import
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ExportFormat::Text),
+            "json" | "ndjson" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(format!("Error unknown export format {} (expected text, json or csv)", s)),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Text => "text/plain; charset=utf-8",
+            ExportFormat::Json => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct NativeRecord<'a> {
+    label_pairs: &'a [RCLabelPair],
+}
+
+/// Search selecting the exported records, everything when there is none
+pub fn parse_selector(selector: Option<&str>) -> Result<query::Search, String> {
+    match selector.map(lexer::parse_query) {
+        None => Ok(query::Search::new(Vec::new())),
+        Some(Ok(query::Query::Simple(search))) => Ok(search),
+        Some(Ok(_)) => Err(String::from("Error the export selector must be a search like {author==\"tolkien\"}")),
+        Some(Err(e)) => Err(e),
+    }
+}
+
+/// The text lexer keeps values as they are between the quotes, without unescaping them and
+/// trimming their trailing spaces, so a value can't be escaped: it can only be written as is
+/// when it is a valid quoted literal on a single line
+fn is_text_safe(pair: &RCLabelPair) -> bool {
+    let key_ok = !pair.key.is_empty() && pair.key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if pair.val.ends_with(char::is_whitespace) {
+        return false;
+    }
+    let mut bytes = pair.val.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'"' | b'\n' | b'\r' => return false,
+            b'\\' => match bytes.next() {
+                None | Some(b'\n') | Some(b'\r') => return false,
+                Some(_) => (),
+            },
+            _ => (),
+        }
+    }
+    key_ok
+}
+
+fn write_json(record: &RCRecord, out: &mut impl Write) -> Result<(), String> {
+    serde_json::to_writer(&mut *out, &NativeRecord { label_pairs: &record.label_pairs }).map_err(|e| e.to_string())?;
+    writeln!(out).map_err(|e| e.to_string())
+}
+
+fn write_text(record: &RCRecord, out: &mut impl Write) -> Result<(), String> {
+    if let Some(pair) = record.label_pairs.iter().find(|pair| !is_text_safe(pair)) {
+        return Err(format!("Error label {}={:?} can't be exported as text, export it as JSON instead", pair.key, pair.val));
+    }
+    let labels: Vec<String> = record.label_pairs.iter().map(|pair| format!("{}=\"{}\"", pair.key, pair.val)).collect();
+    writeln!(out, "{{{}}}", labels.join(", ")).map_err(|e| e.to_string())
+}
+
+/// Write the records matching the search to `out`. The ids of the records are taken once, then
+/// the records are streamed from the shards. CSV reads them twice: once to find the columns, once
+/// to write the rows, both passes reading the same records.
+pub fn export(storage: &RwLock<ShardedStorageBackend>, search: query::Search, format: ExportFormat, mut out: impl Write) -> Result<usize, String> {
+    let snapshot = storage.read().unwrap().export_snapshot(&search)?;
+    let exported = match format {
+        ExportFormat::Text => ShardedStorageBackend::export(storage, &snapshot, |record| write_text(&record, &mut out))?,
+        ExportFormat::Json => ShardedStorageBackend::export(storage, &snapshot, |record| write_json(&record, &mut out))?,
+        ExportFormat::Csv => {
+            let mut columns: Vec<String> = Vec::new();
+            ShardedStorageBackend::export(storage, &snapshot, |record| {
+                for pair in record.label_pairs.iter() {
+                    if !columns.iter().any(|column| **column == *pair.key) {
+                        columns.push(pair.key.to_string());
+                    }
+                }
+                Ok(())
+            })?;
+            let mut writer = ::csv::Writer::from_writer(&mut out);
+            writer.write_record(&columns).map_err(|e| e.to_string())?;
+            let exported = ShardedStorageBackend::export(storage, &snapshot, |record| {
+                let row = columns.iter().map(|column| {
+                    let pair = record.label_pairs.iter().find(|pair| *pair.key == **column);
+                    pair.map(|pair| pair.val.as_ref()).unwrap_or_default()
+                });
+                writer.write_record(row).map_err(|e| e.to_string())
+            })?;
+            writer.flush().map_err(|e| e.to_string())?;
+            exported
+        }
+    };
+    out.flush().map_err(|e| e.to_string())?;
+    Ok(exported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::{BulkLoader, BulkLoaderConfig, RecordFormat};
+    use std::collections::HashSet;
+
+    fn lines(backend: &RwLock<ShardedStorageBackend>, format: ExportFormat) -> Vec<String> {
+        let mut out = Vec::new();
+        export(backend, query::Search::new(Vec::new()), format, &mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(String::from).collect()
+    }
+
+    fn load(input: &str) -> RwLock<ShardedStorageBackend> {
+        let backend = RwLock::new(ShardedStorageBackend::new_with_cpus(2));
+        BulkLoader::new(&backend.read().unwrap(), BulkLoaderConfig::default()).load(input.as_bytes()).unwrap();
+        backend
+    }
+
+    /// Export the records, reimport them and check they export the same
+    fn round_trip(backend: &RwLock<ShardedStorageBackend>, format: ExportFormat) -> Vec<String> {
+        let exported = lines(backend, format);
+        let mut round_trip = lines(&load(&exported.join("\n")), format);
+        let mut original = exported.clone();
+        round_trip.sort();
+        original.sort();
+        assert_eq!(round_trip, original);
+        exported
+    }
+
+    #[test]
+    fn it_round_trips_exports() {
+        let text = r#"{author="tolkien", title="The \"Hobbit\"", note="C:\\"}
+{author="asimov", title="Foundation", publisher=""}"#;
+        let input = format!("{}\n{}", text, r#"{"label_pairs":[{"key":"author","val":"herbert"},{"key":"title","val":"Dune \"1965\"\nfirst"}]}"#);
+        let backend = load(&input);
+        assert_eq!(round_trip(&backend, ExportFormat::Json).len(), 3);
+
+        // A value with a quote or a newline can't be written between quotes
+        let mut out = Vec::new();
+        let error = export(&backend, query::Search::new(Vec::new()), ExportFormat::Text, &mut out).unwrap_err();
+        assert!(error.contains("title=\"Dune"));
+        let exported = round_trip(&load(text), ExportFormat::Text);
+        assert_eq!(exported.iter().map(|l| l.as_str()).collect::<HashSet<&str>>(), text.lines().collect());
+
+        let csv = lines(&backend, ExportFormat::Csv);
+        assert_eq!(csv.len(), 5);
+        let reimported = ShardedStorageBackend::new_with_cpus(1);
+        let config = BulkLoaderConfig {
+            format: RecordFormat::Csv,
+            ..BulkLoaderConfig::default()
+        };
+        let summary = BulkLoader::new(&reimported, config).load(csv.join("\n").as_bytes()).unwrap();
+        assert_eq!((summary.added, summary.rejected), (3, 0));
+
+        let selected = parse_selector(Some(r#"{author=="asimov"}"#)).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(export(&backend, selected, ExportFormat::Text, &mut out).unwrap(), 1);
+        assert!(parse_selector(Some(r#"label_values({author=="asimov"}, title)"#)).is_err());
+    }
+
+    #[test]
+    fn it_exports_the_records_of_its_snapshot() {
+        let backend = RwLock::new(ShardedStorageBackend::new_with_cpus(2));
+        let add = |line: &str| {
+            backend.read().unwrap().raw_add(String::from(line));
+            backend.read().unwrap().wait_pending_operations();
+        };
+        add(r#"{author="tolkien", title="The Hobbit"}"#);
+        let snapshot = backend.read().unwrap().export_snapshot(&query::Search::new(Vec::new())).unwrap();
+        // Added between two passes of a CSV export, with a key the first pass didn't see
+        add(r#"{author="herbert", title="Dune", year="1965"}"#);
+        let mut passes = Vec::new();
+        for _ in 0..2 {
+            let mut authors = Vec::new();
+            ShardedStorageBackend::export(&backend, &snapshot, |record| {
+                authors.push(record.label_pairs[0].val.to_string());
+                Ok(())
+            })
+            .unwrap();
+            passes.push(authors);
+        }
+        assert_eq!(passes, vec![vec![String::from("tolkien")]; 2]);
+        assert_eq!(lines(&backend, ExportFormat::Csv), vec!["author,title,year", "tolkien,The Hobbit,", "herbert,Dune,1965"]);
+    }
+}
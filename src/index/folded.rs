@@ -0,0 +1,100 @@
+use smallvec::SmallVec;
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::Bound;
+use std::sync::Arc;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Most folded values are the folded form of a single value, which is kept inline
+type Values = SmallVec<[Arc<str>; 1]>;
+
+/// Roughly what a B-tree of folded values costs per entry, its nodes being two thirds full on average
+const FOLDED_ENTRY_BYTES: usize = (mem::size_of::<Box<str>>() + mem::size_of::<Values>()) * 3 / 2;
+
+/// Lowercase a text and strip its diacritics: `Anna Karénine` is folded to `anna karenine`
+pub(super) fn fold(text: &str) -> String {
+    // ASCII texts are already decomposed
+    match text.is_ascii() {
+        true => text.to_ascii_lowercase(),
+        // Case insensitive regexes also match the final sigma with the other one
+        false => text
+            .nfkd()
+            .filter(|c| !is_combining_mark(*c))
+            .flat_map(char::to_lowercase)
+            .map(|c| if c == 'ς' { 'σ' } else { c })
+            .collect(),
+    }
+}
+
+/// The values of a key by their folded form
+pub(super) struct FoldedValues {
+    values: BTreeMap<Box<str>, Values>,
+    // Estimated bytes of the folded values and of the lists of values, kept up to date on insert
+    bytes: usize,
+}
+
+impl FoldedValues {
+    pub(super) fn new() -> FoldedValues {
+        FoldedValues {
+            values: BTreeMap::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Index a value, which must not be indexed already
+    pub(super) fn insert(&mut self, value: &Arc<str>) {
+        let folded = fold(value);
+        let values = match self.values.get_mut(folded.as_str()) {
+            Some(values) => values,
+            None => {
+                self.bytes += FOLDED_ENTRY_BYTES + folded.len();
+                self.values.entry(Box::from(folded)).or_default()
+            }
+        };
+        let spilled = |values: &Values| if values.spilled() { values.capacity() * mem::size_of::<Arc<str>>() } else { 0 };
+        self.bytes -= spilled(values);
+        values.push(value.clone());
+        self.bytes += spilled(values);
+    }
+
+    /// Values folding to the given folded text
+    pub(super) fn get(&self, folded: &str) -> &[Arc<str>] {
+        self.values.get(folded).map_or(&[], |values| values.as_slice())
+    }
+
+    /// Values whose folded form starts with the given folded prefix
+    pub(super) fn prefixed<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a Arc<str>> + 'a {
+        self.values
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(folded, _)| folded.starts_with(prefix))
+            .flat_map(|(_, values)| values)
+    }
+
+    /// Estimated bytes of the tree, the values being shared with the records
+    pub(super) fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_folds_case_and_diacritics() {
+        assert_eq!(fold("Anna Karénine"), "anna karenine");
+        assert_eq!(fold("ÉCOLE Ø ﬁn"), "ecole ø fin");
+        assert_eq!(fold("Tolkien"), "tolkien");
+        assert_eq!(fold("Οδυσσεύς"), fold("ΟΔΥΣΣΕΥΣ"));
+
+        let mut folded = FoldedValues::new();
+        for value in ["Karénine", "KARENINE", "Karamazov", "Tolkien"] {
+            folded.insert(&Arc::from(value));
+        }
+        assert_eq!(folded.get("karenine"), [Arc::from("Karénine"), Arc::from("KARENINE")]);
+        assert!(folded.get("Karenine").is_empty());
+        assert_eq!(folded.prefixed("kar").count(), 3);
+        assert!(folded.bytes() > 0);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::record;
+use crate::record::query;
+
+use lazy_static::lazy_static;
+use levenshtein_automata::{Distance, LevenshteinAutomatonBuilder, DFA, SINK_STATE};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+lazy_static! {
+    // Building the parametric automata of a distance is not free, they are shared by the searches
+    static ref BUILDERS: Vec<LevenshteinAutomatonBuilder> =
+        (0..=query::MAX_FUZZY_DISTANCE).map(|distance| LevenshteinAutomatonBuilder::new(distance, false)).collect();
+}
+
+/// Automaton accepting the texts within the distance of the value, the distance being capped
+pub(super) fn automaton(value: &str, distance: u8) -> DFA {
+    BUILDERS[distance.min(query::MAX_FUZZY_DISTANCE) as usize].build_dfa(value)
+}
+
+fn distance(dfa: &DFA, text: &str) -> Option<u8> {
+    match dfa.eval(text) {
+        Distance::Exact(distance) => Some(distance),
+        Distance::AtLeast(_) => None,
+    }
+}
+
+/// Values of the tree accepted by the automaton, in order, until `in_time` is false for the
+/// number of values visited. Once the automaton rejects a prefix, the values starting with it
+/// are skipped at once.
+pub(super) fn matching<'a, V>(values: &'a BTreeMap<Arc<str>, V>, dfa: &DFA, in_time: impl Fn(usize) -> bool) -> Vec<(&'a Arc<str>, &'a V)> {
+    let mut matched = Vec::new();
+    let mut from = Bound::Unbounded;
+    let mut visited = 0;
+    while let Some((value, v)) = values.range::<str, _>((from.as_ref().map(String::as_str), Bound::Unbounded)).next() {
+        if !in_time(visited) {
+            break;
+        }
+        visited += 1;
+        let mut state = dfa.initial_state();
+        let rejected = value.bytes().position(|byte| {
+            state = dfa.transition(state, byte);
+            state == SINK_STATE
+        });
+        from = match rejected.and_then(|i| skip_prefix(value, i)) {
+            Some(next) => Bound::Included(next),
+            None => {
+                if rejected.is_none() && distance(dfa, value).is_some() {
+                    matched.push((value, v));
+                }
+                Bound::Excluded(value.to_string())
+            }
+        };
+    }
+    matched
+}
+
+/// Smallest text greater than the texts starting with the value up to the char of the byte
+/// at `i`, None when there is none
+fn skip_prefix(value: &str, i: usize) -> Option<String> {
+    let (start, c) = value.char_indices().take_while(|(start, _)| *start <= i).last()?;
+    // Surrogates are not chars, the next one is past them
+    let next = (c as u32 + 1..=char::MAX as u32).find_map(char::from_u32)?;
+    let mut next_value = String::from(&value[..start]);
+    next_value.push(next);
+    Some(next_value)
+}
+
+/// Reports the values of a record matched by the fuzzy fields of a search, along with their distance
+pub struct FuzzyMatcher {
+    fields: Vec<(Box<str>, DFA)>,
+}
+
+impl FuzzyMatcher {
+    pub fn new(search: &query::Search) -> FuzzyMatcher {
+        let fields = search
+            .search_fields
+            .iter()
+            .filter_map(|field| match field.op {
+                query::Operation::Fuzzy(distance) => Some((field.key.clone(), automaton(&field.val, distance))),
+                _ => None,
+            })
+            .collect();
+        FuzzyMatcher { fields }
+    }
+
+    pub fn matches(&self, record: &record::RCRecord) -> Vec<record::FuzzyMatch> {
+        let mut matches = Vec::new();
+        for (key, dfa) in &self.fields {
+            for pair in record.label_pairs.iter().filter(|pair| *pair.key == **key) {
+                if let Some(distance) = distance(dfa, &pair.val) {
+                    matches.push(record::FuzzyMatch {
+                        key: pair.key.clone(),
+                        value: pair.val.clone(),
+                        distance,
+                    });
+                }
+            }
+        }
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_skips_the_values_rejected_by_prefix() {
+        let values: BTreeMap<Arc<str>, u32> = ["Tolkien", "Tolkin", "Tolstoy", "Tokien", "Tolkein", "Toulkien", "Tølkien", "Zola", "Tolkienne"]
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (Arc::from(*value), i as u32))
+            .collect();
+        for (query, max) in [("Tolkein", 2), ("Tolkien", 1), ("Tolkien", 0), ("Tølkien", 1), ("", 2)] {
+            let dfa = automaton(query, max);
+            let walked: Vec<&str> = matching(&values, &dfa, |_| true).into_iter().map(|(value, _)| value.as_ref()).collect();
+            let scanned: Vec<&str> = values.keys().map(|value| value.as_ref()).filter(|value| distance(&dfa, value).is_some()).collect();
+            assert_eq!(walked, scanned, "{}~{}", query, max);
+        }
+        let dfa = automaton("Tolkein", 2);
+        assert_eq!(distance(&dfa, "Tolkien"), Some(2));
+        assert_eq!(distance(&dfa, "Tolstoy"), None);
+        assert_eq!(skip_prefix("Tolkien", 3), Some(String::from("Toll")));
+        assert_eq!(skip_prefix("Tølkien", 2), Some(String::from("Tù")));
+    }
+}
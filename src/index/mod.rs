@@ -1,6 +1,19 @@
 use super::record;
 use super::record::query;
 
+mod folded;
+mod fuzzy;
+mod sealed;
+mod text;
+mod trigram;
+
+use folded::{fold, FoldedValues};
+use sealed::SealedValues;
+use text::{TextIndex, TextQuery, Tokenizer};
+use trigram::{TrigramIndex, TrigramQuery};
+
+pub use fuzzy::FuzzyMatcher;
+
 use hashbrown::HashMap;
 use log::debug;
 use regex::Regex;
@@ -8,8 +21,24 @@ use regex_syntax::hir::literal::Literals;
 use regex_syntax::Parser;
 use roaring::RoaringBitmap;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap};
+use std::mem;
 use std::sync::Arc;
+use std::time::Instant;
+
+/// Number of values scanned between two checks of the query deadline
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
+/// Number of keys ranked in the index status
+pub const TOP_KEYS: usize = 10;
+
+/// Roughly what a B-tree of values costs per entry, its nodes being two thirds full on average
+const TREE_ENTRY_BYTES: usize = (mem::size_of::<Arc<str>>() + mem::size_of::<RoaringBitmap>()) * 3 / 2;
+
+/// Fresh values of a key are sealed once there are this many of them, and at least a quarter
+/// as many as the values already sealed so that sealing stays linear in the number of values
+const SEAL_MIN_VALUES: usize = 16384;
 
 #[allow(dead_code)]
 pub enum KeyValuesSearchResult {
@@ -18,20 +47,127 @@ pub enum KeyValuesSearchResult {
     DirtyOk(Vec<u32>),
 }
 
+/// A record ranked by its score, ties broken by id so the ranking is stable
+#[derive(PartialEq)]
+struct Scored {
+    score: f64,
+    id: u32,
+}
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score).then_with(|| other.id.cmp(&self.id))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 /// Index contains a map of field name to field
 /// A field contains a map of
 pub struct Index {
     label_key_index: HashMap<Arc<str>, Field>,
+    // Every record indexed, to find the ones missing a key
+    ids: RoaringBitmap,
+    config: IndexConfig,
+    tokenizer: Tokenizer,
+    // Estimated bytes of the posting lists, of the trees of values and of the trigram, text and
+    // folded indexes, kept up to date on insert
+    bitmap_bytes: usize,
+    tree_bytes: usize,
+    trigram_bytes: usize,
+    text_bytes: usize,
+    folded_bytes: usize,
+}
+
+/// Optional indexes, only built for the keys they are enabled on since they cost memory
+#[derive(Clone, Debug, Default)]
+pub struct IndexConfig {
+    /// Keys whose values are indexed by trigrams, to narrow down regexes without a literal prefix
+    pub trigram_keys: Vec<String>,
+    /// Keys whose values are split into words searchable with `~=`
+    pub text_keys: Vec<String>,
+    /// Words left out of the texts and of the text searches
+    pub stopwords: Vec<String>,
+    /// Keys whose values are also kept lowercased and without diacritics, for `==i` and `(?i)` regexes
+    pub folded_keys: Vec<String>,
 }
 
-// Boilerplate for index status
-#[derive(Serialize, Deserialize)]
-pub struct IndexStatus {}
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct IndexStatus {
+    pub keys: usize,
+    /// Distinct values, summed over the keys
+    pub values: usize,
+    /// Ids in the posting lists, summed over the values
+    pub postings: u64,
+    pub largest_posting_list: Option<PostingListStatus>,
+    /// Estimated bytes of the posting lists
+    pub bitmap_bytes: usize,
+    /// Estimated bytes of the trees of values, the values themselves are shared with the records
+    pub tree_bytes: usize,
+    /// Estimated bytes of the trigram indexes
+    #[serde(default)]
+    pub trigram_bytes: usize,
+    /// Estimated bytes of the text indexes
+    #[serde(default)]
+    pub text_bytes: usize,
+    /// Estimated bytes of the trees of folded values
+    #[serde(default)]
+    pub folded_bytes: usize,
+    /// Keys with the most distinct values
+    pub top_keys: Vec<KeyStatus>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PostingListStatus {
+    pub key: Arc<str>,
+    pub value: Arc<str>,
+    pub postings: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyStatus {
+    pub key: Arc<str>,
+    pub values: usize,
+    pub postings: u64,
+    pub largest_posting_list: Option<PostingListStatus>,
+    pub bitmap_bytes: usize,
+    /// Estimated bytes of the tree of fresh values and of the dictionary of sealed values
+    pub tree_bytes: usize,
+    /// Values moved to the immutable dictionary
+    #[serde(default)]
+    pub sealed_values: usize,
+    /// Estimated bytes of the trigram index, None when the key has none
+    #[serde(default)]
+    pub trigram_bytes: Option<usize>,
+    /// Estimated bytes of the text index, None when the key has none
+    #[serde(default)]
+    pub text_bytes: Option<usize>,
+    /// Estimated bytes of the tree of folded values, None when the key has none
+    #[serde(default)]
+    pub folded_bytes: Option<usize>,
+    /// Values with the largest posting lists, only filled when drilling into a key
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub top_values: Vec<(Arc<str>, u64)>,
+}
 
 impl Index {
-    pub fn new() -> Index {
+    pub fn new_with_config(config: IndexConfig) -> Index {
         Index {
             label_key_index: HashMap::new(),
+            ids: RoaringBitmap::new(),
+            tokenizer: Tokenizer::new(&config.stopwords),
+            config,
+            bitmap_bytes: 0,
+            tree_bytes: 0,
+            trigram_bytes: 0,
+            text_bytes: 0,
+            folded_bytes: 0,
         }
     }
 
@@ -44,14 +180,17 @@ impl Index {
             return KeyValuesSearchResult::Ok(Vec::new());
         }
 
-        let map = &field.unwrap().field_map;
-        if query.query_flags.contains(query::SearchFlags::ABORT_EARLY) && map.len() as u64 > records.len() {
+        let field = field.unwrap();
+        if query.query_flags.contains(query::SearchFlags::ABORT_EARLY) && field.len() as u64 > records.len() {
             return KeyValuesSearchResult::DirtyOk(records.iter().collect());
         }
 
         KeyValuesSearchResult::Ok(
-            map.iter()
-                .filter_map(|field| {
+            field
+                .iter()
+                .enumerate()
+                .take_while(|(i, _)| !i.is_multiple_of(DEADLINE_CHECK_INTERVAL) || !query.is_expired())
+                .filter_map(|(_, field)| {
                     let res = &records & field.1;
                     if res.is_empty() {
                         None
@@ -67,12 +206,17 @@ impl Index {
         // TODO: generate a result instead of empty bitmap
 
         // Key search phase
-        // Get the list of possible values from the index for each keys
+        // Get the list of possible values from the index for each keys, a missing key only
+        // matching the records missing it: all of them
         let key_search: Option<Vec<_>> = query
             .search_fields
             .clone()
             .into_iter()
-            .map(|query| self.label_key_index.get(query.key.as_ref()).map(|field| (query, field)))
+            .filter_map(|query| match (self.label_key_index.get(query.key.as_ref()), &query.op) {
+                (Some(field), _) => Some(Some((query, field))),
+                (None, query::Operation::Missing) => None,
+                (None, _) => Some(None),
+            })
             .collect();
 
         if key_search.is_none() {
@@ -80,68 +224,317 @@ impl Index {
         }
 
         let mut t = key_search.unwrap().into_iter().map(|q| match q.0.op {
-            query::Operation::Re => q.1.re_aggregated_get(&q.0, &query.query_flags),
+            query::Operation::Re => q.1.re_aggregated_get(&q.0, &query.query_flags, query.deadline),
             query::Operation::Eq => q.1.eq_get(&q.0),
+            query::Operation::Text => q.1.text_get(&TextQuery::parse(&q.0.val, &self.tokenizer), &self.tokenizer, query.deadline),
+            query::Operation::FoldedEq => q.1.folded_get(&fold(&q.0.val), query.deadline),
+            query::Operation::Fuzzy(distance) => q.1.fuzzy_get(&q.0.val, distance, query.deadline),
+            query::Operation::Exists => q.1.records.clone(),
+            query::Operation::Missing => &self.ids - &q.1.records,
+            query::Operation::NotEmpty => q.1.not_empty_get(),
         });
 
         let last = t.next_back();
         if last.is_none() {
-            return RoaringBitmap::new();
+            // Either nothing was searched, or only keys no record has, as missing
+            return match query.is_match_all() {
+                true => RoaringBitmap::new(),
+                false => self.ids.clone(),
+            };
         }
-        t.fold(last.unwrap(), |a, b| {
+        let mut result = last.unwrap();
+        loop {
+            // A field cut by the deadline only keeps some of its matches, so the intersection
+            // stays a subset of the real result. Skipping a field would not, give up instead.
+            if query.is_expired() {
+                return RoaringBitmap::new();
+            }
             // TODO: Break early if the bitmap a is empty
-            a & b
-        })
+            match t.next() {
+                Some(b) => result &= b,
+                None => return result,
+            }
+        }
     }
 
     pub fn search(&self, query: &query::Search) -> Vec<u32> {
         self.simple_search(query).iter().collect()
     }
 
+    /// Up to `limit` matching ids, starting at `from`
+    pub fn search_from(&self, query: &query::Search, from: u32, limit: usize) -> Vec<u32> {
+        let mut ids = self.simple_search(query);
+        ids.remove_range(0..from as u64);
+        ids.iter().take(limit).collect()
+    }
+
+    pub fn count(&self, query: &query::Search) -> usize {
+        self.simple_search(query).len() as usize
+    }
+
+    /// Statistics of the words searched as text, for the keys having a text index
+    pub fn text_stats(&self, query: &query::Search) -> query::TextStats {
+        let mut stats = query::TextStats::default();
+        for (field_query, text) in self.text_fields(query) {
+            let key_stats = text.stats(&TextQuery::parse(&field_query.val, &self.tokenizer));
+            stats.keys.entry(field_query.key.clone()).or_insert_with(|| key_stats.clone()).frequencies.extend(key_stats.frequencies);
+        }
+        stats
+    }
+
+    /// The `limit` matching ids having the best BM25 scores, best first. Only the keys having a
+    /// text index contribute to the scores, the other fields filter.
+    pub fn ranked_search(&self, query: &query::Search, stats: &query::TextStats, limit: usize) -> Vec<(u32, f64)> {
+        let scorers: Vec<_> = self
+            .text_fields(query)
+            .filter_map(|(field_query, text)| {
+                let key_stats = stats.keys.get(&field_query.key)?;
+                Some((text, TextQuery::parse(&field_query.val, &self.tokenizer), key_stats))
+            })
+            .collect();
+        let mut best = BinaryHeap::new();
+        for (i, id) in self.simple_search(query).iter().enumerate() {
+            if i.is_multiple_of(DEADLINE_CHECK_INTERVAL) && query.is_expired() {
+                break;
+            }
+            let score = scorers.iter().map(|(text, text_query, key_stats)| text.score(id, text_query, key_stats, &self.tokenizer)).sum();
+            best.push(Reverse(Scored { score, id }));
+            if best.len() > limit {
+                best.pop();
+            }
+        }
+        best.into_sorted_vec().into_iter().map(|Reverse(scored)| (scored.id, scored.score)).collect()
+    }
+
+    fn text_fields<'a>(&'a self, query: &'a query::Search) -> impl Iterator<Item = (&'a query::Field, &'a TextIndex)> {
+        query.search_fields.iter().filter(|field_query| matches!(field_query.op, query::Operation::Text)).filter_map(move |field_query| {
+            let text = self.label_key_index.get(field_query.key.as_ref())?.text.as_ref()?;
+            Some((field_query, text))
+        })
+    }
+
     pub fn insert_record(&mut self, id: u32, record: &record::RCRecord) {
+        let before = self.ids.serialized_size();
+        self.ids.insert(id);
+        self.bitmap_bytes = self.bitmap_bytes + self.ids.serialized_size() - before;
+        let (config, tokenizer) = (&self.config, &self.tokenizer);
         for pair in &record.label_pairs {
-            let field = self.label_key_index.entry(pair.key.clone()).or_insert_with(Field::new);
-            field.add_posting(pair.val.clone(), id);
+            let enabled = |keys: &Vec<String>| keys.iter().any(|key| **key == *pair.key);
+            let field = self
+                .label_key_index
+                .entry(pair.key.clone())
+                .or_insert_with(|| Field::new(enabled(&config.trigram_keys), enabled(&config.text_keys), enabled(&config.folded_keys)));
+            let (new_value, bitmap_growth) = field.add_posting(pair.val.clone(), id);
+            self.bitmap_bytes = (self.bitmap_bytes as isize + bitmap_growth) as usize;
+            if new_value {
+                self.tree_bytes += TREE_ENTRY_BYTES;
+                if let Some(trigrams) = field.trigrams.as_mut() {
+                    let before = trigrams.bytes();
+                    trigrams.insert(&pair.val);
+                    self.trigram_bytes = self.trigram_bytes + trigrams.bytes() - before;
+                }
+                if let Some(folded) = field.folded.as_mut() {
+                    let before = folded.bytes();
+                    folded.insert(&pair.val);
+                    self.folded_bytes = self.folded_bytes + folded.bytes() - before;
+                }
+            }
+            if let Some(text) = field.text.as_mut() {
+                let before = text.bytes();
+                text.insert(id, &pair.val, tokenizer);
+                self.text_bytes = self.text_bytes + text.bytes() - before;
+            }
+            if field.field_map.len() >= SEAL_MIN_VALUES.max(field.sealed_len() / 4) {
+                let before = field.tree_bytes();
+                field.seal();
+                self.tree_bytes = self.tree_bytes + field.tree_bytes() - before;
+            }
         }
     }
 
+    /// Estimated bytes used by the index, the values being shared with the records
+    pub fn memory_usage(&self) -> usize {
+        self.bitmap_bytes + self.tree_bytes + self.trigram_bytes + self.text_bytes + self.folded_bytes
+    }
+
     pub fn get_status(&self) -> IndexStatus {
-        IndexStatus {}
+        let mut keys: Vec<KeyStatus> = self.label_key_index.iter().map(|(key, field)| field.get_status(key, 0)).collect();
+        let mut status = IndexStatus {
+            keys: keys.len(),
+            values: keys.iter().map(|key| key.values).sum(),
+            postings: keys.iter().map(|key| key.postings).sum(),
+            largest_posting_list: keys
+                .iter()
+                .filter_map(|key| key.largest_posting_list.clone())
+                .max_by_key(|largest| largest.postings),
+            bitmap_bytes: keys.iter().map(|key| key.bitmap_bytes).sum::<usize>() + self.ids.serialized_size(),
+            tree_bytes: keys.iter().map(|key| key.tree_bytes).sum(),
+            trigram_bytes: keys.iter().filter_map(|key| key.trigram_bytes).sum(),
+            text_bytes: keys.iter().filter_map(|key| key.text_bytes).sum(),
+            folded_bytes: keys.iter().filter_map(|key| key.folded_bytes).sum(),
+            top_keys: Vec::new(),
+        };
+        keys.sort_unstable_by_key(|key| Reverse(key.values));
+        keys.truncate(TOP_KEYS);
+        status.top_keys = keys;
+        status
+    }
+
+    /// Statistics of a key along with its `top` values having the largest posting lists
+    pub fn get_key_status(&self, key: &str, top: usize) -> Option<KeyStatus> {
+        self.label_key_index.get_key_value(key).map(|(key, field)| field.get_status(key, top))
     }
 }
 
-#[derive(Clone)]
+/// The values of a key: the fresh ones in a tree, the older ones sealed in a transducer
 struct Field {
     field_map: BTreeMap<Arc<str>, RoaringBitmap>,
+    sealed: Option<SealedValues>,
+    // Records carrying the key, whatever its value
+    records: RoaringBitmap,
+    trigrams: Option<TrigramIndex>,
+    text: Option<TextIndex>,
+    folded: Option<FoldedValues>,
 }
 
-impl<'a> Field {
-    fn new() -> Field {
-        Field { field_map: BTreeMap::new() }
+impl Field {
+    fn new(trigrams: bool, text: bool, folded: bool) -> Field {
+        Field {
+            field_map: BTreeMap::new(),
+            sealed: None,
+            records: RoaringBitmap::new(),
+            trigrams: match trigrams {
+                true => Some(TrigramIndex::new()),
+                false => None,
+            },
+            text: match text {
+                true => Some(TextIndex::new()),
+                false => None,
+            },
+            folded: match folded {
+                true => Some(FoldedValues::new()),
+                false => None,
+            },
+        }
     }
 
-    fn add_posting(&mut self, key: Arc<str>, id: u32) {
-        let posting_list = self.field_map.entry(key).or_insert_with(RoaringBitmap::new);
+    fn len(&self) -> usize {
+        self.field_map.len() + self.sealed_len()
+    }
+
+    fn sealed_len(&self) -> usize {
+        self.sealed.as_ref().map_or(0, |sealed| sealed.len())
+    }
+
+    /// Sealed values first then fresh ones, each part in order
+    fn iter(&self) -> impl Iterator<Item = (&Arc<str>, &RoaringBitmap)> {
+        self.sealed.iter().flat_map(|sealed| sealed.iter()).chain(self.field_map.iter())
+    }
+
+    fn tree_bytes(&self) -> usize {
+        self.field_map.len() * TREE_ENTRY_BYTES + self.sealed.as_ref().map_or(0, |sealed| sealed.bytes())
+    }
+
+    /// Move the fresh values to the dictionary of sealed values
+    fn seal(&mut self) {
+        let fresh = mem::take(&mut self.field_map);
+        self.sealed = Some(SealedValues::seal(self.sealed.take(), fresh));
+    }
+
+    fn get_status(&self, key: &Arc<str>, top: usize) -> KeyStatus {
+        let mut top_values = BinaryHeap::with_capacity(top + 1);
+        let mut status = KeyStatus {
+            key: key.clone(),
+            values: self.len(),
+            postings: 0,
+            largest_posting_list: None,
+            bitmap_bytes: self.records.serialized_size(),
+            tree_bytes: self.tree_bytes(),
+            sealed_values: self.sealed_len(),
+            trigram_bytes: self.trigrams.as_ref().map(|trigrams| trigrams.bytes()),
+            text_bytes: self.text.as_ref().map(|text| text.bytes()),
+            folded_bytes: self.folded.as_ref().map(|folded| folded.bytes()),
+            top_values: Vec::new(),
+        };
+        for (value, posting_list) in self.iter() {
+            let postings = posting_list.len();
+            status.postings += postings;
+            status.bitmap_bytes += posting_list.serialized_size();
+            if status.largest_posting_list.as_ref().is_none_or(|largest| postings > largest.postings) {
+                status.largest_posting_list = Some(PostingListStatus {
+                    key: key.clone(),
+                    value: value.clone(),
+                    postings,
+                });
+            }
+            if top > 0 {
+                top_values.push(Reverse((postings, value.clone())));
+                if top_values.len() > top {
+                    top_values.pop();
+                }
+            }
+        }
+        status.top_values = top_values.into_sorted_vec().into_iter().map(|Reverse((postings, value))| (value, postings)).collect();
+        status
+    }
+
+    /// Returns whether the value is new, and by how many bytes its posting list and the records
+    /// of the key grew
+    fn add_posting(&mut self, key: Arc<str>, id: u32) -> (bool, isize) {
+        let records_before = self.records.serialized_size() as isize;
+        self.records.insert(id);
+        let records_growth = self.records.serialized_size() as isize - records_before;
+        if let Some(posting_list) = self.sealed.as_mut().and_then(|sealed| sealed.get_mut(&key)) {
+            let before = posting_list.serialized_size() as isize;
+            posting_list.insert(id);
+            return (false, records_growth + posting_list.serialized_size() as isize - before);
+        }
+        let new_value = !self.field_map.contains_key(&key);
+        let posting_list = self.field_map.entry(key).or_default();
+        let before = posting_list.serialized_size() as isize;
         posting_list.insert(id);
+        (new_value, records_growth + posting_list.serialized_size() as isize - before)
     }
 
-    fn re_aggregated_get(&self, field_query: &query::Field, flags: &query::SearchFlags) -> RoaringBitmap {
+    /// Stops scanning values once the deadline is reached, returning the matches found so far
+    fn re_aggregated_get(&self, field_query: &query::Field, flags: &query::SearchFlags, deadline: Option<Instant>) -> RoaringBitmap {
         // TODO: generate a result instead of option
-        let re = Regex::new(format!("^{}$", &field_query.val).as_str()).unwrap();
+        let re = Regex::new(format!("^(?:{})$", &field_query.val).as_str()).unwrap();
         let mut count = 0;
         let mut matched = 0;
         let mut result = RoaringBitmap::new();
-        let optimized_fields = optimize_regex(&field_query.val);
-        if flags.contains(query::SearchFlags::OPTIMIZE_REGEX_SEARCH) && !optimized_fields.is_empty() {
+        let folded_prefixes = self.folded_prefixes(&field_query.val, flags);
+        let optimized_fields = match folded_prefixes {
+            Some(_) => Vec::new(),
+            None => optimize_regex(&field_query.val),
+        };
+        let in_time = |i: usize| !i.is_multiple_of(DEADLINE_CHECK_INTERVAL) || !query::is_expired(deadline);
+        if let Some((folded, prefixes)) = folded_prefixes {
+            debug!("Running query on the values folding to {:?}", prefixes);
+            prefixes
+                .iter()
+                .flat_map(|prefix| folded.prefixed(prefix))
+                .enumerate()
+                .take_while(|(i, _)| in_time(*i))
+                .for_each(|(_, value)| {
+                    count += 1;
+                    if re.is_match(value) {
+                        result |= self.get(value).unwrap();
+                        matched += 1;
+                    }
+                });
+        } else if flags.contains(query::SearchFlags::OPTIMIZE_REGEX_SEARCH) && !optimized_fields.is_empty() {
             debug!("Running query in optimized mod");
             optimized_fields.into_iter().for_each(|lit| {
                 debug!("Search for {} (cut:{})", lit.1, lit.0);
                 if lit.0 {
                     // If it's a prefix do a range search and fold along the way
-                    (&self.field_map)
-                        .range(lit.1.clone()..)
-                        .take_while(|(k, _)| (**k).starts_with(&*lit.1.clone()))
-                        .for_each(|field| {
+                    let sealed = self.sealed.iter().flat_map(|sealed| sealed.prefixed(&lit.1));
+                    sealed
+                        .chain(self.field_map.range(lit.1.clone()..).take_while(|(k, _)| (**k).starts_with(&*lit.1.clone())))
+                        .enumerate()
+                        .take_while(|(i, _)| in_time(*i))
+                        .for_each(|(_, field)| {
                             count += 1;
                             if re.is_match(field.0) {
                                 result |= field.1;
@@ -153,16 +546,49 @@ impl<'a> Field {
                     matched += 1;
                     // If it's an exact match do a simple get
                     if let Some(list) = self.field_map.get(&*lit.1) { result |= list }
+                    if let Some(list) = self.sealed.as_ref().and_then(|sealed| sealed.get(&lit.1)) { result |= list }
                 }
             });
+        } else if let Some((trigrams, required)) = self.required_trigrams(&field_query.val, flags) {
+            debug!("Running query on the values having the trigrams {:?}", required);
+            trigrams
+                .candidates(&required)
+                .iter()
+                .map(|id| trigrams.value(id))
+                .enumerate()
+                .take_while(|(i, _)| in_time(*i))
+                .for_each(|(_, value)| {
+                    count += 1;
+                    if re.is_match(value) {
+                        result |= self.get(value).unwrap();
+                        matched += 1;
+                    }
+                });
         } else {
-            (&self.field_map).iter().for_each(|b| {
+            self.field_map.iter().enumerate().take_while(|(i, _)| in_time(*i)).for_each(|(_, b)| {
                 count += 1;
                 if re.is_match(b.0) {
                     result |= b.1;
                     matched += 1;
                 }
             });
+            // Sealed values are walked along with the DFA of the regex, the values sharing a prefix
+            // it rejects being skipped at once. Only a regex too large for a DFA has each value tested.
+            if let Some(sealed) = &self.sealed {
+                match sealed.matching(&field_query.val, in_time) {
+                    Some(matching) => matching.into_iter().for_each(|i| {
+                        matched += 1;
+                        result |= sealed.postings(i);
+                    }),
+                    None => sealed.iter().enumerate().take_while(|(i, _)| in_time(*i)).for_each(|(_, b)| {
+                        count += 1;
+                        if re.is_match(b.0) {
+                            result |= b.1;
+                            matched += 1;
+                        }
+                    }),
+                }
+            }
         }
 
         debug!(
@@ -175,8 +601,97 @@ impl<'a> Field {
         result
     }
 
+    /// The folded values of the key along with the folded prefixes of a case insensitive regex, if
+    /// both exist. Prefixes are taken from the regex without its flag, its case variants would be
+    /// too many to be kept whole.
+    fn folded_prefixes(&self, regex: &str, flags: &query::SearchFlags) -> Option<(&FoldedValues, Vec<String>)> {
+        let folded = self.folded.as_ref()?;
+        let regex = regex.strip_prefix("(?i)")?;
+        if !flags.contains(query::SearchFlags::OPTIMIZE_REGEX_SEARCH) || Parser::new().parse(regex).is_err() {
+            return None;
+        }
+        let mut folded_prefixes: Vec<String> = optimize_regex(regex).iter().map(|(_, prefix)| fold(prefix)).collect();
+        // Values starting with a prefix also start with the shorter ones it starts with
+        folded_prefixes.sort_unstable();
+        folded_prefixes.dedup_by(|prefix, shorter| prefix.starts_with(shorter.as_str()));
+        match folded_prefixes.is_empty() || folded_prefixes.iter().any(String::is_empty) {
+            true => None,
+            false => Some((folded, folded_prefixes)),
+        }
+    }
+
+    /// The trigram index of the key along with the trigrams required by the regex, if both exist
+    fn required_trigrams(&self, regex: &str, flags: &query::SearchFlags) -> Option<(&TrigramIndex, TrigramQuery)> {
+        if !flags.contains(query::SearchFlags::OPTIMIZE_REGEX_SEARCH) {
+            return None;
+        }
+        let trigrams = self.trigrams.as_ref()?;
+        let hir = Parser::new().parse(regex).ok()?;
+        TrigramQuery::from_hir(&hir).map(|required| (trigrams, required))
+    }
+
+    fn get(&self, value: &str) -> Option<&RoaringBitmap> {
+        let sealed = self.sealed.as_ref().and_then(|sealed| sealed.get(value));
+        sealed.or_else(|| self.field_map.get(value))
+    }
+
+    /// Uses the text index of the key if any, otherwise splits each value into words until the
+    /// deadline is reached
+    fn text_get(&self, text_query: &TextQuery, tokenizer: &Tokenizer, deadline: Option<Instant>) -> RoaringBitmap {
+        if let Some(text) = &self.text {
+            return text.search(text_query, tokenizer);
+        }
+        let mut result = RoaringBitmap::new();
+        if text_query.is_empty() {
+            return result;
+        }
+        self.iter()
+            .enumerate()
+            .take_while(|(i, _)| !i.is_multiple_of(DEADLINE_CHECK_INTERVAL) || !query::is_expired(deadline))
+            .filter(|(_, (value, _))| text_query.is_match(&tokenizer.tokens(value)))
+            .for_each(|(_, (_, posting_list))| result |= posting_list);
+        result
+    }
+
+    /// Uses the folded values of the key if any, otherwise folds each value until the deadline is
+    /// reached
+    fn folded_get(&self, folded_value: &str, deadline: Option<Instant>) -> RoaringBitmap {
+        let mut result = RoaringBitmap::new();
+        if let Some(folded) = &self.folded {
+            folded.get(folded_value).iter().filter_map(|value| self.get(value)).for_each(|posting_list| result |= posting_list);
+            return result;
+        }
+        self.iter()
+            .enumerate()
+            .take_while(|(i, _)| !i.is_multiple_of(DEADLINE_CHECK_INTERVAL) || !query::is_expired(deadline))
+            .filter(|(_, (value, _))| fold(value) == folded_value)
+            .for_each(|(_, (_, posting_list))| result |= posting_list);
+        result
+    }
+
+    /// Walks the sealed and the fresh values with a Levenshtein automaton, skipping the values
+    /// sharing a prefix it rejects, until the deadline is reached
+    fn fuzzy_get(&self, value: &str, distance: u8, deadline: Option<Instant>) -> RoaringBitmap {
+        let dfa = fuzzy::automaton(value, distance);
+        let in_time = |i: usize| !i.is_multiple_of(DEADLINE_CHECK_INTERVAL) || !query::is_expired(deadline);
+        let mut result = RoaringBitmap::new();
+        if let Some(sealed) = &self.sealed {
+            sealed.fuzzy(&dfa, in_time).into_iter().for_each(|i| result |= sealed.postings(i));
+        }
+        fuzzy::matching(&self.field_map, &dfa, in_time).into_iter().for_each(|(_, posting_list)| result |= posting_list);
+        result
+    }
+
+    /// Records carrying the key with a value other than the empty one
+    fn not_empty_get(&self) -> RoaringBitmap {
+        match self.get("") {
+            Some(empty) => &self.records - empty,
+            None => self.records.clone(),
+        }
+    }
+
     fn eq_get(&self, field_query: &query::Field) -> RoaringBitmap {
-        match self.field_map.get(&*field_query.val) {
+        match self.get(&field_query.val) {
             Some(list) => list.clone(),
             None => RoaringBitmap::new(),
         }
@@ -242,7 +757,7 @@ mod tests {
 
     #[test]
     fn it_works() {
-        let mut index = Index::new();
+        let mut index = Index::new_with_config(IndexConfig::default());
         load_test_data(&mut index);
 
         let mut result = index.search(&query::Search::new(vec![query::Field::new_eq("keya", "val1")]));
@@ -253,7 +768,7 @@ mod tests {
 
     #[test]
     fn it_intersects() {
-        let mut index = Index::new();
+        let mut index = Index::new_with_config(IndexConfig::default());
         load_test_data(&mut index);
 
         let mut result = index.search(&query::Search::new(vec![
@@ -273,6 +788,31 @@ mod tests {
         assert_eq!(result, vec![0]);
     }
 
+    #[test]
+    fn it_stops_at_deadline() {
+        let mut index = Index::new_with_config(IndexConfig::default());
+        load_test_data(&mut index);
+
+        let mut search = query::Search::new(vec![query::Field::new_re("keyc", "val.*")]);
+        assert_eq!(index.search(&search), vec![0, 1, 2]);
+        search.deadline = Some(Instant::now());
+        assert_eq!(index.search(&search), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn it_anchors_alternations() {
+        let mut index = Index::new_with_config(IndexConfig::default());
+        load_test_data(&mut index);
+
+        // Every branch must match the whole value, as in (?:val1|val2), not ^val1 or val2$
+        for flags in [query::SearchFlags::DEFAULT, query::SearchFlags::empty()] {
+            let search = |regex| index.search(&query::Search::new_with_flags(vec![query::Field::new_re("keyc", regex)], flags));
+            assert_eq!(search("val1|val2"), vec![1, 2]);
+            assert_eq!(search("val|3"), Vec::<u32>::new());
+            assert_eq!(search("v.*1|val3"), vec![0, 2]);
+        }
+    }
+
     #[test]
     fn it_optimizes_regex() {
         // TODO make that a real test
@@ -285,4 +825,170 @@ mod tests {
         optimize_regex(".*");
         optimize_regex("(tolkien+|tolkien)");
     }
+
+    #[test]
+    fn it_reports_index_statistics() {
+        let mut index = Index::new_with_config(IndexConfig::default());
+        let records = [("tolkien", "en"), ("tolkien", "fr"), ("herbert", "en"), ("asimov", "en")];
+        for (id, (author, language)) in records.iter().enumerate() {
+            index.insert_record(
+                id as u32,
+                &record::RCRecord::new(vec![record::RCLabelPair::new("author", author), record::RCLabelPair::new("language", language)]),
+            );
+        }
+        let status = index.get_status();
+        assert_eq!((status.keys, status.values, status.postings), (2, 5, 8));
+        let largest = status.largest_posting_list.unwrap();
+        assert_eq!((&*largest.key, &*largest.value, largest.postings), ("language", "en", 3));
+        assert_eq!(status.top_keys.iter().map(|key| &*key.key).collect::<Vec<&str>>(), vec!["author", "language"]);
+        assert!(status.bitmap_bytes > 0 && status.tree_bytes > 0);
+        assert!(status.top_keys[0].top_values.is_empty());
+
+        let author = index.get_key_status("author", 2).unwrap();
+        assert_eq!(author.values, 3);
+        assert_eq!(author.top_values[0], (Arc::from("tolkien"), 2));
+        assert_eq!(author.top_values.len(), 2);
+        assert!(index.get_key_status("title", 2).is_none());
+    }
+
+    #[test]
+    fn it_seals_values() {
+        let mut index = Index::new_with_config(IndexConfig::default());
+        let record = |title: String, language: &str| {
+            record::RCRecord::new(vec![record::RCLabelPair::new("title", &title), record::RCLabelPair::new("language", language)])
+        };
+        for id in 0..SEAL_MIN_VALUES as u32 {
+            index.insert_record(id, &record(format!("Book {}", id), "en"));
+        }
+        let title = index.get_key_status("title", 0).unwrap();
+        assert_eq!((title.values, title.sealed_values), (SEAL_MIN_VALUES, SEAL_MIN_VALUES));
+        // New postings of sealed values, and new values in the fresh tree
+        let id = SEAL_MIN_VALUES as u32;
+        index.insert_record(id, &record(String::from("Book 42"), "fr"));
+        index.insert_record(id + 1, &record(String::from("Book 4242"), "fr"));
+        index.insert_record(id + 2, &record(String::from("The Hobbit 42"), "fr"));
+
+        let search = |field| index.search(&query::Search::new(vec![field]));
+        assert_eq!(search(query::Field::new_eq("title", "Book 42")), vec![42, id]);
+        assert_eq!(search(query::Field::new_eq("title", "The Hobbit 42")), vec![id + 2]);
+        let sealed = |matches: fn(&str) -> bool| (0..SEAL_MIN_VALUES).filter(|id| matches(&id.to_string())).count();
+        assert_eq!(search(query::Field::new_re("title", ".*42")).len(), sealed(|id| id.ends_with("42")) + 3);
+        assert_eq!(search(query::Field::new_re("title", "Book 42|The Hobbit.*")), vec![42, id, id + 2]);
+        let ones = sealed(|id| id.len() > 1 && id.starts_with('1') && id.ends_with('1'));
+        assert_eq!(search(query::Field::new_re("title", "Book 1.*1")).len(), ones);
+        let unoptimized = query::Search::new_with_flags(vec![query::Field::new_re("title", "(?i)book 42(42)?")], query::SearchFlags::empty());
+        assert_eq!(index.search(&unoptimized), vec![42, 4242, id, id + 1]);
+        assert_eq!(search(query::Field::new_fuzzy("title", "Bookk 16383", 1)), vec![16383]);
+        assert_eq!(search(query::Field::new_fuzzy("title", "The Hobit 42", 1)), vec![id + 2]);
+        assert_eq!(search(query::Field::new_fuzzy("title", "Book 4", 1)).len(), 29);
+
+        let values = query::KeyValuesSearch::new_with_flags(vec![query::Field::new_eq("language", "fr")], "title", query::SearchFlags::empty());
+        match index.key_values_search(&values) {
+            KeyValuesSearchResult::Ok(values) => assert_eq!(values.len(), 3),
+            _ => panic!("expected values"),
+        }
+    }
+
+    #[test]
+    fn it_narrows_regexes_by_trigrams() {
+        let mut index = Index::new_with_config(IndexConfig {
+            trigram_keys: vec![String::from("title")],
+            ..IndexConfig::default()
+        });
+        let titles = ["The Silmarillion", "the silmarillion", "Silmaril", "The Hobbit", "Unfinished Tales of the Silmarillion"];
+        for (id, title) in titles.iter().enumerate() {
+            let record = record::RCRecord::new(vec![record::RCLabelPair::new("title", title), record::RCLabelPair::new("author", "Tolkien")]);
+            index.insert_record(id as u32, &record);
+        }
+        for regex in [".*[Ss]ilmarillion", ".*Silmaril.*", ".*(Hobbit|Tales).*", ".*o.*"] {
+            let optimized = index.search(&query::Search::new(vec![query::Field::new_re("title", regex)]));
+            let scanned = query::Search::new_with_flags(vec![query::Field::new_re("title", regex)], query::SearchFlags::empty());
+            assert_eq!(optimized, index.search(&scanned), "{}", regex);
+        }
+        assert_eq!(index.search(&query::Search::new(vec![query::Field::new_re("title", ".*[Ss]ilmarillion")])), vec![0, 1, 4]);
+
+        let status = index.get_status();
+        assert!(status.trigram_bytes > 0);
+        assert_eq!(index.get_key_status("author", 0).unwrap().trigram_bytes, None);
+    }
+
+    #[test]
+    fn it_searches_text() {
+        let mut index = Index::new_with_config(IndexConfig {
+            text_keys: vec![String::from("title")],
+            stopwords: vec![String::from("the")],
+            ..IndexConfig::default()
+        });
+        let titles = ["The Hobbit: or There and Back Again", "Back to the Hobbit", "The Fellowship of the Ring"];
+        for (id, title) in titles.iter().enumerate() {
+            let record = record::RCRecord::new(vec![record::RCLabelPair::new("title", title), record::RCLabelPair::new("copy", title)]);
+            index.insert_record(id as u32, &record);
+        }
+        // Keys without a text index are searched by splitting each of their values
+        for key in ["title", "copy"] {
+            let search = |text| index.search(&query::Search::new(vec![query::Field::new_text(key, text)]));
+            assert_eq!(search("hobbit"), vec![0, 1]);
+            assert_eq!(search(r#"\"back again\" HOBBIT"#), vec![0]);
+            assert_eq!(search("the"), Vec::<u32>::new());
+        }
+        assert!(index.get_status().text_bytes > 0);
+        assert_eq!(index.get_key_status("copy", 0).unwrap().text_bytes, None);
+
+        // Only the key having a text index is ranked, the others keep a score of 0
+        let search = query::Search::new(vec![query::Field::new_text("title", "hobbit")]);
+        let stats = index.text_stats(&search);
+        assert_eq!(stats.keys["title"].frequencies["hobbit"], 2);
+        let ranked = index.ranked_search(&search, &stats, 10);
+        assert_eq!(ranked.iter().map(|(id, _)| *id).collect::<Vec<u32>>(), vec![1, 0]);
+        assert_eq!(index.ranked_search(&search, &stats, 1).len(), 1);
+        let search = query::Search::new(vec![query::Field::new_text("copy", "hobbit")]);
+        assert!(index.text_stats(&search).keys.is_empty());
+        assert_eq!(index.ranked_search(&search, &query::TextStats::default(), 10), vec![(0, 0.0), (1, 0.0)]);
+    }
+
+    #[test]
+    fn it_finds_present_and_missing_keys() {
+        let mut index = Index::new_with_config(IndexConfig::default());
+        let publishers = [Some("Allen & Unwin"), Some(""), None, Some("Gallimard"), None];
+        for (id, publisher) in publishers.iter().enumerate() {
+            let mut label_pairs = vec![record::RCLabelPair::new("author", "Tolkien")];
+            label_pairs.extend(publisher.map(|publisher| record::RCLabelPair::new("publisher", publisher)));
+            index.insert_record(id as u32, &record::RCRecord::new(label_pairs));
+        }
+        let search = |fields| index.search(&query::Search::new(fields));
+        assert_eq!(search(vec![query::Field::new_exists("publisher")]), vec![0, 1, 3]);
+        assert_eq!(search(vec![query::Field::new_missing("publisher")]), vec![2, 4]);
+        assert_eq!(search(vec![query::Field::new_not_empty("publisher")]), vec![0, 3]);
+        assert_eq!(search(vec![query::Field::new_eq("publisher", "")]), vec![1]);
+        // No record has an edition
+        assert_eq!(search(vec![query::Field::new_missing("edition")]), vec![0, 1, 2, 3, 4]);
+        assert_eq!(search(vec![query::Field::new_missing("edition"), query::Field::new_exists("publisher")]), vec![0, 1, 3]);
+        assert!(search(vec![query::Field::new_exists("edition")]).is_empty());
+    }
+
+    #[test]
+    fn it_ignores_case_and_accents() {
+        let mut index = Index::new_with_config(IndexConfig {
+            folded_keys: vec![String::from("title")],
+            ..IndexConfig::default()
+        });
+        let titles = ["Anna Karénine", "ANNA KARENINE", "Anna Karenina", "Tolkien", "Kelvin", "Οδυσσευς"];
+        for (id, title) in titles.iter().enumerate() {
+            let record = record::RCRecord::new(vec![record::RCLabelPair::new("title", title), record::RCLabelPair::new("copy", title)]);
+            index.insert_record(id as u32, &record);
+        }
+        // Keys without folded values are searched by folding each of their values
+        for key in ["title", "copy"] {
+            let search = |field: query::Field| index.search(&query::Search::new(vec![field]));
+            assert_eq!(search(query::Field::new_folded_eq(key, "anna karenine")), vec![0, 1]);
+            assert_eq!(search(query::Field::new_folded_eq(key, "Anna Karénine")), vec![0, 1]);
+            assert_eq!(search(query::Field::new_folded_eq(key, "anna")), Vec::<u32>::new());
+            assert_eq!(search(query::Field::new_re(key, "(?i)anna karen.*")), vec![1, 2]);
+            assert_eq!(search(query::Field::new_re(key, "(?i)(tolkien|kelvin)")), vec![3, 4]);
+            assert_eq!(search(query::Field::new_re(key, "(?i)anna.*|.*vin")), vec![0, 1, 2, 4]);
+            assert_eq!(search(query::Field::new_re(key, "(?i)ΟΔΥΣΣΕΥΣ")), vec![5]);
+        }
+        assert!(index.get_status().folded_bytes > 0);
+        assert_eq!(index.get_key_status("copy", 0).unwrap().folded_bytes, None);
+    }
 }
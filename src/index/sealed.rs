@@ -0,0 +1,208 @@
+use fst::automaton::Str;
+use fst::{Automaton, IntoStreamer, Map, MapBuilder, Streamer};
+use regex_automata::hybrid::dfa::{Cache, DFA};
+use regex_automata::hybrid::LazyStateID;
+use regex_automata::util::start;
+use regex_automata::Anchored;
+use roaring::RoaringBitmap;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::Arc;
+
+/// Immutable dictionary of the values of a key, mapping each value to the index of its
+/// posting list. Posting lists can still grow, only new values go to the fresh tree.
+pub(super) struct SealedValues {
+    values: Map<Vec<u8>>,
+    // By index, the symbols are kept to be handed out without allocating
+    symbols: Vec<Arc<str>>,
+    postings: Vec<RoaringBitmap>,
+}
+
+impl SealedValues {
+    /// Merge the sealed values (if any) with the fresh ones, which must not be sealed already
+    pub(super) fn seal(sealed: Option<SealedValues>, fresh: BTreeMap<Arc<str>, RoaringBitmap>) -> SealedValues {
+        let sealed = sealed.map(|sealed| sealed.symbols.into_iter().zip(sealed.postings));
+        let mut merged: Vec<(Arc<str>, RoaringBitmap)> = sealed.into_iter().flatten().chain(fresh).collect();
+        merged.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut symbols = Vec::with_capacity(merged.len());
+        let mut postings = Vec::with_capacity(merged.len());
+
+        let mut builder = MapBuilder::memory();
+        for (i, (value, posting_list)) in merged.into_iter().enumerate() {
+            builder.insert(value.as_bytes(), i as u64).expect("sealed values are sorted and distinct");
+            symbols.push(value);
+            postings.push(posting_list);
+        }
+        SealedValues {
+            values: builder.into_map(),
+            symbols,
+            postings,
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub(super) fn get(&self, value: &str) -> Option<&RoaringBitmap> {
+        self.values.get(value).map(|i| &self.postings[i as usize])
+    }
+
+    pub(super) fn get_mut(&mut self, value: &str) -> Option<&mut RoaringBitmap> {
+        self.values.get(value).map(move |i| &mut self.postings[i as usize])
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = (&Arc<str>, &RoaringBitmap)> {
+        self.symbols.iter().zip(self.postings.iter())
+    }
+
+    /// Values starting with the prefix, in order
+    pub(super) fn prefixed<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a Arc<str>, &'a RoaringBitmap)> + 'a {
+        let mut stream = self.values.search(Str::new(prefix).starts_with()).into_stream();
+        std::iter::from_fn(move || stream.next().map(|(_, i)| i as usize)).map(move |i| (&self.symbols[i], &self.postings[i]))
+    }
+
+    /// Indexes of the values fully matching the regex, by walking the dictionary with its DFA,
+    /// until `in_time` is false for the number of bytes fed to the DFA. None when the regex can't
+    /// be compiled to a DFA, or when the DFA grew too large and had to start over.
+    pub(super) fn matching(&self, regex: &str, in_time: impl Fn(usize) -> bool) -> Option<Vec<usize>> {
+        let dfa = RegexAutomaton::new(regex)?;
+        let matched = self.walk(InTime::new(&dfa, in_time));
+        // States kept by the stream are invalidated when the DFA clears its cache
+        let cleared = dfa.cache.borrow().clear_count() > 0;
+        match cleared {
+            false => Some(matched),
+            true => None,
+        }
+    }
+
+    /// Indexes of the values accepted by the Levenshtein automaton, until `in_time` is false for
+    /// the number of bytes fed to it
+    pub(super) fn fuzzy(&self, dfa: &levenshtein_automata::DFA, in_time: impl Fn(usize) -> bool) -> Vec<usize> {
+        self.walk(InTime::new(dfa, in_time))
+    }
+
+    fn walk<A: Automaton>(&self, automaton: InTime<A, impl Fn(usize) -> bool>) -> Vec<usize> {
+        let mut stream = self.values.search(&automaton).into_stream();
+        let mut matched = Vec::new();
+        while let Some((_, i)) = stream.next() {
+            matched.push(i as usize);
+        }
+        matched
+    }
+
+    pub(super) fn postings(&self, i: usize) -> &RoaringBitmap {
+        &self.postings[i]
+    }
+
+    /// Estimated bytes of the transducer and of the slots of the values, their posting lists excluded
+    pub(super) fn bytes(&self) -> usize {
+        self.values.as_fst().size() + self.len() * (mem::size_of::<Arc<str>>() + mem::size_of::<RoaringBitmap>())
+    }
+}
+
+/// Stops an automaton once `in_time` is false for the number of bytes it was fed, a walk
+/// visiting many values but matching few of them still stopping at the deadline
+struct InTime<A, F> {
+    automaton: A,
+    in_time: F,
+    steps: Cell<usize>,
+    expired: Cell<bool>,
+}
+
+impl<A: Automaton, F: Fn(usize) -> bool> InTime<A, F> {
+    fn new(automaton: A, in_time: F) -> InTime<A, F> {
+        InTime {
+            automaton,
+            in_time,
+            steps: Cell::new(0),
+            expired: Cell::new(false),
+        }
+    }
+}
+
+impl<A: Automaton, F: Fn(usize) -> bool> Automaton for InTime<A, F> {
+    type State = A::State;
+
+    fn start(&self) -> Self::State {
+        self.automaton.start()
+    }
+
+    fn is_match(&self, state: &Self::State) -> bool {
+        !self.expired.get() && self.automaton.is_match(state)
+    }
+
+    fn can_match(&self, state: &Self::State) -> bool {
+        !self.expired.get() && self.automaton.can_match(state)
+    }
+
+    fn accept(&self, state: &Self::State, byte: u8) -> Self::State {
+        let steps = self.steps.get() + 1;
+        self.steps.set(steps);
+        if !(self.in_time)(steps) {
+            self.expired.set(true);
+        }
+        self.automaton.accept(state, byte)
+    }
+}
+
+/// An anchored lazy DFA walked along the transducer, pruning the values that can't match
+struct RegexAutomaton {
+    dfa: DFA,
+    cache: RefCell<Cache>,
+}
+
+impl RegexAutomaton {
+    fn new(regex: &str) -> Option<RegexAutomaton> {
+        let dfa = DFA::new(&format!("^(?:{})$", regex)).ok()?;
+        let cache = RefCell::new(dfa.create_cache());
+        Some(RegexAutomaton { dfa, cache })
+    }
+}
+
+impl Automaton for &RegexAutomaton {
+    // None once the DFA can't match anymore
+    type State = Option<LazyStateID>;
+
+    fn start(&self) -> Self::State {
+        let config = start::Config::new().anchored(Anchored::Yes);
+        self.dfa.start_state(&mut self.cache.borrow_mut(), &config).ok()
+    }
+
+    fn is_match(&self, state: &Self::State) -> bool {
+        // Matches are delayed by one byte, the end of the value is fed to find them
+        state.is_some_and(|state| self.dfa.next_eoi_state(&mut self.cache.borrow_mut(), state).is_ok_and(|eoi| eoi.is_match()))
+    }
+
+    fn can_match(&self, state: &Self::State) -> bool {
+        state.is_some()
+    }
+
+    fn accept(&self, state: &Self::State, byte: u8) -> Self::State {
+        let next = self.dfa.next_state(&mut self.cache.borrow_mut(), (*state)?, byte).ok()?;
+        match next.is_dead() || next.is_quit() {
+            true => None,
+            false => Some(next),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_walks_values_until_the_deadline() {
+        let fresh = (0..1000).map(|i| (Arc::from(format!("Book {}", i)), [i].iter().copied().collect::<RoaringBitmap>())).collect();
+        let sealed = SealedValues::seal(None, fresh);
+        assert_eq!(sealed.matching(".*99", |_| true).map(|matched| matched.len()), Some(10));
+        // Nothing matches, the walk still stops once out of time
+        let steps = Cell::new(0);
+        let matched = sealed.matching(".*silmarillion", |i| {
+            steps.set(i);
+            i < 50
+        });
+        assert_eq!((matched, steps.get()), (Some(Vec::new()), 50));
+    }
+}
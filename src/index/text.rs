@@ -0,0 +1,230 @@
+use crate::record::query::KeyTextStats;
+
+use hashbrown::{HashMap, HashSet};
+use roaring::RoaringBitmap;
+use std::mem;
+use std::sync::Arc;
+use unicode_normalization::UnicodeNormalization;
+
+/// Splits a text into its words, normalized (NFKC) and lowercased, leaving out the stopwords
+pub(super) struct Tokenizer {
+    stopwords: HashSet<String>,
+}
+
+impl Tokenizer {
+    pub(super) fn new(stopwords: &[String]) -> Tokenizer {
+        let mut tokenizer = Tokenizer { stopwords: HashSet::new() };
+        // Stopwords are normalized like the texts they are compared to
+        tokenizer.stopwords = stopwords.iter().flat_map(|stopword| tokenizer.tokens(stopword)).collect();
+        tokenizer
+    }
+
+    pub(super) fn tokens(&self, text: &str) -> Vec<String> {
+        let mut tokens = Vec::new();
+        self.each_token(text, |token| tokens.push(String::from(token)));
+        tokens
+    }
+
+    /// Same as tokens without allocating each of them
+    pub(super) fn each_token(&self, text: &str, each: impl FnMut(&str)) {
+        // ASCII texts are already normalized
+        let text: String = match text.is_ascii() {
+            true => text.to_ascii_lowercase(),
+            false => text.nfkc().flat_map(char::to_lowercase).collect(),
+        };
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|token| !token.is_empty() && !self.stopwords.contains(*token))
+            .for_each(each)
+    }
+}
+
+/// Phrases a text must all contain, a word being a phrase of its own. Phrases are written
+/// between double quotes: `hobbit "back again"`.
+#[derive(Debug, PartialEq)]
+pub(super) struct TextQuery {
+    phrases: Vec<Vec<String>>,
+}
+
+impl TextQuery {
+    pub(super) fn parse(text: &str, tokenizer: &Tokenizer) -> TextQuery {
+        let mut phrases = Vec::new();
+        for (i, part) in text.split('"').enumerate() {
+            let tokens = tokenizer.tokens(part);
+            match i % 2 {
+                0 => phrases.extend(tokens.into_iter().map(|token| vec![token])),
+                _ if !tokens.is_empty() => phrases.push(tokens),
+                _ => (),
+            }
+        }
+        TextQuery { phrases }
+    }
+
+    /// A query left without words (e.g. only stopwords) matches nothing
+    pub(super) fn is_empty(&self) -> bool {
+        self.phrases.is_empty()
+    }
+
+    pub(super) fn is_match(&self, tokens: &[String]) -> bool {
+        !self.is_empty() && self.phrases.iter().all(|phrase| tokens.windows(phrase.len()).any(|window| window == phrase.as_slice()))
+    }
+
+    fn has_phrases(&self) -> bool {
+        self.phrases.iter().any(|phrase| phrase.len() > 1)
+    }
+
+    /// Every word of the query, once
+    pub(super) fn words(&self) -> HashSet<&str> {
+        self.phrases.iter().flatten().map(String::as_str).collect()
+    }
+}
+
+/// Saturation of the frequency of a word in a value, and how much the length of the value matters
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+/// The records containing each word of the values of a key
+pub(super) struct TextIndex {
+    tokens: HashMap<Box<str>, RoaringBitmap>,
+    // Value of each record, to check the order of the words of phrases
+    values: HashMap<u32, Arc<str>>,
+    // Estimated bytes of the bitmaps and of the words, kept up to date on insert
+    bitmap_bytes: usize,
+    token_bytes: usize,
+    // Words of all the values, for their average length
+    words: u64,
+}
+
+impl TextIndex {
+    pub(super) fn new() -> TextIndex {
+        TextIndex {
+            tokens: HashMap::new(),
+            values: HashMap::new(),
+            bitmap_bytes: 0,
+            token_bytes: 0,
+            words: 0,
+        }
+    }
+
+    pub(super) fn insert(&mut self, id: u32, value: &Arc<str>, tokenizer: &Tokenizer) {
+        let tokens = tokenizer.tokens(value);
+        self.words += tokens.len() as u64;
+        for token in tokens {
+            if !self.tokens.contains_key(token.as_str()) {
+                self.token_bytes += token.len();
+            }
+            let ids = self.tokens.entry(Box::from(token)).or_default();
+            let before = ids.serialized_size();
+            if ids.insert(id) {
+                self.bitmap_bytes = self.bitmap_bytes + ids.serialized_size() - before;
+            }
+        }
+        self.values.insert(id, value.clone());
+    }
+
+    /// Records containing every word of the query, then checked for its phrases
+    pub(super) fn search(&self, query: &TextQuery, tokenizer: &Tokenizer) -> RoaringBitmap {
+        let mut postings = query.phrases.iter().flatten().map(|token| self.tokens.get(token.as_str()));
+        let first = match postings.next() {
+            Some(Some(first)) => first.clone(),
+            _ => return RoaringBitmap::new(),
+        };
+        let mut ids = first;
+        for posting_list in postings {
+            match posting_list {
+                Some(posting_list) => ids &= posting_list,
+                None => return RoaringBitmap::new(),
+            }
+        }
+        if query.has_phrases() {
+            ids = ids.iter().filter(|id| self.values.get(id).is_some_and(|value| query.is_match(&tokenizer.tokens(value)))).collect();
+        }
+        ids
+    }
+
+    /// Statistics of the words of the query in this index, to be summed with other shards
+    pub(super) fn stats(&self, query: &TextQuery) -> KeyTextStats {
+        KeyTextStats {
+            records: self.values.len() as u64,
+            words: self.words,
+            frequencies: query
+                .words()
+                .into_iter()
+                .map(|word| (String::from(word), self.tokens.get(word).map_or(0, |ids| ids.len())))
+                .collect(),
+        }
+    }
+
+    /// BM25 score of the value of a record, given statistics of all the shards
+    pub(super) fn score(&self, id: u32, query: &TextQuery, stats: &KeyTextStats, tokenizer: &Tokenizer) -> f64 {
+        let value = match self.values.get(&id) {
+            Some(value) => value,
+            None => return 0.0,
+        };
+        let words: Vec<&str> = query.words().into_iter().collect();
+        let mut counts = vec![0; words.len()];
+        let mut length = 0;
+        tokenizer.each_token(value, |token| {
+            length += 1;
+            if let Some(i) = words.iter().position(|word| *word == token) {
+                counts[i] += 1;
+            }
+        });
+        let records = stats.records as f64;
+        let average_length = stats.words as f64 / records.max(1.0);
+        let length_norm = 1.0 - BM25_B + BM25_B * length as f64 / average_length.max(1.0);
+        words
+            .iter()
+            .zip(counts)
+            .map(|(word, count)| {
+                let frequency = stats.frequencies.get(*word).copied().unwrap_or(0) as f64;
+                let idf = (1.0 + (records - frequency + 0.5) / (frequency + 0.5)).ln();
+                idf * count as f64 * (BM25_K1 + 1.0) / (count as f64 + BM25_K1 * length_norm)
+            })
+            .sum()
+    }
+
+    /// Estimated bytes of the bitmaps and of the tables, the values being shared with the records
+    pub(super) fn bytes(&self) -> usize {
+        // hashbrown keeps a control byte per bucket
+        self.bitmap_bytes
+            + self.token_bytes
+            + self.tokens.capacity() * (mem::size_of::<(Box<str>, RoaringBitmap)>() + 1)
+            + self.values.capacity() * (mem::size_of::<(u32, Arc<str>)>() + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_matches_words_and_phrases() {
+        let tokenizer = Tokenizer::new(&[String::from("The"), String::from("and")]);
+        assert_eq!(tokenizer.tokens("The Hobbit: or There and Back Again"), vec!["hobbit", "or", "there", "back", "again"]);
+        assert_eq!(tokenizer.tokens("ＨＯＢＢＩＴ ﬁre"), vec!["hobbit", "fire"]);
+
+        let mut index = TextIndex::new();
+        let values = ["The Hobbit: or There and Back Again", "Back to the hobbit", "The Fellowship of the Ring"];
+        for (id, value) in values.iter().enumerate() {
+            index.insert(id as u32, &Arc::from(*value), &tokenizer);
+        }
+        let search = |text: &str| index.search(&TextQuery::parse(text, &tokenizer), &tokenizer).iter().collect::<Vec<u32>>();
+        assert_eq!(search("hobbit"), vec![0, 1]);
+        assert_eq!(search("HOBBIT back"), vec![0, 1]);
+        assert_eq!(search(r#"hobbit \"back again\""#), vec![0]);
+        assert_eq!(search(r#""there and back""#), vec![0]);
+        assert_eq!(search(r#""back hobbit""#), Vec::<u32>::new());
+        assert_eq!(search("the"), Vec::<u32>::new());
+        assert_eq!(search("ring wizard"), Vec::<u32>::new());
+        assert!(index.bytes() > 0);
+
+        // Rarer words and shorter values score higher
+        let query = TextQuery::parse("hobbit ring", &tokenizer);
+        let stats = index.stats(&query);
+        assert_eq!((stats.records, stats.words, stats.frequencies["hobbit"], stats.frequencies["ring"]), (3, 11, 2, 1));
+        let score = |id| index.score(id, &query, &stats, &tokenizer);
+        assert!(score(2) > score(1) && score(1) > score(0) && score(0) > 0.0);
+        let query = TextQuery::parse("hobbit", &tokenizer);
+        assert!(index.score(1, &query, &index.stats(&query), &tokenizer) > index.score(0, &query, &index.stats(&query), &tokenizer));
+    }
+}
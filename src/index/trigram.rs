@@ -0,0 +1,175 @@
+use hashbrown::HashMap;
+use regex_syntax::hir::{Class, Hir, HirKind, Literal, RepetitionKind, RepetitionRange};
+use roaring::RoaringBitmap;
+use std::mem;
+use std::sync::Arc;
+
+type Trigram = [u8; 3];
+
+/// The values of a key numbered in insertion order, along with the ids of the values containing
+/// each trigram of their bytes
+pub(super) struct TrigramIndex {
+    values: Vec<Arc<str>>,
+    trigrams: HashMap<Trigram, RoaringBitmap>,
+    // Estimated bytes of the bitmaps, kept up to date on insert
+    bitmap_bytes: usize,
+}
+
+/// Trigrams a value must contain to match a regex
+#[derive(Debug, PartialEq)]
+pub(super) enum TrigramQuery {
+    All(Vec<TrigramQuery>),
+    Any(Vec<TrigramQuery>),
+    Trigram(Trigram),
+}
+
+impl TrigramIndex {
+    pub(super) fn new() -> TrigramIndex {
+        TrigramIndex {
+            values: Vec::new(),
+            trigrams: HashMap::new(),
+            bitmap_bytes: 0,
+        }
+    }
+
+    /// Index a value, which must not be indexed already
+    pub(super) fn insert(&mut self, value: &Arc<str>) {
+        let id = self.values.len() as u32;
+        self.values.push(value.clone());
+        for trigram in value.as_bytes().windows(3) {
+            let ids = self.trigrams.entry([trigram[0], trigram[1], trigram[2]]).or_default();
+            let before = ids.serialized_size();
+            // A trigram repeated in the value is only counted once
+            if ids.insert(id) {
+                self.bitmap_bytes = self.bitmap_bytes + ids.serialized_size() - before;
+            }
+        }
+    }
+
+    /// Ids of the values containing the trigrams required by the query
+    pub(super) fn candidates(&self, query: &TrigramQuery) -> RoaringBitmap {
+        match query {
+            TrigramQuery::Trigram(trigram) => self.trigrams.get(trigram).cloned().unwrap_or_default(),
+            TrigramQuery::Any(queries) => queries.iter().map(|query| self.candidates(query)).fold(RoaringBitmap::new(), |a, b| a | b),
+            TrigramQuery::All(queries) => {
+                let mut candidates = queries.iter().map(|query| self.candidates(query));
+                let first = candidates.next().unwrap_or_default();
+                candidates.fold(first, |a, b| a & b)
+            }
+        }
+    }
+
+    pub(super) fn value(&self, id: u32) -> &Arc<str> {
+        &self.values[id as usize]
+    }
+
+    /// Estimated bytes of the bitmaps and of the tables, the values being shared with the records
+    pub(super) fn bytes(&self) -> usize {
+        // hashbrown keeps a control byte per bucket
+        self.bitmap_bytes
+            + self.values.capacity() * mem::size_of::<Arc<str>>()
+            + self.trigrams.capacity() * (mem::size_of::<(Trigram, RoaringBitmap)>() + 1)
+    }
+}
+
+impl TrigramQuery {
+    /// Trigrams required by a parsed regex, None when any value could match it
+    pub(super) fn from_hir(hir: &Hir) -> Option<TrigramQuery> {
+        match hir.kind() {
+            HirKind::Literal(_) | HirKind::Class(_) => TrigramQuery::all(literal_bytes(hir).map(|bytes| of_literal(&bytes)).unwrap_or_default()),
+            HirKind::Concat(hirs) => {
+                // Consecutive literals form a run whose trigrams are all required
+                let mut parts = Vec::new();
+                let mut run = Vec::new();
+                for hir in hirs {
+                    match literal_bytes(hir) {
+                        Some(bytes) => run.extend(bytes),
+                        None => {
+                            parts.extend(of_literal(&run));
+                            run.clear();
+                            parts.extend(TrigramQuery::from_hir(hir));
+                        }
+                    }
+                }
+                parts.extend(of_literal(&run));
+                TrigramQuery::all(parts)
+            }
+            // A branch requiring nothing lets any value match
+            HirKind::Alternation(hirs) => hirs.iter().map(TrigramQuery::from_hir).collect::<Option<Vec<_>>>().map(TrigramQuery::Any),
+            HirKind::Repetition(repetition) => {
+                let at_least_once = match &repetition.kind {
+                    RepetitionKind::ZeroOrOne | RepetitionKind::ZeroOrMore => false,
+                    RepetitionKind::OneOrMore => true,
+                    RepetitionKind::Range(RepetitionRange::Exactly(n) | RepetitionRange::AtLeast(n) | RepetitionRange::Bounded(n, _)) => *n > 0,
+                };
+                match at_least_once {
+                    true => TrigramQuery::from_hir(&repetition.hir),
+                    false => None,
+                }
+            }
+            HirKind::Group(group) => TrigramQuery::from_hir(&group.hir),
+            HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => None,
+        }
+    }
+
+    fn all(mut parts: Vec<TrigramQuery>) -> Option<TrigramQuery> {
+        match parts.len() {
+            0 => None,
+            1 => parts.pop(),
+            _ => Some(TrigramQuery::All(parts)),
+        }
+    }
+}
+
+/// Bytes matched by a literal or by a class of a single character
+fn literal_bytes(hir: &Hir) -> Option<Vec<u8>> {
+    let char_bytes = |c: char| c.encode_utf8(&mut [0; 4]).as_bytes().to_vec();
+    match hir.kind() {
+        HirKind::Literal(Literal::Unicode(c)) => Some(char_bytes(*c)),
+        HirKind::Literal(Literal::Byte(b)) => Some(vec![*b]),
+        HirKind::Class(Class::Unicode(class)) => match class.ranges() {
+            [range] if range.start() == range.end() => Some(char_bytes(range.start())),
+            _ => None,
+        },
+        HirKind::Class(Class::Bytes(class)) => match class.ranges() {
+            [range] if range.start() == range.end() => Some(vec![range.start()]),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn of_literal(bytes: &[u8]) -> Vec<TrigramQuery> {
+    bytes.windows(3).map(|trigram| TrigramQuery::Trigram([trigram[0], trigram[1], trigram[2]])).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex_syntax::Parser;
+    use std::convert::TryInto;
+
+    fn query(regex: &str) -> Option<TrigramQuery> {
+        TrigramQuery::from_hir(&Parser::new().parse(regex).unwrap())
+    }
+
+    #[test]
+    fn it_requires_trigrams_of_regexes() {
+        let trigram = |s: &str| TrigramQuery::Trigram(s.as_bytes().try_into().unwrap());
+        assert_eq!(query(".*[Ss]ilmarillion.*"), query("ilmarillion"));
+        assert_eq!(query("ab.*cde"), Some(trigram("cde")));
+        assert_eq!(query("(abcd|xyz)+"), Some(TrigramQuery::Any(vec![TrigramQuery::All(vec![trigram("abc"), trigram("bcd")]), trigram("xyz")])));
+        assert_eq!(query("abc|.*"), None);
+        assert_eq!(query("(abc)?"), None);
+        assert_eq!(query("(?i)abc"), None);
+
+        let mut index = TrigramIndex::new();
+        for value in ["The Silmarillion", "the silmarillion", "Silmaril", "ab"] {
+            index.insert(&Arc::from(value));
+        }
+        assert_eq!(index.candidates(&query(".*[Ss]ilmarillion").unwrap()).iter().collect::<Vec<u32>>(), vec![0, 1]);
+        assert_eq!(index.candidates(&query("Silmaril|silmaril.*").unwrap()).iter().collect::<Vec<u32>>(), vec![0, 1, 2]);
+        assert_eq!(index.value(2).as_ref(), "Silmaril");
+        assert!(index.bytes() > 0);
+    }
+}
@@ -0,0 +1,201 @@
+use super::Encoding;
+use crate::record::{SmallLabelPair, SmallRecord};
+
+use ::csv::ByteRecord;
+use serde::{Deserialize, Serialize};
+use smallstr::SmallString;
+use smallvec::SmallVec;
+
+use std::fs;
+
+/// How the columns of a CSV/TSV file become labels
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CsvConfig {
+    /// The first row names the columns, and gives their default label key
+    pub headers: bool,
+    /// Columns to rename, drop or normalise, by header name or by index
+    pub columns: Vec<CsvColumn>,
+    /// Only keep the listed columns. Always the case without headers, other columns have no name.
+    pub drop_unlisted: bool,
+    /// Index empty values instead of skipping them
+    pub keep_empty: bool,
+    /// Applied to every column, before the normalisations of the column
+    pub normalise: Vec<Normalisation>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CsvColumn {
+    /// Header name, or index starting at 0
+    pub column: String,
+    /// Label key, the header name by default
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub drop: bool,
+    #[serde(default)]
+    pub normalise: Vec<Normalisation>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalisation {
+    Trim,
+    Lowercase,
+    Uppercase,
+    /// Replace runs of whitespaces by one space
+    Squash,
+}
+
+impl Default for CsvConfig {
+    fn default() -> Self {
+        CsvConfig {
+            headers: true,
+            columns: Vec::new(),
+            drop_unlisted: false,
+            keep_empty: false,
+            normalise: Vec::new(),
+        }
+    }
+}
+
+impl Normalisation {
+    fn apply(self, value: String) -> String {
+        match self {
+            Normalisation::Trim => value.trim().to_string(),
+            Normalisation::Lowercase => value.to_lowercase(),
+            Normalisation::Uppercase => value.to_uppercase(),
+            Normalisation::Squash => value.split_whitespace().collect::<Vec<&str>>().join(" "),
+        }
+    }
+}
+
+struct MappedColumn {
+    key: SmallString<[u8; 16]>,
+    normalise: Vec<Normalisation>,
+}
+
+/// Mapping of a CSV config resolved against the header of a file
+pub(crate) struct CsvMapping {
+    columns: Vec<Option<MappedColumn>>,
+    // Number of fields of every row, when known from the header
+    width: Option<usize>,
+    keep_empty: bool,
+    encoding: Encoding,
+}
+
+impl CsvConfig {
+    pub fn from_file(path: &str) -> Result<CsvConfig, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Error reading CSV config {}: {}", path, e))?;
+        serde_json::from_str(&content).map_err(|e| format!("Error parsing CSV config {}: {}", path, e))
+    }
+
+    pub(crate) fn mapping(&self, headers: Option<&ByteRecord>, encoding: Encoding) -> Result<CsvMapping, String> {
+        let names = match headers {
+            Some(headers) => headers.iter().map(|name| encoding.decode(name)).collect::<Result<Vec<String>, String>>()?,
+            None => Vec::new(),
+        };
+        let position = |column: &str| match names.iter().position(|name| name == column) {
+            Some(i) => Ok(i),
+            None => column
+                .parse::<usize>()
+                .map_err(|_| format!("Error column {} is neither in the CSV header nor an index", column)),
+        };
+        let mut columns: Vec<Option<MappedColumn>> = names
+            .iter()
+            .map(|name| match self.drop_unlisted {
+                true => None,
+                false => Some(MappedColumn {
+                    key: SmallString::from(name.as_str()),
+                    normalise: self.normalise.clone(),
+                }),
+            })
+            .collect();
+        for column in self.columns.iter() {
+            let i = position(&column.column)?;
+            if columns.len() <= i {
+                columns.resize_with(i + 1, || None);
+            }
+            columns[i] = match column.drop {
+                true => None,
+                false => {
+                    let key = match (&column.label, names.get(i)) {
+                        (Some(label), _) => label.as_str(),
+                        (None, Some(name)) => name.as_str(),
+                        (None, None) => return Err(format!("Error column {} needs a label, the CSV has no header", column.column)),
+                    };
+                    Some(MappedColumn {
+                        key: SmallString::from(key),
+                        normalise: self.normalise.iter().chain(column.normalise.iter()).copied().collect(),
+                    })
+                }
+            };
+        }
+        Ok(CsvMapping {
+            columns,
+            width: headers.map(|headers| headers.len()),
+            keep_empty: self.keep_empty,
+            encoding,
+        })
+    }
+}
+
+impl CsvMapping {
+    pub(crate) fn map(&self, row: &ByteRecord) -> Result<SmallRecord, String> {
+        if let Some(width) = self.width {
+            if row.len() != width {
+                return Err(format!("Error expected {} fields like the header, got {}", width, row.len()));
+            }
+        }
+        let mut label_pairs = SmallVec::new();
+        for (column, field) in self.columns.iter().zip(row.iter()) {
+            let column = match column {
+                Some(column) => column,
+                None => continue,
+            };
+            let val = column.normalise.iter().fold(self.encoding.decode(field)?, |val, normalisation| normalisation.apply(val));
+            if val.is_empty() && !self.keep_empty {
+                continue;
+            }
+            label_pairs.push(SmallLabelPair {
+                key: column.key.clone(),
+                val: SmallString::from(val.as_str()),
+            });
+        }
+        Ok(SmallRecord { label_pairs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(record: &SmallRecord) -> Vec<String> {
+        record.label_pairs.iter().map(|p| format!("{}={}", p.key, p.val)).collect()
+    }
+
+    #[test]
+    fn it_maps_columns_to_labels() {
+        let config: CsvConfig = serde_json::from_str(
+            r#"{"columns": [
+                {"column": "author", "normalise": ["lowercase"]},
+                {"column": "name", "label": "title", "normalise": ["trim", "squash"]},
+                {"column": "isbn", "drop": true}
+            ]}"#,
+        )
+        .unwrap();
+        let headers = ByteRecord::from(vec!["isbn", "name", "author", "publisher"]);
+        let mapping = config.mapping(Some(&headers), Encoding::Utf8).unwrap();
+        let record = mapping.map(&ByteRecord::from(vec!["123", " The   Hobbit ", "Tolkien", ""])).unwrap();
+        assert_eq!(pairs(&record), vec!["title=The Hobbit", "author=tolkien"]);
+        assert!(mapping.map(&ByteRecord::from(vec!["123", "The Hobbit"])).is_err());
+
+        let positional: CsvConfig = serde_json::from_str(r#"{"headers": false, "columns": [{"column": "1", "label": "title"}]}"#).unwrap();
+        let mapping = positional.mapping(None, Encoding::Latin1).unwrap();
+        let record = mapping.map(&ByteRecord::from(vec![&b"x"[..], &b"Les Mis\xe9rables"[..], &b"Hugo"[..]])).unwrap();
+        assert_eq!(pairs(&record), vec!["title=Les Misérables"]);
+
+        let missing: CsvConfig = serde_json::from_str(r#"{"columns": [{"column": "year"}]}"#).unwrap();
+        assert!(missing.mapping(Some(&headers), Encoding::Utf8).is_err());
+    }
+}
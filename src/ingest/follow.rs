@@ -0,0 +1,268 @@
+use super::{BulkLoader, BulkLoaderConfig, Parsers, RecordFormat};
+use crate::backend::multithread_backend::ShardedStorageBackend;
+
+use crossbeam_channel::{bounded, RecvTimeoutError};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Bytes read from the followed file at once
+const READ_BUFFER_BYTES: usize = 4 * 1024 * 1024;
+
+/// Position in a followed file, persisted so that a restart resumes where it stopped
+/// instead of loading every line again
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadOffset {
+    pub inode: u64,
+    /// Byte following the last loaded line
+    pub offset: u64,
+    /// Lines loaded so far
+    pub lines: usize,
+}
+
+impl ReadOffset {
+    fn load(path: &Path) -> ReadOffset {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring the read offset {}: {}", path.display(), e);
+                ReadOffset::default()
+            }),
+            Err(_) => ReadOffset::default(),
+        }
+    }
+
+    /// Written aside then renamed, so a crash never leaves a partial offset
+    fn save(&self, path: &Path) -> Result<(), String> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(self).unwrap())
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| format!("Error saving the read offset {}: {}", path.display(), e))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FollowConfig {
+    /// Where the read offset is persisted, nowhere by default
+    pub offset_file: Option<PathBuf>,
+    /// Delay between two checks for new lines
+    pub poll_interval: Duration,
+}
+
+impl Default for FollowConfig {
+    fn default() -> Self {
+        FollowConfig {
+            offset_file: None,
+            poll_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Load the lines appended to a file, or written to stdin, as they come. The lines are parsed
+/// by one pool of parsers for as long as they are followed, the backend is only locked while a
+/// batch of records is added so that resharding can go on.
+pub struct Follower {
+    backend: Arc<RwLock<ShardedStorageBackend>>,
+    loader: BulkLoaderConfig,
+    config: FollowConfig,
+}
+
+impl Follower {
+    pub fn new(backend: Arc<RwLock<ShardedStorageBackend>>, loader: BulkLoaderConfig, config: FollowConfig) -> Result<Follower, String> {
+        if matches!(loader.format, RecordFormat::Csv | RecordFormat::Tsv) {
+            return Err(String::from("Error CSV and TSV files cannot be followed, only line formats can"));
+        }
+        Ok(Follower { backend, loader, config })
+    }
+
+    fn load(&self, parsers: &Parsers, lines: &[u8], first_line: usize) -> Result<(), String> {
+        let summary = parsers.load_at(lines, first_line)?;
+        summary
+            .errors
+            .iter()
+            .for_each(|rejected| error!("{} (on line {})", rejected.error, rejected.line_number));
+        Ok(())
+    }
+
+    fn save(&self, position: &ReadOffset) -> Result<(), String> {
+        match &self.config.offset_file {
+            Some(path) => position.save(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Run `follow` with the parsers loading the followed lines
+    fn with_parsers(&self, follow: impl FnOnce(&Parsers) -> Result<(), String>) -> Result<(), String> {
+        BulkLoader::new_shared(&self.backend, self.loader.clone()).with_parsers(follow)?
+    }
+
+    /// Follow the file forever, from the persisted offset when it is still the same file.
+    /// A truncated file is read again from its start, a rotated file is drained before
+    /// switching to the new file at the same path.
+    pub fn follow_file(&self, path: &str) -> Result<(), String> {
+        self.with_parsers(|parsers| self.read_file(parsers, path))
+    }
+
+    fn read_file(&self, parsers: &Parsers, path: &str) -> Result<(), String> {
+        let open = || File::open(path).map_err(|e| format!("Error opening {}: {}", path, e));
+        let inode = |file: &File| file.metadata().map(|m| m.ino()).map_err(|e| format!("Error reading {}: {}", path, e));
+        let mut file = open()?;
+        let mut position = self.config.offset_file.as_deref().map(ReadOffset::load).unwrap_or_default();
+        let current = inode(&file)?;
+        let length = file.metadata().map(|m| m.len()).unwrap_or_default();
+        if position.inode == current && position.offset <= length {
+            info!("Resuming {} at byte {} (line {})", path, position.offset, position.lines + 1);
+        } else {
+            position = ReadOffset {
+                inode: current,
+                ..ReadOffset::default()
+            };
+        }
+        file.seek(SeekFrom::Start(position.offset)).map_err(|e| format!("Error seeking in {}: {}", path, e))?;
+
+        // Bytes of the last line, not terminated yet
+        let mut pending = Vec::new();
+        let mut buffer = vec![0; READ_BUFFER_BYTES];
+        loop {
+            let read = file.read(&mut buffer).map_err(|e| format!("Error reading {}: {}", path, e))?;
+            if read > 0 {
+                pending.extend_from_slice(&buffer[..read]);
+                if let Some(end) = pending.iter().rposition(|&b| b == b'\n') {
+                    let lines: Vec<u8> = pending.drain(..=end).collect();
+                    self.load(parsers, &lines, position.lines + 1)?;
+                    position.offset += lines.len() as u64;
+                    position.lines += lines.iter().filter(|&&b| b == b'\n').count();
+                    self.save(&position)?;
+                }
+                continue;
+            }
+
+            let length = file.metadata().map(|m| m.len()).unwrap_or_default();
+            let rotated = fs::metadata(path).map(|m| m.ino() != position.inode).unwrap_or(false);
+            if length < position.offset + pending.len() as u64 {
+                warn!("{} was truncated, reading it again from the start", path);
+                file.seek(SeekFrom::Start(0)).map_err(|e| format!("Error seeking in {}: {}", path, e))?;
+                position.offset = 0;
+                position.lines = 0;
+            } else if rotated {
+                if !pending.is_empty() {
+                    warn!("Ignoring the unterminated last line of the rotated {}", path);
+                }
+                info!("{} was rotated, following the new file", path);
+                file = open()?;
+                position = ReadOffset {
+                    inode: inode(&file)?,
+                    ..ReadOffset::default()
+                };
+            } else {
+                thread::sleep(self.config.poll_interval);
+                continue;
+            }
+            pending.clear();
+            self.save(&position)?;
+        }
+    }
+
+    /// Load the lines of stdin until it is closed. Lines are loaded once a batch is full
+    /// or when no line came during a poll interval, so that a slow producer is not delayed.
+    pub fn follow_stdin(&self) -> Result<(), String> {
+        self.with_parsers(|parsers| self.read_stdin(parsers))
+    }
+
+    fn read_stdin(&self, parsers: &Parsers) -> Result<(), String> {
+        let (lines_s, lines_r) = bounded(self.loader.batch_size);
+        thread::Builder::new()
+            .name(String::from("stdin-reader"))
+            .spawn(move || {
+                for line in io::stdin().lock().split(b'\n') {
+                    if lines_s.send(line).is_err() {
+                        break;
+                    }
+                }
+            })
+            .unwrap();
+
+        let mut batch = Vec::new();
+        let (mut batch_lines, mut loaded_lines) = (0, 0);
+        loop {
+            let closed = match lines_r.recv_timeout(self.config.poll_interval) {
+                Ok(line) => {
+                    batch.extend(line.map_err(|e| format!("Error reading stdin: {}", e))?);
+                    batch.push(b'\n');
+                    batch_lines += 1;
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+            let full = batch_lines >= self.loader.batch_size;
+            if !batch.is_empty() && (full || closed || lines_r.is_empty()) {
+                self.load(parsers, &batch, loaded_lines + 1)?;
+                loaded_lines += batch_lines;
+                batch.clear();
+                batch_lines = 0;
+            }
+            if closed {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::query;
+    use std::io::Write;
+
+    #[test]
+    fn it_follows_appended_and_rotated_files() {
+        let dir = std::env::temp_dir().join(format!("rusted_post_follow_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("records.txt");
+        let offset_file = dir.join("records.offset");
+        fs::write(&path, "{author=\"tolkien\", title=\"The Hobbit\"}\n{author=\"herbert\", ").unwrap();
+
+        let backend = Arc::new(RwLock::new(ShardedStorageBackend::new_with_cpus(1)));
+        let config = FollowConfig {
+            offset_file: Some(offset_file.clone()),
+            poll_interval: Duration::from_millis(10),
+        };
+        let follower = Follower::new(backend.clone(), BulkLoaderConfig::default(), config).unwrap();
+        let followed = path.to_str().unwrap().to_string();
+        thread::spawn(move || follower.follow_file(&followed));
+
+        let count = |author: &str| {
+            let search = query::Search::new(vec![query::Field::new_eq("author", author)]);
+            backend.read().unwrap().search(search).len()
+        };
+        let wait_for = |author: &str| {
+            for _ in 0..500 {
+                if count(author) > 0 {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("{} was never loaded", author);
+        };
+        wait_for("tolkien");
+        assert_eq!(count("herbert"), 0);
+
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"title=\"Dune\"}\n").unwrap();
+        wait_for("herbert");
+        thread::sleep(Duration::from_millis(50));
+        let offset = ReadOffset::load(&offset_file);
+        assert_eq!((offset.offset, offset.lines), (fs::metadata(&path).unwrap().len(), 2));
+
+        fs::rename(&path, dir.join("records.txt.1")).unwrap();
+        fs::write(&path, "{author=\"asimov\", title=\"Foundation\"}\n").unwrap();
+        wait_for("asimov");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+use crate::record::{SmallLabelPair, SmallRecord};
+
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use smallstr::SmallString;
+use smallvec::{Array, SmallVec};
+
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Parse a JSON record, either in the native format (`{"label_pairs":[{"key":..,"val":..}]}`,
+/// `label_pair` is accepted too) or as a plain object of labels (`{"author":"x"}`).
+/// Numbers and booleans of plain objects are kept as text, null labels are skipped.
+pub fn parse_json_record(l: &str) -> Result<SmallRecord, String> {
+    serde_json::from_str::<JsonRecord>(l)
+        .map(|record| record.0)
+        .map_err(|e| format!("Error invalid JSON record: {}", e))
+}
+
+struct JsonRecord(SmallRecord);
+
+/// A key or a value, copied straight into a small string
+struct Text<A: Array<Item = u8>>(Option<SmallString<A>>);
+
+#[derive(Deserialize)]
+struct JsonLabelPair {
+    key: Text<[u8; 16]>,
+    val: Text<[u8; 32]>,
+}
+
+struct TextVisitor<A>(PhantomData<A>);
+
+impl<'de, A: Array<Item = u8>> Visitor<'de> for TextVisitor<A> {
+    type Value = Text<A>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string, a number or a boolean")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(Text(Some(SmallString::from(v))))
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(Text(Some(SmallString::from(if v { "true" } else { "false" }))))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(Text(Some(SmallString::from(v.to_string().as_str()))))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(Text(Some(SmallString::from(v.to_string().as_str()))))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(Text(Some(SmallString::from(v.to_string().as_str()))))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(Text(None))
+    }
+}
+
+impl<'de, A: Array<Item = u8>> Deserialize<'de> for Text<A> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(TextVisitor(PhantomData))
+    }
+}
+
+/// Label pairs of the native format, pushed to the record being built
+struct LabelPairsSeed<'a>(&'a mut SmallVec<[SmallLabelPair; 16]>);
+
+impl<'de, 'a> de::DeserializeSeed<'de> for LabelPairsSeed<'a> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for LabelPairsSeed<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of {\"key\":..,\"val\":..} objects")
+    }
+
+    fn visit_seq<S: SeqAccess<'de>>(self, mut seq: S) -> Result<(), S::Error> {
+        while let Some(pair) = seq.next_element::<JsonLabelPair>()? {
+            match (pair.key.0, pair.val.0) {
+                (Some(key), Some(val)) => self.0.push(SmallLabelPair { key, val }),
+                _ => return Err(de::Error::custom("null key or value in label_pairs")),
+            }
+        }
+        Ok(())
+    }
+}
+
+struct JsonRecordVisitor;
+
+impl<'de> Visitor<'de> for JsonRecordVisitor {
+    type Value = JsonRecord;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a JSON object")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<JsonRecord, M::Error> {
+        let mut label_pairs = SmallVec::new();
+        while let Some(Text(key)) = map.next_key::<Text<[u8; 16]>>()? {
+            let key = key.ok_or_else(|| de::Error::custom("null key"))?;
+            match key.as_str() {
+                "label_pairs" | "label_pair" => map.next_value_seed(LabelPairsSeed(&mut label_pairs))?,
+                _ => {
+                    let val: Text<[u8; 32]> = map
+                        .next_value()
+                        .map_err(|e| de::Error::custom(format!("bad value for label {}: {}", key, e)))?;
+                    if let Some(val) = val.0 {
+                        label_pairs.push(SmallLabelPair { key, val });
+                    }
+                }
+            }
+        }
+        Ok(JsonRecord(SmallRecord { label_pairs }))
+    }
+}
+
+impl<'de> Deserialize<'de> for JsonRecord {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(JsonRecordVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(record: &SmallRecord) -> Vec<(String, String)> {
+        record.label_pairs.iter().map(|p| (p.key.to_string(), p.val.to_string())).collect()
+    }
+
+    #[test]
+    fn it_parses_json_records() {
+        let native = parse_json_record(r#"{"label_pair":[{"key":"title","val":"Dune \"1965\""},{"key":"author","val":"herbert"}]}"#).unwrap();
+        assert_eq!(
+            pairs(&native),
+            vec![(String::from("title"), String::from("Dune \"1965\"")), (String::from("author"), String::from("herbert"))]
+        );
+        let plain = parse_json_record(r#"{"author":"herbert", "year": 1965, "edition": null}"#).unwrap();
+        assert_eq!(pairs(&plain), vec![(String::from("author"), String::from("herbert")), (String::from("year"), String::from("1965"))]);
+
+        let error = parse_json_record(r#"{"author":["herbert"]}"#).err().unwrap();
+        assert!(error.contains("bad value for label author"), "{}", error);
+        assert!(parse_json_record(r#"["author"]"#).is_err());
+        assert!(parse_json_record(r#"{"author":"herbert""#).is_err());
+    }
+}
@@ -0,0 +1,574 @@
+use crate::backend::multithread_backend::{self, ShardedStorageBackend};
+use crate::lexer;
+use crate::record::SmallRecord;
+use crate::telemetry::BULK_LOADED_LINES;
+use self::csv::{CsvConfig, CsvMapping};
+
+use ::csv::{ByteRecord, ReaderBuilder};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub mod csv;
+pub mod follow;
+pub mod json;
+
+/// Number of rejected lines detailed in the summary, the error sink has all of them
+const MAX_REPORTED_ERRORS: usize = 100;
+
+const STOPPED_PARSERS: &str = "Error the parser threads stopped before the end of the input";
+
+/// Format of the lines to load
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// `{author="tolkien"}`
+    Text,
+    /// NDJSON, native records or plain objects
+    Json,
+    /// Comma separated values, mapped to labels by a `CsvConfig`
+    Csv,
+    /// Tab separated values, without quoting
+    Tsv,
+    /// Detected on each line
+    Auto,
+}
+
+impl FromStr for RecordFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(RecordFormat::Text),
+            "json" | "ndjson" => Ok(RecordFormat::Json),
+            "csv" => Ok(RecordFormat::Csv),
+            "tsv" => Ok(RecordFormat::Tsv),
+            "auto" => Ok(RecordFormat::Auto),
+            _ => Err(format!("Error unknown record format {} (expected text, json, csv, tsv or auto)", s)),
+        }
+    }
+}
+
+impl RecordFormat {
+    /// Parse a line of a line oriented format, CSV rows go through a `CsvMapping`
+    pub fn parse(self, line: &str) -> Result<SmallRecord, String> {
+        match self {
+            RecordFormat::Text => lexer::parse_record(line),
+            RecordFormat::Json => json::parse_json_record(line),
+            RecordFormat::Auto => RecordFormat::detect(line).parse(line),
+            RecordFormat::Csv | RecordFormat::Tsv => Err(String::from("Error CSV rows cannot be parsed alone, their header is needed")),
+        }
+    }
+
+    /// JSON objects start with a quoted key (or are empty), text records with a bare one
+    fn detect(line: &str) -> RecordFormat {
+        let mut chars = line.trim_start().chars();
+        match (chars.next(), chars.find(|c| !c.is_whitespace())) {
+            (Some('{'), Some('"')) | (Some('{'), Some('}')) => RecordFormat::Json,
+            _ => RecordFormat::Text,
+        }
+    }
+}
+
+/// Encoding of the data to load, records are stored as UTF-8
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    /// ISO-8859-1
+    Latin1,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(Encoding::Utf8),
+            "iso-8859-1" | "latin1" | "latin-1" => Ok(Encoding::Latin1),
+            _ => Err(format!("Error unknown encoding {} (expected utf-8 or iso-8859-1)", s)),
+        }
+    }
+}
+
+impl Encoding {
+    pub fn decode(self, bytes: &[u8]) -> Result<String, String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec()).map_err(|e| format!("Error invalid UTF-8: {}", e)),
+            // Latin-1 bytes are the first 256 unicode code points
+            Encoding::Latin1 => Ok(bytes.iter().map(|&b| b as char).collect()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BulkLoaderConfig {
+    pub format: RecordFormat,
+    pub encoding: Encoding,
+    /// Used by the CSV and TSV formats
+    pub csv: CsvConfig,
+    /// Number of threads parsing lines
+    pub parsers: usize,
+    /// Number of lines parsed together, their records are sent with one request per shard
+    pub batch_size: usize,
+    /// File the rejected lines are appended to, prefixed by their line number and the error
+    pub error_sink: Option<PathBuf>,
+    pub progress_interval: Duration,
+}
+
+impl Default for BulkLoaderConfig {
+    fn default() -> Self {
+        BulkLoaderConfig {
+            format: RecordFormat::Auto,
+            encoding: Encoding::Utf8,
+            csv: CsvConfig::default(),
+            parsers: multithread_backend::available_cpus() as usize,
+            batch_size: 10000,
+            error_sink: None,
+            progress_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RejectedLine {
+    /// Starts at 1
+    pub line_number: usize,
+    pub error: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BulkLoadSummary {
+    /// Lines read, blank lines included (rows for CSV, header excluded)
+    pub lines: usize,
+    pub parsed: usize,
+    /// Parsed records which were not already stored
+    pub added: usize,
+    pub duplicates: usize,
+    pub rejected: usize,
+    /// Parsed records which could not reach their shard
+    pub failed: usize,
+    /// First rejected lines
+    pub errors: Vec<RejectedLine>,
+    pub elapsed_ms: u128,
+}
+
+/// A line, or a CSV row, along with its line number
+enum Input {
+    Line(Vec<u8>),
+    Row(ByteRecord),
+}
+
+struct Rejection {
+    line_number: usize,
+    error: String,
+    line: String,
+}
+
+#[derive(Default)]
+struct Counters {
+    lines: AtomicUsize,
+    parsed: AtomicUsize,
+    rejected: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+/// Where the parsers of a load report to, sent along with each chunk of the load
+#[derive(Clone)]
+struct Batch {
+    rejected: Sender<Rejection>,
+    added: Sender<usize>,
+    counters: Arc<Counters>,
+    mapping: Option<Arc<CsvMapping>>,
+}
+
+/// Numbered lines to parse, the load they belong to is done once all of its chunks are dropped
+type Chunk = (Vec<(usize, Input)>, Batch);
+
+/// The backend the records are loaded into, locked for each batch of records when shared
+enum Target<'a> {
+    Backend(&'a ShardedStorageBackend),
+    Shared(&'a RwLock<ShardedStorageBackend>),
+}
+
+/// Load records, one per line (or CSV row). Lines are parsed by a pool of threads instead
+/// of the shards, rejected lines are reported instead of only being logged.
+pub struct BulkLoader<'a> {
+    backend: Target<'a>,
+    config: BulkLoaderConfig,
+}
+
+/// Pool of parser threads kept alive across several loads, see `BulkLoader::with_parsers`
+pub(crate) struct Parsers<'l, 'a> {
+    loader: &'l BulkLoader<'a>,
+    chunks: Sender<Chunk>,
+}
+
+impl<'a> BulkLoader<'a> {
+    pub fn new(backend: &'a ShardedStorageBackend, config: BulkLoaderConfig) -> BulkLoader<'a> {
+        BulkLoader {
+            backend: Target::Backend(backend),
+            config,
+        }
+    }
+
+    /// The backend is only locked while a batch of records is added, so that resharding can go on
+    pub(crate) fn new_shared(backend: &'a RwLock<ShardedStorageBackend>, config: BulkLoaderConfig) -> BulkLoader<'a> {
+        BulkLoader {
+            backend: Target::Shared(backend),
+            config,
+        }
+    }
+
+    fn with_backend<T>(&self, f: impl FnOnce(&ShardedStorageBackend) -> T) -> T {
+        match self.backend {
+            Target::Backend(backend) => f(backend),
+            Target::Shared(backend) => f(&backend.read().unwrap()),
+        }
+    }
+
+    pub fn load_file(&self, path: &str) -> Result<BulkLoadSummary, String> {
+        let file = File::open(path).map_err(|e| format!("Error opening {}: {}", path, e))?;
+        self.load(BufReader::new(file))
+    }
+
+    /// Load every line of the reader and wait until the records are applied by the shards
+    pub fn load(&self, reader: impl BufRead) -> Result<BulkLoadSummary, String> {
+        self.load_at(reader, 1)
+    }
+
+    /// Load lines which follow already loaded ones, numbering them from `first_line`
+    pub fn load_at(&self, reader: impl BufRead, first_line: usize) -> Result<BulkLoadSummary, String> {
+        self.with_parsers(|parsers| parsers.load_at(reader, first_line))?
+    }
+
+    /// Run `feed` with a pool of parser threads, which is kept until it returns
+    pub(crate) fn with_parsers<T>(&self, feed: impl FnOnce(&Parsers) -> T) -> Result<T, String> {
+        let (chunks_s, chunks_r) = bounded(self.config.parsers.max(1) * 2);
+        thread::scope(|scope| {
+            let parsers: Vec<_> = (0..self.config.parsers.max(1))
+                .map(|_| {
+                    let chunks_r = chunks_r.clone();
+                    scope.spawn(move || self.parse_chunks(chunks_r))
+                })
+                .collect();
+            drop(chunks_r);
+            let fed = feed(&Parsers {
+                loader: self,
+                chunks: chunks_s,
+            });
+            match parsers.into_iter().all(|parser| parser.join().is_ok()) {
+                true => Ok(fed),
+                false => Err(String::from("Error a parser thread panicked")),
+            }
+        })
+    }
+
+    /// Numbered lines of the reader, or its rows along with their mapping for CSV
+    #[allow(clippy::type_complexity)]
+    fn inputs<'r>(&self, reader: impl BufRead + 'r, first_line: usize) -> Result<(Box<dyn Iterator<Item = Result<(usize, Input), String>> + 'r>, Option<CsvMapping>), String> {
+        if !matches!(self.config.format, RecordFormat::Csv | RecordFormat::Tsv) {
+            let lines = reader.split(b'\n').enumerate().map(move |(i, line)| {
+                let mut line = line.map_err(|e| format!("Error reading line {}: {}", first_line + i, e))?;
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                Ok((first_line + i, Input::Line(line)))
+            });
+            return Ok((Box::new(lines), None));
+        }
+        let mut rows = ReaderBuilder::new()
+            .delimiter(self.delimiter())
+            .quoting(self.config.format == RecordFormat::Csv)
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(reader);
+        let mut headers = ByteRecord::new();
+        if self.config.csv.headers {
+            rows.read_byte_record(&mut headers).map_err(|e| format!("Error reading the CSV header: {}", e))?;
+        }
+        let mapping = self.config.csv.mapping(Some(&headers).filter(|_| self.config.csv.headers), self.config.encoding)?;
+        let rows = rows.into_byte_records().map(|row| {
+            let row = row.map_err(|e| format!("Error reading CSV: {}", e))?;
+            let line_number = row.position().map(|position| position.line() as usize).unwrap_or_default();
+            Ok((line_number, Input::Row(row)))
+        });
+        Ok((Box::new(rows), Some(mapping)))
+    }
+
+    fn delimiter(&self) -> u8 {
+        match self.config.format {
+            RecordFormat::Tsv => b'\t',
+            _ => b',',
+        }
+    }
+
+    /// Split the input in chunks of numbered lines for the parsers, reporting progress along the way
+    fn read_chunks(
+        &self,
+        inputs: impl Iterator<Item = Result<(usize, Input), String>>,
+        chunks: &Sender<Chunk>,
+        batch: Batch,
+        start: Instant,
+    ) -> Result<(), String> {
+        let counters = batch.counters.clone();
+        let mut chunk = Vec::with_capacity(self.config.batch_size);
+        let mut last_report = Instant::now();
+        for input in inputs {
+            chunk.push(input?);
+            counters.lines.fetch_add(1, Ordering::SeqCst);
+            if chunk.len() >= self.config.batch_size {
+                chunks.send((mem::take(&mut chunk), batch.clone())).map_err(|_| STOPPED_PARSERS)?;
+            }
+            if last_report.elapsed() >= self.config.progress_interval {
+                last_report = Instant::now();
+                let lines = counters.lines.load(Ordering::SeqCst);
+                info!(
+                    "Bulk load in progress: {} lines read, {} parsed, {} rejected ({} lines/s)",
+                    lines,
+                    counters.parsed.load(Ordering::SeqCst),
+                    counters.rejected.load(Ordering::SeqCst),
+                    (lines as f64 / start.elapsed().as_secs_f64()) as u64
+                );
+            }
+        }
+        if !chunk.is_empty() {
+            chunks.send((chunk, batch)).map_err(|_| STOPPED_PARSERS)?;
+        }
+        Ok(())
+    }
+
+    fn parse(&self, input: &Input, mapping: Option<&CsvMapping>) -> Result<SmallRecord, String> {
+        match (input, mapping) {
+            (Input::Line(line), _) => self.config.format.parse(&self.config.encoding.decode(line)?),
+            (Input::Row(row), Some(mapping)) => mapping.map(row),
+            (Input::Row(_), None) => Err(String::from("Error CSV row without a mapping")),
+        }
+    }
+
+    fn parse_chunks(&self, chunks: Receiver<Chunk>) {
+        for (chunk, batch) in chunks.iter() {
+            let (counters, mapping) = (&batch.counters, batch.mapping.as_deref());
+            let mut records = Vec::with_capacity(chunk.len());
+            let first_line = chunk.first().map(|(line_number, _)| *line_number).unwrap_or_default();
+            for (line_number, input) in chunk {
+                if let Input::Line(line) = &input {
+                    if line.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                }
+                match self.parse(&input, mapping) {
+                    Ok(record) => records.push(record),
+                    Err(error) => {
+                        counters.rejected.fetch_add(1, Ordering::SeqCst);
+                        BULK_LOADED_LINES.with_label_values(&["rejected"]).inc();
+                        let line = match input {
+                            Input::Line(line) => String::from_utf8_lossy(&line).into_owned(),
+                            Input::Row(row) => {
+                                let fields: Vec<String> = row.iter().map(|field| String::from_utf8_lossy(field).into_owned()).collect();
+                                fields.join(&(self.delimiter() as char).to_string())
+                            }
+                        };
+                        batch.rejected.send(Rejection { line_number, error, line }).ok();
+                    }
+                }
+            }
+            let count = records.len();
+            counters.parsed.fetch_add(count, Ordering::SeqCst);
+            BULK_LOADED_LINES.with_label_values(&["parsed"]).inc_by(count as u64);
+            if let Err((failed, e)) = self.with_backend(|backend| backend.bulk_add(records, &batch.added)) {
+                error!("Error adding {} of {} records starting at line {}: {}", failed, count, first_line, e);
+                counters.failed.fetch_add(failed, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl Parsers<'_, '_> {
+    /// Load the lines of the reader with the parsers of the pool, numbering them from `first_line`
+    pub(crate) fn load_at(&self, reader: impl BufRead, first_line: usize) -> Result<BulkLoadSummary, String> {
+        let loader = self.loader;
+        let start = Instant::now();
+        let (inputs, mapping) = loader.inputs(reader, first_line)?;
+        let sink = match &loader.config.error_sink {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path).map_err(|e| format!("Error creating error sink {}: {}", path.display(), e))?;
+                Some(BufWriter::new(file))
+            }
+            None => None,
+        };
+        let counters = Arc::new(Counters::default());
+        let (rejected_s, rejected_r) = unbounded();
+        let (added_s, added_r) = unbounded();
+        let batch = Batch {
+            rejected: rejected_s,
+            added: added_s,
+            counters: counters.clone(),
+            mapping: mapping.map(Arc::new),
+        };
+
+        // The sink is done once the parsers dropped every chunk of the batch
+        let (read, errors) = thread::scope(|scope| {
+            let sink = scope.spawn(move || write_rejections(rejected_r, sink));
+            let read = loader.read_chunks(inputs, &self.chunks, batch, start);
+            (read, sink.join().unwrap_or_else(|_| Err(String::from("Error the thread writing the rejected lines panicked"))))
+        });
+        let errors = errors?;
+        read?;
+        loader.with_backend(ShardedStorageBackend::wait_pending_operations);
+
+        let parsed = counters.parsed.load(Ordering::SeqCst);
+        let failed = counters.failed.load(Ordering::SeqCst);
+        let added = added_r.try_iter().sum();
+        let summary = BulkLoadSummary {
+            lines: counters.lines.load(Ordering::SeqCst),
+            parsed,
+            added,
+            duplicates: parsed.saturating_sub(failed + added),
+            rejected: counters.rejected.load(Ordering::SeqCst),
+            failed,
+            errors,
+            elapsed_ms: start.elapsed().as_millis(),
+        };
+        info!(
+            "Bulk load done in {}ms: {} lines, {} added, {} duplicates, {} rejected, {} failed",
+            summary.elapsed_ms, summary.lines, summary.added, summary.duplicates, summary.rejected, summary.failed
+        );
+        Ok(summary)
+    }
+}
+
+/// Write the rejected lines to the sink, returning the first ones for the summary
+fn write_rejections(rejections: Receiver<Rejection>, mut sink: Option<BufWriter<File>>) -> Result<Vec<RejectedLine>, String> {
+    let mut errors = Vec::new();
+    for rejection in rejections.iter() {
+        if let Some(sink) = sink.as_mut() {
+            writeln!(sink, "{}\t{}\t{}", rejection.line_number, rejection.error, rejection.line)
+                .map_err(|e| format!("Error writing to the error sink: {}", e))?;
+        }
+        if errors.len() < MAX_REPORTED_ERRORS {
+            errors.push(RejectedLine {
+                line_number: rejection.line_number,
+                error: rejection.error,
+            });
+        }
+    }
+    if let Some(mut sink) = sink {
+        sink.flush().map_err(|e| format!("Error writing to the error sink: {}", e))?;
+    }
+    errors.sort_by_key(|error| error.line_number);
+    Ok(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::query;
+    use std::fs;
+
+    #[test]
+    fn it_loads_in_bulk() {
+        let backend = ShardedStorageBackend::new_with_cpus(2);
+        let input = r#"{author="tolkien", title="The Silmarillion"}
+{author="tolkien", title="The Hobbit"}
+{author=tolkien}
+
+{author="tolkien", title="The Hobbit"}
+{author="herbert", title="Dune"
+{"label_pairs":[{"key":"author","val":"herbert"},{"key":"title","val":"Dune"}]}
+{"author":"asimov", "title":"Foundation"}"#;
+        let error_sink = std::env::temp_dir().join(format!("rusted_post_rejected_{}.txt", std::process::id()));
+        fs::remove_file(&error_sink).ok();
+        let loader = BulkLoader::new(
+            &backend,
+            BulkLoaderConfig {
+                parsers: 2,
+                batch_size: 2,
+                error_sink: Some(error_sink.clone()),
+                ..BulkLoaderConfig::default()
+            },
+        );
+        let summary = loader.load(input.as_bytes()).unwrap();
+        assert_eq!(summary.lines, 8);
+        assert_eq!(summary.parsed, 5);
+        assert_eq!(summary.added, 4);
+        assert_eq!(summary.duplicates, 1);
+        assert_eq!(summary.rejected, 2);
+        assert_eq!(summary.errors.iter().map(|e| e.line_number).collect::<Vec<usize>>(), vec![3, 6]);
+
+        let rejected = fs::read_to_string(&error_sink).unwrap();
+        fs::remove_file(&error_sink).unwrap();
+        assert_eq!(rejected.lines().count(), 2);
+        assert!(rejected.lines().any(|line| line.starts_with("3\t") && line.ends_with("{author=tolkien}")));
+
+        let search = query::Search::new(vec![query::Field::new_re("author", ".*")]);
+        assert_eq!(backend.search(search).len(), 4);
+    }
+
+    #[test]
+    fn it_loads_csv_rows() {
+        let backend = ShardedStorageBackend::new_with_cpus(1);
+        let input = b"title\tauthor\tpublisher\nLes Mis\xe9rables\tHugo\t\nThe Hobbit\n\"Dune\"\tHerbert\tChilton\n";
+        let loader = BulkLoader::new(
+            &backend,
+            BulkLoaderConfig {
+                format: RecordFormat::Tsv,
+                encoding: Encoding::Latin1,
+                ..BulkLoaderConfig::default()
+            },
+        );
+        let summary = loader.load(&input[..]).unwrap();
+        assert_eq!((summary.lines, summary.added, summary.rejected), (3, 2, 1));
+        assert_eq!(summary.errors[0].line_number, 3);
+
+        let search = query::Search::new(vec![query::Field::new_eq("title", "Les Misérables")]);
+        let found = backend.search(search);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].record.label_pairs.len(), 2);
+        assert_eq!(backend.search(query::Search::new(vec![query::Field::new_eq("title", "\"Dune\"")])).len(), 1);
+    }
+
+    #[test]
+    fn it_keeps_its_parsers_across_loads() {
+        let storage = Arc::new(RwLock::new(ShardedStorageBackend::new_with_cpus(2)));
+        let loader = BulkLoader::new_shared(&storage, BulkLoaderConfig::default());
+        let summaries = loader
+            .with_parsers(|parsers| {
+                let first = parsers.load_at(&b"{author=\"tolkien\", title=\"The Hobbit\"}\n{author=bad}\n"[..], 1).unwrap();
+                // The backend isn't locked between two loads
+                ShardedStorageBackend::reshard(&storage, 3).unwrap();
+                while storage.read().unwrap().is_resharding() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                let second = parsers.load_at(&b"{author=\"herbert\", title=\"Dune\"}\n"[..], 3).unwrap();
+                (first, second)
+            })
+            .unwrap();
+        assert_eq!((summaries.0.added, summaries.0.rejected, summaries.1.added), (1, 1, 1));
+        assert_eq!(summaries.0.errors[0].line_number, 2);
+
+        let backend = storage.read().unwrap();
+        assert_eq!(backend.shard_count(), 3);
+        assert_eq!(backend.search(query::Search::new(vec![query::Field::new_re("author", ".*")])).len(), 2);
+    }
+
+    #[test]
+    fn it_detects_record_formats() {
+        assert_eq!(RecordFormat::detect(r#" { "author":"tolkien"}"#), RecordFormat::Json);
+        assert_eq!(RecordFormat::detect(r#"{author="tolkien"}"#), RecordFormat::Text);
+        assert!(RecordFormat::Text.parse(r#"{"author":"tolkien"}"#).is_err());
+        assert!("xml".parse::<RecordFormat>().is_err());
+    }
+}
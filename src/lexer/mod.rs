@@ -1,28 +1,19 @@
 use crate::record;
 use crate::record::query;
 use logos::{Lexer, Logos};
+use regex::Regex;
 use smallstr::SmallString;
 use smallvec::SmallVec;
 use std::str;
 
 #[inline]
 fn next_non_space_char(chars: &[u8], start: usize) -> Option<usize> {
-    for i in start..chars.len() {
-        if chars[i] != b' ' {
-            Some(i);
-        }
-    }
-    None
+    chars.iter().skip(start).position(|c| *c != b' ').map(|i| i + start)
 }
 
 #[inline]
 fn find_next(chars: &[u8], start: usize, matcher: u8) -> Option<usize> {
-    for i in start..chars.len() {
-        if chars[i] == matcher {
-            return Some(i);
-        }
-    }
-    None
+    chars.iter().skip(start).position(|c| *c == matcher).map(|i| i + start)
 }
 
 #[inline]
@@ -92,6 +83,20 @@ enum Token {
     DoubleEqual,
     #[token("=~")]
     TildeEqual,
+    #[token("~=")]
+    EqualTilde,
+    #[token("==i")]
+    DoubleEqualI,
+    #[token("=%")]
+    EqualPercent,
+    #[token("~")]
+    Tilde,
+    #[token("=*")]
+    EqualStar,
+    #[token("!=*")]
+    BangEqualStar,
+    #[token("!=")]
+    BangEqual,
     #[token(",")]
     Comma,
 
@@ -176,22 +181,65 @@ fn parse_search_fields(lex: &mut Lexer<Token>) -> Result<Vec<query::Field>, Stri
         let op = match lex.next() {
             Some(Token::DoubleEqual) => query::Operation::Eq,
             Some(Token::TildeEqual) => query::Operation::Re,
+            Some(Token::EqualTilde) => query::Operation::Text,
+            Some(Token::DoubleEqualI) => query::Operation::FoldedEq,
+            Some(Token::EqualPercent) => query::Operation::Fuzzy(query::DEFAULT_FUZZY_DISTANCE),
+            Some(Token::EqualStar) => query::Operation::Exists,
+            Some(Token::BangEqualStar) => query::Operation::Missing,
+            Some(Token::BangEqual) => query::Operation::NotEmpty,
             _ => {
                 return Err(format!(
-                    "Error eq term: {} used instead of supported == (strict equal) or =~ (regex equal)",
+                    "Error eq term: {} used instead of supported == (strict equal), ==i (equal ignoring case and accents), =~ (regex equal), ~= (text match), =% (fuzzy match), =* (key exists), !=* (key missing) or !=\"\" (not empty)",
                     lex.slice()
                 ))
             }
         };
 
-        let val = match lex.next() {
-            Some(Token::ValueLiteral) => lex.slice().strip_prefix('"').unwrap().strip_suffix('"').unwrap(),
-            _ => {
-                return Err(format!(
-                    "Error wrong value format: {} used, did you forget to enclose it in double quotes \"\"?",
-                    lex.slice()
-                ))
+        // Presence of a key has no value
+        let val = match op {
+            query::Operation::Exists | query::Operation::Missing => "",
+            _ => match lex.next() {
+                Some(Token::ValueLiteral) => lex.slice().strip_prefix('"').unwrap().strip_suffix('"').unwrap(),
+                _ => {
+                    return Err(format!(
+                        "Error wrong value format: {} used, did you forget to enclose it in double quotes \"\"?",
+                        lex.slice()
+                    ))
+                }
+            },
+        };
+        if let (query::Operation::NotEmpty, false) = (&op, val.is_empty()) {
+            return Err(format!("Error only the empty value can be excluded with != instead of {}", val));
+        }
+        // An invalid regex would otherwise only fail once it reaches the shards
+        if let query::Operation::Re = op {
+            if let Err(e) = Regex::new(val) {
+                return Err(format!("Error invalid regex {}: {}", val, e));
+            }
+        }
+        let mut separator = lex.next();
+        // Fuzzy values may be followed by their distance: =%"Tolkein"~2
+        let op = match (op, &separator) {
+            (query::Operation::Fuzzy(_), Some(Token::Tilde)) => {
+                let distance = match lex.next() {
+                    Some(Token::Literal) => lex.slice().parse::<u8>().ok().filter(|d| *d <= query::MAX_FUZZY_DISTANCE),
+                    _ => None,
+                };
+                match distance {
+                    Some(distance) => {
+                        separator = lex.next();
+                        query::Operation::Fuzzy(distance)
+                    }
+                    None => {
+                        return Err(format!(
+                            "Error fuzzy distance: {} used instead of a distance up to {}",
+                            lex.slice(),
+                            query::MAX_FUZZY_DISTANCE
+                        ))
+                    }
+                }
             }
+            (op, _) => op,
         };
         let lp = query::Field {
             key: Box::from(key),
@@ -200,7 +248,7 @@ fn parse_search_fields(lex: &mut Lexer<Token>) -> Result<Vec<query::Field>, Stri
         };
         fields.push(lp);
 
-        match lex.next() {
+        match separator {
             Some(Token::Comma) => continue,
             Some(Token::ClosingBraces) => break,
             _ => {
@@ -217,10 +265,44 @@ fn parse_search_fields(lex: &mut Lexer<Token>) -> Result<Vec<query::Field>, Stri
 #[inline]
 fn parse_fn_search_fields(lex: &mut Lexer<Token>) -> Result<query::Query, String> {
     let search_fields = parse_search_fields(lex)?;
-    Ok(query::Query::Simple(query::Search {
-        search_fields,
-        query_flags: query::SearchFlags::DEFAULT,
-    }))
+    let mut search = query::Search::new(search_fields);
+    // Keys may be named keep, the projection is told apart by its place after the search
+    search.projection = match lex.next() {
+        None => None,
+        Some(Token::Literal) if matches!(lex.slice(), "keep" | "keep_distinct") => Some(parse_projection(lex)?),
+        _ => {
+            return Err(format!(
+                "Error unexpected {} after the search, only keep(<keys>) or keep_distinct(<keys>) may follow it",
+                lex.slice()
+            ))
+        }
+    };
+    Ok(query::Query::Simple(search))
+}
+
+#[inline]
+fn parse_projection(lex: &mut Lexer<Token>) -> Result<query::Projection, String> {
+    let distinct = lex.slice() == "keep_distinct";
+    match lex.next() {
+        Some(Token::OpeningParenthesis) => (),
+        _ => return Err(format!("Error bad function start: {} instead of (", lex.slice())),
+    };
+    let mut keys = Vec::new();
+    loop {
+        match lex.next() {
+            Some(Token::Literal) => keys.push(Box::from(lex.slice())),
+            _ => return Err(format!("Error bad key format: usage of token: {} used instead of litteral string", lex.slice())),
+        };
+        match lex.next() {
+            Some(Token::Comma) => continue,
+            Some(Token::ClosingParenthesis) => break,
+            _ => return Err(format!("Error bad separator in keys: usage of token: {} used instead of , or )", lex.slice())),
+        };
+    }
+    match lex.next() {
+        None => Ok(query::Projection { keys, distinct }),
+        Some(_) => Err(format!("Error unexpected {} after the end of the query", lex.slice())),
+    }
 }
 
 #[inline]
@@ -261,11 +343,7 @@ fn parse_fn_label_values(lex: &mut Lexer<Token>) -> Result<query::Query, String>
         Some(Token::ClosingParenthesis) => (),
         _ => return Err(format!("Error bad function end: {} instead of )", lex.slice())),
     };
-    return Ok(query::Query::KeyValues(query::KeyValuesSearch {
-        search_fields,
-        query_flags: query::SearchFlags::DEFAULT,
-        key_field: Box::from(key_field),
-    }));
+    Ok(query::Query::KeyValues(query::KeyValuesSearch::new(search_fields, key_field)))
 }
 
 #[inline]
@@ -311,6 +389,16 @@ mod tests {
         assert!(parse_record(escaped_quote_field).is_ok());
     }
 
+    #[test]
+    fn parse_small_record_works() {
+        assert_eq!(next_non_space_char(b"  {", 0), Some(2));
+        assert_eq!(next_non_space_char(b"{ ,", 1), Some(2));
+        assert_eq!(next_non_space_char(b"}  ", 1), None);
+        let record = parse_small_record(r#" { author="tolkien" , title="The Hobbit"}"#).unwrap();
+        let labels: Vec<(&str, &str)> = record.label_pairs.iter().map(|l| (l.key.as_str(), l.val.as_str())).collect();
+        assert_eq!(labels, vec![("author", "tolkien"), ("title", "The Hobbit")]);
+    }
+
     #[test]
     fn parse_query_works() {
         let mut field = parse_query(r#"{author_family_name=="Tolkien", language=~"English", extension=="epub"}"#);
@@ -322,4 +410,54 @@ mod tests {
             _ => panic!("Wrong query parsed"),
         };
     }
+
+    fn parsed_search(l: &str) -> query::Search {
+        match parse_query(l).unwrap() {
+            query::Query::Simple(x) => x,
+            _ => panic!("Wrong query parsed"),
+        }
+    }
+
+    #[test]
+    fn parse_query_rejects_invalid_regexes() {
+        assert!(parse_query(r#"{author_family_name=~"(Tolkien"}"#).is_err());
+    }
+
+    #[test]
+    fn parse_query_works_with_text() {
+        let search = parsed_search(r#"{title~="hobbit \"back again\""}"#);
+        assert_eq!(format!("{}", search), r#"{title~="hobbit \"back again\""}"#);
+    }
+
+    #[test]
+    fn parse_query_works_with_folded_values() {
+        let search = parsed_search(r#"{title==i"anna karenine", author=="Tolstoï"}"#);
+        assert_eq!(format!("{}", search), r#"{title==i"anna karenine", author=="Tolstoï"}"#);
+    }
+
+    #[test]
+    fn parse_query_works_with_fuzzy_values() {
+        let search = parsed_search(r#"{author=%"Tolkein"~2, title=%"Hobit"}"#);
+        assert_eq!(format!("{}", search), r#"{author=%"Tolkein"~2, title=%"Hobit"~1}"#);
+        assert!(parse_query(r#"{author=%"Tolkein"~3}"#).is_err());
+        assert!(parse_query(r#"{author=="Tolkien"~1}"#).is_err());
+    }
+
+    #[test]
+    fn parse_query_works_with_presence() {
+        let search = parsed_search(r#"{publisher!=*, edition=*, title!=""}"#);
+        assert_eq!(format!("{}", search), r#"{publisher!=*, edition=*, title!=""}"#);
+        assert!(parse_query(r#"{publisher=*"Allen"}"#).is_err());
+        assert!(parse_query(r#"{publisher!="Allen"}"#).is_err());
+    }
+
+    #[test]
+    fn parse_query_works_with_projection() {
+        let search = parsed_search(r#"{author=="Tolkien"} keep(title, language)"#);
+        assert_eq!(format!("{}", search), r#"{author=="Tolkien"} keep(title, language)"#);
+        let search = parsed_search(r#"{keep=="x"} keep_distinct(keep)"#);
+        assert_eq!(search.projection.map(|p| (p.keys, p.distinct)), Some((vec![Box::from("keep")], true)));
+        assert!(parse_query(r#"{author=="Tolkien"} keep()"#).is_err());
+        assert!(parse_query(r#"{author=="Tolkien"} title"#).is_err());
+    }
 }
@@ -1,6 +1,8 @@
 pub mod api;
 pub mod backend;
+pub mod export;
 mod index;
+pub mod ingest;
 pub mod lexer;
 pub mod record;
 mod store;
@@ -7,6 +7,7 @@ use std::cmp::Eq;
 use std::fmt;
 use std::hash::{BuildHasher, Hash, Hasher};
 use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 pub mod query;
@@ -33,26 +34,62 @@ impl LabelPair {
 }
 
 /////////////////////////// RC RECORDS ///////////////////////////
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RCRecord {
     pub label_pairs: Vec<RCLabelPair>,
-    hash_cache: u64
+    // Computed the first time it is needed, records rebuilt to be read are seldom hashed
+    #[serde(skip)]
+    hash_cache: AtomicU64,
 }
 
 impl RCRecord {
     pub fn new(pairs: Vec<RCLabelPair>) -> RCRecord {
-        let mut state = RandomState::new().build_hasher();
-        pairs.hash(&mut state);
         RCRecord {
             label_pairs: pairs,
-            hash_cache: state.finish(),
+            hash_cache: AtomicU64::new(0),
+        }
+    }
+
+    /// Hash of the labels, equal records having equal hashes
+    pub fn hash_value(&self) -> u64 {
+        // 0 until computed, a record hashing to 0 is hashed each time
+        let hash = self.hash_cache.load(Ordering::Relaxed);
+        if hash != 0 {
+            return hash;
         }
+        // Seeds must be fixed: equal records need equal hashes to be deduplicated
+        let hash = RandomState::with_seeds(0, 0, 0, 0).hash_one(&self.label_pairs);
+        self.hash_cache.store(hash, Ordering::Relaxed);
+        hash
+    }
+
+    /// The labels of the keys, in the order of the keys
+    pub fn project(&self, keys: &[Box<str>]) -> RCRecord {
+        let pairs = keys.iter().flat_map(|key| self.label_pairs.iter().filter(move |pair| *pair.key == **key)).cloned().collect();
+        RCRecord::new(pairs)
     }
 }
 
+impl Clone for RCRecord {
+    fn clone(&self) -> Self {
+        RCRecord {
+            label_pairs: self.label_pairs.clone(),
+            hash_cache: AtomicU64::new(self.hash_cache.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl PartialEq for RCRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.label_pairs == other.label_pairs
+    }
+}
+
+impl Eq for RCRecord {}
+
 impl Hash for RCRecord {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        state.write_u64(self.hash_cache);
+        state.write_u64(self.hash_value());
     }
 }
 
@@ -83,14 +120,55 @@ impl fmt::Display for RCLabelPair {
     }
 }
 
+/////////////////////////// IDENTIFIED RECORDS ///////////////////////////
+// A record along with the global id that can be used to fetch it back
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct IdentifiedRecord {
+    pub id: u64,
+    #[serde(flatten)]
+    pub record: Arc<RCRecord>,
+    /// Relevance of the record to the words searched as text, the higher the better
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// Values matched by the fuzzy fields of the search, when asked for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fuzzy_matches: Vec<FuzzyMatch>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FuzzyMatch {
+    pub key: Arc<str>,
+    pub value: Arc<str>,
+    pub distance: u8,
+}
+
 /////////////////////////// SMALL RECORDS ///////////////////////////
 // Small records are like regular records except they are made to stay
 // in stack
+#[derive(Clone)]
 pub struct SmallRecord {
     pub label_pairs: SmallVec<[SmallLabelPair; 16]>,
 }
 
+#[derive(Clone)]
 pub struct SmallLabelPair {
     pub key: SmallString<[u8; 16]>,
     pub val: SmallString<[u8; 32]>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    // The cached hash only depends on the labels, it can't change once a record is in a set
+    #[allow(clippy::mutable_key_type)]
+    fn it_dedupes_records_built_on_different_threads() {
+        let build = || RCRecord::new(vec![RCLabelPair::new("author", "tolkien"), RCLabelPair::new("title", "The Hobbit")]);
+        let built_aside = thread::spawn(build).join().unwrap();
+        let records: HashSet<RCRecord> = vec![build(), built_aside].into_iter().collect();
+        assert_eq!(records.len(), 1);
+    }
+}
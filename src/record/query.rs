@@ -2,8 +2,10 @@ use bitflags::bitflags;
 use itertools::free::join;
 use serde::{Deserialize, Serialize};
 use std::cmp::Eq;
+use std::collections::HashMap;
 use std::fmt;
 use std::str;
+use std::time::Instant;
 
 bitflags! {
     pub struct SearchFlags: u8 {
@@ -11,6 +13,8 @@ bitflags! {
         const OPTIMIZE_REGEX_SEARCH = 0b00000001;
         /// NOT YET IMPLEMENTED: Don't perform all intersections if the result have been reduced enough
         const ABORT_EARLY = 0b00000010;
+        /// Report the values matched by fuzzy fields along with each record
+        const REPORT_FUZZY_MATCHES = 0b00000100;
         const DEFAULT = Self::OPTIMIZE_REGEX_SEARCH.bits | Self::ABORT_EARLY.bits;
     }
 }
@@ -24,29 +28,70 @@ pub enum Query {
 pub struct Search {
     pub search_fields: Vec<Field>,
     pub query_flags: SearchFlags,
+    /// Past this instant shards stop searching and return what they found so far
+    pub deadline: Option<Instant>,
+    /// At most this many records are returned, the best scored ones for text searches
+    pub limit: Option<usize>,
+    /// Statistics of the words searched as text summed over the shards, set by the coordinator
+    /// so that shards score their records alike
+    pub text_stats: Option<TextStats>,
+    /// Labels kept in the records returned, applied by the coordinator once the records are gathered.
+    /// Distinct tuples are also deduped by the shards, the limit counting distinct tuples
+    pub projection: Option<Projection>,
 }
 
 impl Search {
     pub fn new(search_fields: Vec<Field>) -> Search {
-        Search {
-            search_fields,
-            query_flags: SearchFlags::DEFAULT,
-        }
+        Search::new_with_flags(search_fields, SearchFlags::DEFAULT)
     }
     pub fn new_with_flags(search_fields: Vec<Field>, flags: SearchFlags) -> Search {
         Search {
             search_fields,
             query_flags: flags,
+            deadline: None,
+            limit: None,
+            text_stats: None,
+            projection: None,
         }
     }
     pub fn is_match_all(&self) -> bool {
         self.search_fields.is_empty()
     }
+    /// Searches matching words are ranked by relevance
+    pub fn has_text(&self) -> bool {
+        self.search_fields.iter().any(|field| matches!(field.op, Operation::Text))
+    }
+    pub fn is_expired(&self) -> bool {
+        is_expired(self.deadline)
+    }
+}
+
+pub fn is_expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
 }
 
 impl fmt::Display for Search {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{{{}}}", join(self.search_fields.clone().into_iter().map(|f| format!("{}", f)), ", "))
+        write!(f, "{{{}}}", join(self.search_fields.clone().into_iter().map(|f| format!("{}", f)), ", "))?;
+        match &self.projection {
+            Some(projection) => write!(f, " {}", projection),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Keys of the labels returned: `keep(title, language)`, or `keep_distinct(title, language)` to
+/// only return the first record of each tuple of values
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Projection {
+    pub keys: Vec<Box<str>>,
+    pub distinct: bool,
+}
+
+impl fmt::Display for Projection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = if self.distinct { "keep_distinct" } else { "keep" };
+        write!(f, "{}({})", name, join(self.keys.iter(), ", "))
     }
 }
 
@@ -55,6 +100,7 @@ pub struct KeyValuesSearch {
     pub search_fields: Vec<Field>,
     pub key_field: Box<str>,
     pub query_flags: SearchFlags,
+    pub deadline: Option<Instant>,
 }
 
 impl KeyValuesSearch {
@@ -63,6 +109,7 @@ impl KeyValuesSearch {
             search_fields,
             key_field: Box::from(key),
             query_flags: SearchFlags::DEFAULT,
+            deadline: None,
         }
     }
 
@@ -71,19 +118,23 @@ impl KeyValuesSearch {
             search_fields,
             key_field: Box::from(key),
             query_flags: new_with_flags,
+            deadline: None,
         }
     }
 
     pub fn to_search_query(&self) -> Search {
-        Search {
-            search_fields: self.search_fields.clone(),
-            query_flags: SearchFlags::DEFAULT,
-        }
+        let mut search = Search::new(self.search_fields.clone());
+        search.deadline = self.deadline;
+        search
     }
 
     pub fn is_match_all(&self) -> bool {
         self.search_fields.is_empty()
     }
+
+    pub fn is_expired(&self) -> bool {
+        is_expired(self.deadline)
+    }
 }
 
 impl fmt::Display for KeyValuesSearch {
@@ -97,6 +148,35 @@ impl fmt::Display for KeyValuesSearch {
     }
 }
 
+/// Statistics of the words searched as text, per key
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TextStats {
+    pub keys: HashMap<Box<str>, KeyTextStats>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeyTextStats {
+    /// Records whose value of the key is split into words
+    pub records: u64,
+    /// Words of these values
+    pub words: u64,
+    /// Records containing each of the words searched
+    pub frequencies: HashMap<String, u64>,
+}
+
+impl TextStats {
+    pub fn merge(&mut self, other: TextStats) {
+        for (key, other) in other.keys {
+            let stats = self.keys.entry(key).or_default();
+            stats.records += other.records;
+            stats.words += other.words;
+            for (word, frequency) in other.frequencies {
+                *stats.frequencies.entry(word).or_default() += frequency;
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Field {
     pub key: Box<str>,
@@ -106,7 +186,11 @@ pub struct Field {
 
 impl fmt::Display for Field {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}{}\"{}\"", self.key, self.op, self.val)
+        match self.op {
+            Operation::Fuzzy(distance) => write!(f, "{}{}\"{}\"~{}", self.key, self.op, self.val, distance),
+            Operation::Exists | Operation::Missing => write!(f, "{}{}", self.key, self.op),
+            _ => write!(f, "{}{}\"{}\"", self.key, self.op, self.val),
+        }
     }
 }
 
@@ -126,19 +210,90 @@ impl Field {
             op: Operation::Re,
         }
     }
+
+    pub fn new_text(key: &str, val: &str) -> Field {
+        Field {
+            key: Box::from(key),
+            val: Box::from(val),
+            op: Operation::Text,
+        }
+    }
+
+    pub fn new_folded_eq(key: &str, val: &str) -> Field {
+        Field {
+            key: Box::from(key),
+            val: Box::from(val),
+            op: Operation::FoldedEq,
+        }
+    }
+
+    pub fn new_fuzzy(key: &str, val: &str, distance: u8) -> Field {
+        Field {
+            key: Box::from(key),
+            val: Box::from(val),
+            op: Operation::Fuzzy(distance),
+        }
+    }
+
+    pub fn new_exists(key: &str) -> Field {
+        Field {
+            key: Box::from(key),
+            val: Box::from(""),
+            op: Operation::Exists,
+        }
+    }
+
+    pub fn new_missing(key: &str) -> Field {
+        Field {
+            key: Box::from(key),
+            val: Box::from(""),
+            op: Operation::Missing,
+        }
+    }
+
+    pub fn new_not_empty(key: &str) -> Field {
+        Field {
+            key: Box::from(key),
+            val: Box::from(""),
+            op: Operation::NotEmpty,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Operation {
     Eq,
     Re,
+    /// Words and phrases of the value, see `index::text`
+    Text,
+    /// Equal ignoring case and diacritics
+    FoldedEq,
+    /// Within this Levenshtein distance of the value
+    Fuzzy(u8),
+    /// The record carries the key, whatever its value (even empty)
+    Exists,
+    /// The record doesn't carry the key
+    Missing,
+    /// The record carries the key with a value other than the empty one
+    NotEmpty,
 }
 
+/// Largest distance of fuzzy searches
+pub const MAX_FUZZY_DISTANCE: u8 = 2;
+/// Distance of fuzzy searches not giving one
+pub const DEFAULT_FUZZY_DISTANCE: u8 = 1;
+
 impl fmt::Display for Operation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Operation::Eq => write!(f, "=="),
             Operation::Re => write!(f, "=~"),
+            Operation::Text => write!(f, "~="),
+            Operation::FoldedEq => write!(f, "==i"),
+            Operation::Fuzzy(_) => write!(f, "=%"),
+            Operation::Exists => write!(f, "=*"),
+            Operation::Missing => write!(f, "!=*"),
+            Operation::NotEmpty => write!(f, "!="),
         }
     }
 }
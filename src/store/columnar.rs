@@ -0,0 +1,228 @@
+use hashbrown::hash_map::Entry;
+use hashbrown::HashMap;
+use roaring::RoaringBitmap;
+use std::mem;
+use std::sync::Arc;
+
+use super::record;
+use super::symbols::SymbolTable;
+
+const CHUNK_SIZE: usize = 1 << 16;
+
+/// Labels of up to 2^16 records: the labels of record `i` are at `offsets[i]..offsets[i + 1]`
+/// in the key and value columns
+struct ColumnarChunk {
+    offsets: Vec<u32>,
+    keys: Vec<u32>,
+    vals: Vec<u32>,
+}
+
+impl ColumnarChunk {
+    fn new() -> ColumnarChunk {
+        ColumnarChunk {
+            offsets: vec![0],
+            keys: Vec::new(),
+            vals: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    fn labels(&self, index: usize) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let range = self.offsets[index] as usize..self.offsets[index + 1] as usize;
+        self.keys[range.clone()].iter().copied().zip(self.vals[range].iter().copied())
+    }
+
+    fn bytes(&self) -> usize {
+        (self.offsets.capacity() + self.keys.capacity() + self.vals.capacity()) * mem::size_of::<u32>()
+    }
+}
+
+/// Records stored as columns of symbol ids, a record being rebuilt each time it is read.
+/// Ids are allocated like in the row layout: the chunk in the upper bits, the position in
+/// the chunk in the lower 16 bits.
+pub(super) struct ColumnarStore {
+    chunks: Vec<ColumnarChunk>,
+    // Symbol ids are given by the table shared by the shards, the store holding the ones it uses
+    symbols: Arc<SymbolTable>,
+    held: RoaringBitmap,
+    // Id of the first record having a given hash, the next ones are kept aside
+    hashes: HashMap<u64, u32>,
+    collisions: HashMap<u64, Vec<u32>>,
+}
+
+impl ColumnarStore {
+    pub(super) fn new(symbols: Arc<SymbolTable>) -> ColumnarStore {
+        ColumnarStore {
+            chunks: Vec::new(),
+            symbols,
+            held: RoaringBitmap::new(),
+            hashes: HashMap::new(),
+            collisions: HashMap::new(),
+        }
+    }
+
+    fn is_stored(&self, id: u32, labels: &[(u32, u32)]) -> bool {
+        let chunk = &self.chunks[(id >> 16) as usize];
+        let index = (id & 0xffff) as usize;
+        chunk.len() > index
+            && chunk.offsets[index + 1] - chunk.offsets[index] == labels.len() as u32
+            && chunk.labels(index).eq(labels.iter().copied())
+    }
+
+    fn find(&self, hash: u64, labels: &[(u32, u32)]) -> Option<u32> {
+        let first = self.hashes.get(&hash)?;
+        let mut candidates = std::iter::once(first).chain(self.collisions.get(&hash).into_iter().flatten());
+        candidates.find(|id| self.is_stored(**id, labels)).copied()
+    }
+
+    /// Store the record, returning its id or None when an equal record is already stored
+    pub(super) fn push(&mut self, record: &record::RCRecord) -> Option<u32> {
+        let hash = record.hash_value();
+        // An equal record already stored holds the same symbols, acquiring them again holds nothing more
+        let (symbols, held) = (&self.symbols, &mut self.held);
+        let labels: Vec<(u32, u32)> = record.label_pairs.iter().map(|pair| (symbols.acquire(&pair.key, held), symbols.acquire(&pair.val, held))).collect();
+        if self.find(hash, &labels).is_some() {
+            return None;
+        }
+        if self.chunks.last().is_none_or(|chunk| chunk.len() >= CHUNK_SIZE) {
+            self.chunks.push(ColumnarChunk::new());
+        }
+        let chunk_id = self.chunks.len() - 1;
+        let chunk = self.chunks.last_mut().unwrap();
+        for (key, val) in labels {
+            chunk.keys.push(key);
+            chunk.vals.push(val);
+        }
+        chunk.offsets.push(chunk.keys.len() as u32);
+        if chunk.len() == CHUNK_SIZE {
+            // A full chunk won't grow anymore, give back what the columns reserved ahead
+            chunk.offsets.shrink_to_fit();
+            chunk.keys.shrink_to_fit();
+            chunk.vals.shrink_to_fit();
+        }
+        let id = ((chunk_id << 16) | (chunk.len() - 1)) as u32;
+        match self.hashes.entry(hash) {
+            Entry::Vacant(entry) => {
+                entry.insert(id);
+            }
+            Entry::Occupied(_) => self.collisions.entry(hash).or_default().push(id),
+        }
+        Some(id)
+    }
+
+    pub(super) fn get(&self, id: u32) -> Option<Arc<record::RCRecord>> {
+        let chunk = self.chunks.get((id >> 16) as usize)?;
+        let index = (id & 0xffff) as usize;
+        if index >= chunk.len() {
+            return None;
+        }
+        let label_pairs = chunk
+            .labels(index)
+            .map(|(key, val)| record::RCLabelPair {
+                key: self.symbols.resolve(key),
+                val: self.symbols.resolve(val),
+            })
+            .collect();
+        Some(Arc::new(record::RCRecord::new(label_pairs)))
+    }
+
+    pub(super) fn len(&self) -> usize {
+        match self.chunks.last() {
+            Some(chunk) => ((self.chunks.len() - 1) << 16) + chunk.len(),
+            None => 0,
+        }
+    }
+
+    /// Estimated bytes of the columns and of the set of symbol ids held, the symbols being
+    /// accounted by the shared table
+    pub(super) fn record_bytes(&self) -> usize {
+        let columns: usize = self.chunks.iter().map(|chunk| chunk.bytes()).sum();
+        columns + self.held.serialized_size()
+    }
+
+    /// Estimated bytes of the tables deduplicating the records
+    pub(super) fn table_bytes(&self) -> usize {
+        let collisions: usize = self.collisions.values().map(|ids| ids.capacity() * mem::size_of::<u32>()).sum();
+        self.chunks.capacity() * mem::size_of::<ColumnarChunk>()
+            + self.hashes.capacity() * (mem::size_of::<(u64, u32)>() + 1)
+            + self.collisions.capacity() * (mem::size_of::<(u64, Vec<u32>)>() + 1)
+            + collisions
+    }
+
+    pub(super) fn hash_capacity(&self) -> usize {
+        self.hashes.capacity()
+    }
+
+    pub(super) fn deduplicated(&self) -> usize {
+        self.hashes.len() + self.collisions.values().map(|ids| ids.len()).sum::<usize>()
+    }
+}
+
+impl Drop for ColumnarStore {
+    /// Let the next sweep release the symbols only this store used
+    fn drop(&mut self) {
+        self.symbols.release(&self.held);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::symbols::SymbolTable;
+    use super::super::{RecordStore, StoreLayout};
+    use crate::record::{RCLabelPair, RCRecord};
+    use std::sync::Arc;
+
+    fn record(labels: &[(&str, &str)]) -> RCRecord {
+        RCRecord::new(labels.iter().map(|(key, val)| RCLabelPair::new(key, val)).collect())
+    }
+
+    #[test]
+    fn it_stores_records_in_columns() {
+        let records = [
+            record(&[("author", "tolkien"), ("title", "The Hobbit")]),
+            record(&[("author", "tolkien"), ("title", "The Silmarillion"), ("language", "french")]),
+            record(&[]),
+            record(&[("title", "The Hobbit"), ("author", "tolkien")]),
+            record(&[("author", "tolkien"), ("title", "The Hobbit")]),
+        ];
+        let symbols = Arc::new(SymbolTable::new());
+        let mut rows = RecordStore::new_with_layout(StoreLayout::Rows, &symbols);
+        let mut columns = RecordStore::new_with_layout(StoreLayout::Columnar, &symbols);
+        for record in records.iter() {
+            let added = columns.add(record.clone());
+            assert_eq!(added.as_ref().map(|(id, _)| *id), rows.add(record.clone()).map(|(id, _)| id));
+            if let Some((id, stored)) = added {
+                assert_eq!(*stored, *record);
+                assert_eq!(columns.get(id).as_deref(), Some(record));
+            }
+        }
+        assert_eq!(columns.len(), 4);
+        assert_eq!(columns.get_status().record_count(), 4);
+        assert_eq!(columns.scan(1, 2), rows.scan(1, 2));
+        assert_eq!(columns.get_all(10), rows.get_all(10));
+        assert_eq!(columns.get(4), None);
+        assert!(columns.record_bytes() > 0);
+    }
+
+    #[test]
+    fn it_releases_its_symbols_once_dropped() {
+        let symbols = Arc::new(SymbolTable::new());
+        let mut columns = RecordStore::new_with_layout(StoreLayout::Columnar, &symbols);
+        let mut other = RecordStore::new_with_layout(StoreLayout::Columnar, &symbols);
+        columns.add(record(&[("author", "tolkien"), ("title", "The Hobbit")]));
+        other.add(record(&[("author", "herbert")]));
+        assert_eq!(symbols.sweep(), 0);
+        assert_eq!(symbols.get_status().symbols, 5);
+
+        // The key is still held by the other store
+        drop(columns);
+        assert_eq!(symbols.sweep(), 3);
+        assert_eq!(other.get(0).unwrap().to_string(), "author==herbert");
+        drop(other);
+        assert_eq!(symbols.sweep(), 2);
+        assert_eq!(symbols.memory_usage(), 0);
+    }
+}
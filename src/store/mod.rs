@@ -1,10 +1,18 @@
 use hashbrown::HashMap;
 use log::info;
 use serde::{Deserialize, Serialize};
+use std::mem;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use super::record;
 
+mod columnar;
+pub mod symbols;
+
+/// Bytes taken by the reference counts in front of the data of an `Arc`
+pub const ARC_HEADER_BYTES: usize = 2 * mem::size_of::<usize>();
+
 struct IdChunk {
     chunk: Vec::<Arc<record::RCRecord>>
 }
@@ -34,7 +42,7 @@ struct ChunkedIdStore {
 
 impl ChunkedIdStore {
     fn new() -> ChunkedIdStore {
-        return ChunkedIdStore{ chunk_vec: Vec::new() }
+        ChunkedIdStore{ chunk_vec: Vec::new() }
     }
 
     fn push(&mut self, record: Arc<record::RCRecord>) -> u32 {
@@ -63,16 +71,15 @@ impl ChunkedIdStore {
     }
 
     fn len(&self) -> usize {
-        let upper_bucket = ((self.chunk_vec.len() - 1) << 16) as u32;
-        let lower_bucket = match self.chunk_vec.last() {
-            Some(chunk) => chunk.chunk.len(),
+        match self.chunk_vec.last() {
+            // A full chunk holds 2^16 records, so the last one can't be OR-ed with the upper bucket
+            Some(chunk) => ((self.chunk_vec.len() - 1) << 16) + chunk.chunk.len(),
             None => 0,
-        } as u32;
-        return (upper_bucket | lower_bucket) as usize;
+        }
     }
 
-    fn iter(&self) -> ChunkedIdStoreIter {
-        ChunkedIdStoreIter{pointer: 0, chunk_store: self }
+    fn iter_from(&self, id: u32) -> ChunkedIdStoreIter<'_> {
+        ChunkedIdStoreIter{pointer: id, chunk_store: self }
     }
 }
 
@@ -85,7 +92,7 @@ impl<'a> Iterator for ChunkedIdStoreIter<'a> {
     type Item = Arc<record::RCRecord>;
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.pointer as usize, Some(self.chunk_store.len() as usize))
+        (self.pointer as usize, Some(self.chunk_store.len()))
     }
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -95,65 +102,174 @@ impl<'a> Iterator for ChunkedIdStoreIter<'a> {
     }
 }
 
+/// How the records of a store are laid out in memory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreLayout {
+    /// One allocation per record, handed out as is on read
+    #[default]
+    Rows,
+    /// Columns of symbol ids per chunk of records, records being rebuilt on read
+    Columnar,
+}
+
+impl FromStr for StoreLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rows" => Ok(StoreLayout::Rows),
+            "columnar" => Ok(StoreLayout::Columnar),
+            _ => Err(format!("Error unknown store layout {} (expected rows or columnar)", s)),
+        }
+    }
+}
+
+enum Records {
+    Rows {
+        id_store: ChunkedIdStore,
+        hash_store: HashMap<Arc<record::RCRecord>, u32>,
+        // Estimated bytes of the records, their strings excluded
+        record_bytes: usize,
+    },
+    Columnar(columnar::ColumnarStore),
+}
+
 pub struct RecordStore {
-    id_store: ChunkedIdStore,
-    hash_store: HashMap<Arc<record::RCRecord>, u32>,
+    records: Records,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct RecordStoreStatus {
+    #[serde(default)]
+    layout: StoreLayout,
     hash_store_size: usize,
     hash_store_hashtable_capacity: usize,
     id_store_size: usize,
 }
 
+impl RecordStoreStatus {
+    pub fn record_count(&self) -> usize {
+        self.hash_store_size
+    }
+}
+
 impl RecordStore {
-    pub fn new() -> RecordStore {
-        RecordStore {
-            id_store: ChunkedIdStore::new(),
-            hash_store: HashMap::new(),
-        }
+    /// Columnar records take their symbol ids from the table
+    pub fn new_with_layout(layout: StoreLayout, symbols: &Arc<symbols::SymbolTable>) -> RecordStore {
+        let records = match layout {
+            StoreLayout::Rows => Records::Rows {
+                id_store: ChunkedIdStore::new(),
+                hash_store: HashMap::new(),
+                record_bytes: 0,
+            },
+            StoreLayout::Columnar => Records::Columnar(columnar::ColumnarStore::new(symbols.clone())),
+        };
+        RecordStore { records }
     }
 
+    pub fn layout(&self) -> StoreLayout {
+        match self.records {
+            Records::Rows { .. } => StoreLayout::Rows,
+            Records::Columnar(_) => StoreLayout::Columnar,
+        }
+    }
 
     pub fn add(&mut self, original_record: record::RCRecord) -> Option<(u32, Arc<record::RCRecord>)> {
-        let rc = Arc::new(original_record);
-        let result = self.hash_store.get(&rc);
-        match result {
-            Some(_record) => None,
-            _ => {
-                let id = self.id_store.push(rc.clone());
-                self.hash_store.insert(rc.clone(), id);
-                Some((id, rc.clone()))
+        match &mut self.records {
+            Records::Rows {
+                id_store,
+                hash_store,
+                record_bytes,
+            } => {
+                let rc = Arc::new(original_record);
+                match hash_store.get(&rc) {
+                    Some(_record) => None,
+                    _ => {
+                        *record_bytes +=
+                            ARC_HEADER_BYTES + mem::size_of::<record::RCRecord>() + rc.label_pairs.capacity() * mem::size_of::<record::RCLabelPair>();
+                        let id = id_store.push(rc.clone());
+                        hash_store.insert(rc.clone(), id);
+                        Some((id, rc))
+                    }
+                }
             }
+            Records::Columnar(columns) => columns.push(&original_record).map(|id| (id, Arc::new(original_record))),
         }
     }
 
     pub fn get(&self, id: u32) -> Option<Arc<record::RCRecord>> {
-        self.id_store.get(id)
+        match &self.records {
+            Records::Rows { id_store, .. } => id_store.get(id),
+            Records::Columnar(columns) => columns.get(id),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.records {
+            Records::Rows { id_store, .. } => id_store.len(),
+            Records::Columnar(columns) => columns.len(),
+        }
+    }
+
+    fn deduplicated(&self) -> (usize, usize) {
+        match &self.records {
+            Records::Rows { hash_store, .. } => (hash_store.len(), hash_store.capacity()),
+            Records::Columnar(columns) => (columns.deduplicated(), columns.hash_capacity()),
+        }
     }
 
     pub fn print_status(&self) {
         info!(
             "Size of structs: hashes: {}, ids: {}",
-            self.hash_store.len(),
-            self.id_store.len()
+            self.deduplicated().0,
+            self.len()
         );
     }
 
     pub fn get_status(&self) -> RecordStoreStatus {
+        let (hash_store_size, hash_store_hashtable_capacity) = self.deduplicated();
         RecordStoreStatus {
-            hash_store_size: self.hash_store.len(),
-            id_store_size: self.id_store.len(),
-            hash_store_hashtable_capacity: self.hash_store.capacity(),
+            layout: self.layout(),
+            hash_store_size,
+            id_store_size: self.len(),
+            hash_store_hashtable_capacity,
         }
     }
 
-    pub fn multi_get(&self, ids: Vec<u32>) -> Vec<Arc<record::RCRecord>> {
-        ids.into_iter().filter_map(|id| self.get(id)).collect()
+    /// Estimated bytes of the records, their strings excluded
+    pub fn record_bytes(&self) -> usize {
+        match &self.records {
+            Records::Rows { record_bytes, .. } => *record_bytes,
+            Records::Columnar(columns) => columns.record_bytes(),
+        }
+    }
+
+    /// Estimated bytes of the id chunks and of the table deduplicating the records
+    pub fn table_bytes(&self) -> usize {
+        match &self.records {
+            Records::Rows { id_store, hash_store, .. } => {
+                let chunks = id_store.chunk_vec.len() * 2_usize.pow(16) * mem::size_of::<Arc<record::RCRecord>>();
+                // hashbrown keeps a control byte per bucket
+                chunks + hash_store.capacity() * (mem::size_of::<(Arc<record::RCRecord>, u32)>() + 1)
+            }
+            Records::Columnar(columns) => columns.table_bytes(),
+        }
+    }
+
+    pub fn multi_get(&self, ids: Vec<u32>) -> Vec<(u32, Arc<record::RCRecord>)> {
+        ids.into_iter().filter_map(|id| self.get(id).map(|record| (id, record))).collect()
+    }
+
+    /// Return up to `limit` records starting at id `from`, ids being allocated contiguously
+    pub fn scan(&self, from: u32, limit: usize) -> Vec<(u32, Arc<record::RCRecord>)> {
+        match &self.records {
+            Records::Rows { id_store, .. } => id_store.iter_from(from).take(limit).zip(from..).map(|(record, id)| (id, record)).collect(),
+            Records::Columnar(columns) => (from..).map_while(|id| columns.get(id).map(|record| (id, record))).take(limit).collect(),
+        }
     }
 
-    pub fn get_all(&self, limit: usize) -> Vec<Arc<record::RCRecord>> {
-        self.id_store.iter().take(limit).collect()
+    pub fn get_all(&self, limit: usize) -> Vec<(u32, Arc<record::RCRecord>)> {
+        self.scan(0, limit)
     }
 }
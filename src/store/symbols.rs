@@ -0,0 +1,245 @@
+use ahash::AHasher;
+use hashbrown::{HashMap, HashSet};
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::ARC_HEADER_BYTES;
+
+/// Number of independently locked parts of the table, so that shards rarely wait on each other
+const STRIPES: usize = 64;
+
+/// hashbrown keeps a control byte per bucket
+const BUCKET_BYTES: usize = mem::size_of::<Arc<str>>() + 1;
+const ID_BUCKET_BYTES: usize = mem::size_of::<(Arc<str>, u32)>() + 1;
+
+/// Keys and values shared by every shard of a process. A symbol stays in the table as long
+/// as a record references it, `sweep` releases the ones only the table still holds.
+/// Symbols can also be given compact ids, which stay valid until every holder released them.
+pub struct SymbolTable {
+    stripes: Vec<Mutex<Stripe>>,
+    symbols: AtomicUsize,
+    // Estimated bytes of the strings and of the buckets of the stripes
+    bytes: AtomicUsize,
+}
+
+/// Ids are spread over the stripes: the stripe of id `i` is `i % STRIPES`
+#[derive(Default)]
+struct Stripe {
+    symbols: HashSet<Arc<str>>,
+    // Ids of the symbols which were given one, only used by the columnar layout
+    symbol_ids: HashMap<Arc<str>, u32>,
+    // By id divided by the number of stripes, the symbol and its number of holders
+    ids: Vec<Option<(Arc<str>, u32)>>,
+    free_ids: Vec<u32>,
+    string_bytes: usize,
+}
+
+impl Stripe {
+    fn bytes(&self) -> usize {
+        self.string_bytes
+            + self.symbols.capacity() * BUCKET_BYTES
+            + self.symbol_ids.capacity() * ID_BUCKET_BYTES
+            + self.ids.capacity() * mem::size_of::<Option<(Arc<str>, u32)>>()
+            + self.free_ids.capacity() * mem::size_of::<u32>()
+    }
+
+    fn intern(&mut self, symbol: &str) -> Arc<str> {
+        if let Some(interned) = self.symbols.get(symbol) {
+            return interned.clone();
+        }
+        self.string_bytes += ARC_HEADER_BYTES + symbol.len();
+        self.symbols.get_or_insert_with(symbol, |x| Arc::from(x)).clone()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SymbolTableStatus {
+    pub symbols: usize,
+    pub bytes: usize,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            stripes: (0..STRIPES).map(|_| Mutex::new(Stripe::default())).collect(),
+            symbols: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    fn stripe_index(symbol: &str) -> usize {
+        let mut hasher = AHasher::new_with_keys(0, 0);
+        symbol.hash(&mut hasher);
+        hasher.finish() as usize % STRIPES
+    }
+
+    /// Apply a change to a stripe, accounting for the symbols and bytes it added or removed
+    fn update<T>(&self, index: usize, change: impl FnOnce(&mut Stripe) -> T) -> T {
+        let mut stripe = self.stripes[index].lock().unwrap();
+        let (len, bytes) = (stripe.symbols.len(), stripe.bytes());
+        let result = change(&mut stripe);
+        let (new_len, new_bytes) = (stripe.symbols.len(), stripe.bytes());
+        match new_len >= len {
+            true => self.symbols.fetch_add(new_len - len, Ordering::Relaxed),
+            false => self.symbols.fetch_sub(len - new_len, Ordering::Relaxed),
+        };
+        match new_bytes >= bytes {
+            true => self.bytes.fetch_add(new_bytes - bytes, Ordering::Relaxed),
+            false => self.bytes.fetch_sub(bytes - new_bytes, Ordering::Relaxed),
+        };
+        result
+    }
+
+    pub fn intern(&self, symbol: &str) -> Arc<str> {
+        self.update(SymbolTable::stripe_index(symbol), |stripe| stripe.intern(symbol))
+    }
+
+    /// Id of the symbol, interning it if needed. The holder (the set of ids held by a store)
+    /// holds the id until it releases it, the id resolving to the symbol until then.
+    pub fn acquire(&self, symbol: &str, holder: &mut RoaringBitmap) -> u32 {
+        let index = SymbolTable::stripe_index(symbol);
+        self.update(index, |stripe| {
+            let id = match stripe.symbol_ids.get(symbol) {
+                Some(id) => *id,
+                None => {
+                    let interned = stripe.intern(symbol);
+                    let local = stripe.free_ids.pop().unwrap_or(stripe.ids.len() as u32);
+                    match stripe.ids.get_mut(local as usize) {
+                        Some(slot) => *slot = Some((interned.clone(), 0)),
+                        None => stripe.ids.push(Some((interned.clone(), 0))),
+                    }
+                    let id = local * STRIPES as u32 + index as u32;
+                    stripe.symbol_ids.insert(interned, id);
+                    id
+                }
+            };
+            if holder.insert(id) {
+                stripe.ids[id as usize / STRIPES].as_mut().unwrap().1 += 1;
+            }
+            id
+        })
+    }
+
+    /// The symbol of an id held by a store
+    pub fn resolve(&self, id: u32) -> Arc<str> {
+        let stripe = self.stripes[id as usize % STRIPES].lock().unwrap();
+        stripe.ids[id as usize / STRIPES].as_ref().expect("resolved ids are held").0.clone()
+    }
+
+    /// Release every id of the holder, the symbols being released by the next sweep
+    pub fn release(&self, holder: &RoaringBitmap) {
+        for id in holder.iter() {
+            let mut stripe = self.stripes[id as usize % STRIPES].lock().unwrap();
+            stripe.ids[id as usize / STRIPES].as_mut().expect("released ids are held").1 -= 1;
+        }
+    }
+
+    /// Release the symbols no record references anymore, returning how many were released.
+    /// A symbol can only be handed out under the lock of its stripe, so one that is only
+    /// referenced by the table while the lock is held can't be in use.
+    pub fn sweep(&self) -> usize {
+        let mut released = 0;
+        for index in 0..STRIPES {
+            released += self.update(index, |stripe| {
+                let len = stripe.symbols.len();
+                // Ids nobody holds anymore are given back first, their symbols may be unused then
+                let Stripe {
+                    symbols,
+                    symbol_ids,
+                    ids,
+                    free_ids,
+                    string_bytes,
+                } = stripe;
+                for (local, slot) in ids.iter_mut().enumerate() {
+                    if let Some((symbol, 0)) = slot.as_ref() {
+                        symbol_ids.remove(symbol);
+                        *slot = None;
+                        free_ids.push(local as u32);
+                    }
+                }
+                if symbol_ids.len() <= symbol_ids.capacity() / 4 {
+                    symbol_ids.shrink_to_fit();
+                }
+                symbols.retain(|symbol| {
+                    let unused = Arc::strong_count(symbol) == 1;
+                    if unused {
+                        *string_bytes -= ARC_HEADER_BYTES + symbol.len();
+                    }
+                    !unused
+                });
+                if symbols.len() <= symbols.capacity() / 4 {
+                    symbols.shrink_to_fit();
+                }
+                if free_ids.len() == ids.len() {
+                    *ids = Vec::new();
+                    *free_ids = Vec::new();
+                }
+                len - symbols.len()
+            });
+        }
+        released
+    }
+
+    pub fn get_status(&self) -> SymbolTableStatus {
+        SymbolTableStatus {
+            symbols: self.symbols.load(Ordering::Relaxed),
+            bytes: self.memory_usage(),
+        }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn it_shares_and_sweeps_symbols() {
+        let table = Arc::new(SymbolTable::new());
+        let interned: Vec<Arc<str>> = (0..4)
+            .map(|_| {
+                let table = table.clone();
+                thread::spawn(move || table.intern("english"))
+            })
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        assert!(interned.iter().all(|symbol| Arc::ptr_eq(symbol, &interned[0])));
+
+        let french = table.intern("french");
+        assert_eq!(table.get_status().symbols, 2);
+        assert_eq!(table.sweep(), 0);
+        drop(interned);
+        assert_eq!(table.sweep(), 1);
+        assert_eq!(table.get_status().symbols, 1);
+        assert!(Arc::ptr_eq(&french, &table.intern("french")));
+        drop(french);
+        assert_eq!(table.sweep(), 1);
+        assert_eq!(table.memory_usage(), 0);
+    }
+
+    #[test]
+    fn it_gives_ids_to_symbols_until_released() {
+        let table = SymbolTable::new();
+        let (mut first, mut second) = (RoaringBitmap::new(), RoaringBitmap::new());
+        let english = table.acquire("english", &mut first);
+        assert_eq!(table.acquire("english", &mut first), english);
+        assert_eq!(table.acquire("english", &mut second), english);
+        assert_eq!(&*table.resolve(english), "english");
+
+        table.release(&first);
+        assert_eq!(table.sweep(), 0);
+        assert_eq!(&*table.resolve(english), "english");
+        table.release(&second);
+        assert_eq!(table.sweep(), 1);
+        assert_eq!(table.memory_usage(), 0);
+    }
+}
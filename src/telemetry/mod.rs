@@ -6,8 +6,14 @@ make_auto_flush_static_metric! {
     pub label_enum Operations {
         add,
         raw_add,
+        get,
+        migrate,
+        scan,
         search,
         key_values_search,
+        count,
+        bulk_add,
+        text_stats,
     }
 
     pub struct LocalShardLatencyHistogram: LocalHistogram {
@@ -28,9 +34,33 @@ lazy_static! {
     .unwrap();
 }
 
+lazy_static! {
+    pub static ref SHARD_QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "shard_queue_depth",
+        "Number of requests waiting in the queues of a shard",
+        &["shard", "lane"]
+    )
+    .unwrap();
+    pub static ref SHARD_CRASHES: IntCounter =
+        register_int_counter!("shard_crashes", "Number of times a shard panicked and was restarted").unwrap();
+    pub static ref MEMORY_USAGE_BYTES: IntGaugeVec = register_int_gauge_vec!(
+        "memory_usage_bytes",
+        "Estimated bytes used by the records, their table, the interned strings and the index of a shard",
+        &["shard", "component"]
+    )
+    .unwrap();
+    pub static ref SHARD_REJECTED_REQUESTS: IntCounter = register_int_counter!(
+        "shard_rejected_requests",
+        "Number of requests rejected because the queue of a shard was full"
+    )
+    .unwrap();
+}
+
 lazy_static! {
     pub static ref INIT_FILE_RECORDS_APPENDED: IntCounter =
         prometheus::register_int_counter!("init_file_records_appended", "Number of records appended during initial load").unwrap();
+    pub static ref BULK_LOADED_LINES: IntCounterVec =
+        register_int_counter_vec!("bulk_loaded_lines", "Number of lines handled by bulk loads, by result (parsed or rejected)", &["result"]).unwrap();
     pub static ref API_ADD_LATENCY: Histogram =
         prometheus::register_histogram!("init_file_records_appended", "Number of records appended during initial load").unwrap();
     pub static ref LOCAL_SHARD_LATENCY_HISTOGRAM: LocalShardLatencyHistogram = auto_flush_from!(SHARD_LATENCY_HISTOGRAM, LocalShardLatencyHistogram);